@@ -0,0 +1,101 @@
+// Fills the table from /admin/signals. The browser already has the admin
+// token from basic auth, so plain fetches are enough.
+"use strict";
+
+const form = document.getElementById("filters");
+const tbody = document.getElementById("signals");
+const errorBox = document.getElementById("error");
+
+function text(tag, content, className) {
+  const element = document.createElement(tag);
+  element.textContent = content == null ? "" : String(content);
+  if (className) element.className = className;
+  return element;
+}
+
+// datetime-local inputs have no zone, so treat them as UTC like the table
+function toUtc(value) {
+  return value ? value + ":00Z" : "";
+}
+
+function query() {
+  const params = new URLSearchParams();
+  const data = new FormData(form);
+  if (data.get("strategy")) params.set("strategy", data.get("strategy"));
+  if (data.get("since")) params.set("since", toUtc(data.get("since")));
+  if (data.get("until")) params.set("until", toUtc(data.get("until")));
+  if (data.get("failed")) params.set("failed", "true");
+  if (data.get("limit")) params.set("limit", data.get("limit"));
+  return params.toString();
+}
+
+function describe(entry) {
+  switch (entry.event) {
+    case "request_executed":
+      return `${entry.account}: ${entry.action} bot ${entry.bot_id} → ${entry.status || entry.error} (${entry.latency_ms} ms, ${entry.attempts} tries)`;
+    case "webhook_executed":
+      return `webhook ${entry.name} → ${entry.status || entry.error} (${entry.latency_ms} ms, ${entry.attempts} tries)`;
+    case "sequence_planned":
+      return `${entry.account}: planned ${entry.requests.map((r) => r.action).join(", ") || "nothing"}`;
+    case "exits_planned":
+      return `bot ${entry.bot_id}: stop-loss ${entry.stop_loss_percentage ?? "-"}%, take-profit ${entry.take_profit_percentage ?? "-"}%`;
+    default:
+      return entry.event;
+  }
+}
+
+function row(history) {
+  const order = history.signal.order || {};
+  const tr = document.createElement("tr");
+  tr.className = history.status;
+  tr.appendChild(text("td", history.received_at.replace("T", " ").replace(/\..*|Z$/, "")));
+  tr.appendChild(text("td", history.strategy));
+  tr.appendChild(text("td", order.action));
+  tr.appendChild(text("td", history.signal.ticker));
+  tr.appendChild(text("td", history.reason ? `${history.status}: ${history.reason}` : history.status, "status"));
+
+  const requests = document.createElement("td");
+  const list = document.createElement("ul");
+  history.events
+    .filter((entry) => entry.event !== "signal_rejected" && entry.event !== "signal_deferred")
+    .forEach((entry) => list.appendChild(text("li", describe(entry))));
+  requests.appendChild(list);
+  tr.appendChild(requests);
+
+  // The whole story, for when the summary isn't enough
+  tr.addEventListener("click", () => {
+    const next = tr.nextElementSibling;
+    if (next && next.classList.contains("details")) {
+      next.remove();
+      return;
+    }
+    const details = document.createElement("tr");
+    details.className = "details";
+    const cell = document.createElement("td");
+    cell.colSpan = 6;
+    cell.appendChild(text("pre", JSON.stringify(history, null, 2)));
+    details.appendChild(cell);
+    tr.after(details);
+  });
+  return tr;
+}
+
+async function refresh() {
+  try {
+    const response = await fetch(`signals?${query()}`, { credentials: "same-origin" });
+    if (!response.ok) throw new Error(`${response.status} ${await response.text()}`);
+    const signals = await response.json();
+    tbody.replaceChildren(...signals.map(row));
+    errorBox.hidden = true;
+  } catch (e) {
+    errorBox.textContent = `Couldn't load signals: ${e.message}`;
+    errorBox.hidden = false;
+  }
+}
+
+form.addEventListener("submit", (event) => {
+  event.preventDefault();
+  refresh();
+});
+
+refresh();
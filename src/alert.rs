@@ -0,0 +1,23 @@
+use log::error;
+use reqwest::Client;
+use serde_json::json;
+use crate::settings::get_settings;
+
+/// Logs `message` as an error, and passes it on to the alert webhook if there is one.
+pub fn raise(message: String) {
+    error!("ALERT: {}", message);
+
+    let webhook_url = get_settings().alerts.webhook_url.clone();
+    if let Some(url) = webhook_url {
+        tokio::spawn(async move {
+            let body = json!({ "text": message, "content": message });
+            match Client::new().post(&url).json(&body).send().await {
+                Ok(response) if !response.status().is_success() => {
+                    error!("Alert webhook said {}", response.status());
+                }
+                Err(e) => error!("Couldn't send alert: {}", e),
+                Ok(_) => (),
+            }
+        });
+    }
+}
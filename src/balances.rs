@@ -0,0 +1,107 @@
+use lazy_static::lazy_static;
+use log::{error, info};
+use std::{collections::{BTreeSet, HashMap}, sync::RwLock};
+use tokio::time::{interval, Duration};
+use crate::{reconcile::ThreeCommasApi, settings::Settings};
+
+/// A 3commas account id, and a currency it holds.
+pub type BalanceKey = (u64, String);
+
+/// The account balances strategies size their deals by, as of the last time
+/// we asked 3commas.
+#[derive(Default)]
+pub struct BalanceCache {
+    balances: RwLock<HashMap<BalanceKey, f64>>,
+}
+
+lazy_static! {
+    pub static ref BALANCES: BalanceCache = BalanceCache::default();
+}
+
+impl BalanceCache {
+    /// How much of `currency` account `account_id` held last we heard.
+    pub fn find(&self, account_id: u64, currency: &str) -> Option<f64> {
+        let key = (account_id, currency.to_ascii_uppercase());
+        self.balances.read().unwrap().get(&key).copied()
+    }
+
+    pub fn set(&self, account_id: u64, currency: &str, balance: f64) {
+        let key = (account_id, currency.to_ascii_uppercase());
+        self.balances.write().unwrap().insert(key, balance);
+    }
+
+    /// Asks 3commas for each of `wanted` again. Ones it doesn't answer for
+    /// keep the balance we had.
+    pub async fn refresh(&self, api: &ThreeCommasApi, wanted: &BTreeSet<BalanceKey>) {
+        for (account_id, currency) in wanted {
+            match api.balance(*account_id, currency).await {
+                Ok(balance) => {
+                    info!("Account {} has {} {}", account_id, balance, currency);
+                    self.set(*account_id, currency, balance);
+                }
+                Err(e) => error!("Couldn't get account {}'s {} balance, keeping what we had: {}", account_id, currency, e),
+            }
+        }
+    }
+}
+
+/// Every balance a strategy sizes by, tenants' included.
+pub fn wanted(settings: &Settings) -> BTreeSet<BalanceKey> {
+    settings
+        .strategies
+        .values()
+        .chain(settings.tenants.values().flat_map(|tenant| tenant.strategies.values()))
+        .filter_map(|strategy| strategy.sizing.as_ref()?.rule.balance())
+        .map(|(account_id, currency)| (account_id, currency.to_ascii_uppercase()))
+        .collect()
+}
+
+/// Asks for `wanted` again every `interval_secs`, after the first time at startup.
+pub async fn refresh_periodically(api: ThreeCommasApi, wanted: BTreeSet<BalanceKey>, interval_secs: u64) {
+    let mut ticks = interval(Duration::from_secs(interval_secs));
+    // The first tick is straight away
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        BALANCES.refresh(&api, &wanted).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+    use crate::settings::ReconcileSettings;
+
+    #[tokio::test]
+    async fn it_keeps_what_it_had_when_a_refresh_fails() {
+        let server = MockServer::start();
+        let mut table = server.mock(|when, then| {
+            when.method("POST")
+                .path("/public/api/ver1/accounts/42/account_table_data")
+                .header("APIKEY", "key")
+                .header_exists("Signature");
+            then.status(200).body(r#"[{"currency_code": "BTC", "position": "0.5"}, {"currency_code": "USDT", "position": "2500.0"}]"#);
+        });
+        let api = ThreeCommasApi::new(&ReconcileSettings {
+            api_server: server.base_url(),
+            api_key: "key".into(),
+            api_secret: "secret".into(),
+            ..Default::default()
+        });
+        let wanted = vec![(42, "USDT".to_string())].into_iter().collect();
+        let cache = BalanceCache::default();
+
+        cache.refresh(&api, &wanted).await;
+        assert_eq!(cache.find(42, "usdt"), Some(2500.0));
+
+        table.delete();
+        server.mock(|when, then| {
+            when.method("POST");
+            then.status(500);
+        });
+        cache.refresh(&api, &wanted).await;
+        assert_eq!(cache.find(42, "USDT"), Some(2500.0));
+        assert_eq!(cache.find(43, "USDT"), None);
+    }
+}
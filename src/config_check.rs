@@ -0,0 +1,179 @@
+use crate::{
+    balances,
+    incoming::{parse_signal, rules},
+    outgoing::account::Account,
+    schedule,
+    settings::{Settings, DEFAULT_ACCOUNT, TENANT_SEPARATOR},
+};
+
+/// Tenants' webhook tokens shorter than this are too easy to guess.
+const MIN_TENANT_TOKEN_LEN: usize = 16;
+
+/// Mistakes in `settings` that loading them doesn't catch, like a strategy
+/// trading on an account that doesn't exist.
+pub fn problems(settings: &Settings) -> Vec<String> {
+    let mut problems = strategy_problems(settings);
+
+    if settings.reconcile.is_none() && !balances::wanted(settings).is_empty() {
+        problems.push("Some strategies size deals by balance, but there's no reconcile section to get it with".into());
+    }
+
+    for schedule in &settings.schedules {
+        if let Err(e) = schedule::next_after(&schedule.cron, chrono::Utc::now()) {
+            problems.push(e);
+        }
+    }
+
+    let mut tenants: Vec<_> = settings.tenants.iter().collect();
+    tenants.sort_by_key(|(name, _)| name.as_str());
+    for (name, tenant) in tenants {
+        if name.contains(TENANT_SEPARATOR) {
+            problems.push(format!("Tenant {:?} can't have {:?} in its name", name, TENANT_SEPARATOR));
+        }
+        if tenant.token.expose().len() < MIN_TENANT_TOKEN_LEN {
+            problems.push(format!("Tenant {:?}'s token should be at least {} characters", name, MIN_TENANT_TOKEN_LEN));
+        }
+        let shared = settings.tenants.iter().any(|(other, other_tenant)| {
+            other != name && !tenant.token.is_empty() && other_tenant.token == tenant.token
+        });
+        if shared {
+            problems.push(format!("Tenant {:?}'s token is another tenant's too", name));
+        }
+        if let Some(tenant_settings) = settings.for_tenant(name) {
+            let tenant_problems = strategy_problems(&tenant_settings);
+            problems.extend(tenant_problems.into_iter().map(|problem| format!("Tenant {:?}: {}", name, problem)));
+        }
+    }
+    problems
+}
+
+fn strategy_problems(settings: &Settings) -> Vec<String> {
+    let mut problems = vec![];
+
+    let mut strategies: Vec<_> = settings.strategies.iter().collect();
+    strategies.sort_by_key(|(name, _)| name.as_str());
+    for (name, strategy) in strategies {
+        for account in strategy.accounts.iter().filter(|account| Account::named(settings, account).is_none()) {
+            problems.push(format!("Strategy {:?} trades on {:?}, which isn't in accounts", name, account));
+        }
+        if strategy.accounts.is_empty() && Account::named(settings, DEFAULT_ACCOUNT).is_none() {
+            problems.push(format!("Strategy {:?} doesn't say which accounts it trades on, and there's no default", name));
+        }
+        if strategy.sanity.max_price_deviation_pct.is_some() && strategy.sanity.reference_price.is_none() {
+            problems.push(format!("Strategy {:?} limits the price deviation, but has no reference_price to check against", name));
+        }
+        if strategy.sanity.check_market && settings.markets.is_none() {
+            problems.push(format!("Strategy {:?} checks markets, but there's no markets section", name));
+        }
+        for webhook in strategy.webhooks.iter().filter(|webhook| !settings.webhooks.contains_key(*webhook)) {
+            problems.push(format!("Strategy {:?} sends to {:?}, which isn't in webhooks", name, webhook));
+        }
+    }
+    problems
+}
+
+/// What a webhook body would turn into with `settings`: which strategy and rule
+/// it goes to, and the requests that come of it. Nothing is checked against
+/// the sanity checks or risk limits, since those depend on when it arrives.
+pub fn evaluate(settings: &Settings, body: &str) -> String {
+    let mut signal = match parse_signal(None, body.as_bytes()) {
+        Ok(signal) => signal,
+        Err(e) => return format!("rejected: {}", e),
+    };
+    let strategy = match signal.route(settings) {
+        Ok(strategy) => strategy,
+        Err(e) => return format!("rejected: {}", e),
+    };
+    let rule = match rules::matching(&strategy.rules, &signal) {
+        Some(rule) => format!("rule {}", rule),
+        None => "built-in".to_string(),
+    };
+
+    let mut result = format!("strategy {:?}, {}: ", signal.strategy_name(), rule);
+    match signal.to_requests(&strategy) {
+        Ok(requests) if requests.is_empty() => result.push_str("nothing"),
+        Ok(requests) => {
+            let requests: Vec<String> = requests
+                .iter()
+                .map(|request| {
+                    let mut request_text = format!("{:?} {}", request.action, request.bot_id);
+                    if request.hold_secs > 0 {
+                        request_text.push_str(&format!(" after {}s", request.hold_secs));
+                    }
+                    request_text
+                })
+                .collect();
+            result.push_str(&requests.join(", "));
+        }
+        Err(e) => result.push_str(&format!("rejected: {}", e)),
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{RuleSettings, RuleStep, StrategySettings, TenantSettings};
+    use crate::outgoing::deal_and_bot_types::{ActionType, BotType};
+
+    fn settings() -> Settings {
+        let rule = RuleSettings {
+            name: Some("slow close".into()),
+            when: Default::default(),
+            then: vec![
+                RuleStep::Wait(60),
+                RuleStep::Request { action: ActionType::CloseDeal, bots: vec![BotType::Long] },
+            ],
+        };
+        let fancy = StrategySettings {
+            accounts: vec!["default".into(), "family".into()],
+            rules: vec![rule],
+            ..StrategySettings::default()
+        };
+        let mut settings = Settings::default();
+        settings.strategies.insert("fancy".into(), fancy);
+        settings
+    }
+
+    #[test]
+    fn it_finds_missing_accounts() {
+        assert_eq!(
+            problems(&settings()),
+            ["Strategy \"fancy\" trades on \"family\", which isn't in accounts"]
+        );
+    }
+
+    #[test]
+    fn it_wants_a_reference_price_for_price_deviation() {
+        let mut settings = settings();
+        settings.strategies.get_mut("fancy").unwrap().sanity.max_price_deviation_pct = Some(2.5);
+        assert!(problems(&settings).contains(
+            &"Strategy \"fancy\" limits the price deviation, but has no reference_price to check against".to_string()
+        ));
+    }
+
+    #[test]
+    fn it_checks_tenants() {
+        let mut alice = TenantSettings { token: "correct horse battery staple".into(), ..TenantSettings::default() };
+        alice.strategies.insert("fancy".into(), StrategySettings::default());
+        let mut settings = Settings::default();
+        settings.tenants.insert("alice".into(), alice);
+        settings.tenants.insert("bob".into(), TenantSettings { token: "bob".into(), ..TenantSettings::default() });
+
+        assert_eq!(problems(&settings), [
+            "Tenant \"alice\": Strategy \"fancy\" doesn't say which accounts it trades on, and there's no default",
+            "Tenant \"bob\"'s token should be at least 16 characters",
+        ]);
+    }
+
+    #[test]
+    fn it_evaluates_samples() {
+        let settings = settings();
+        assert_eq!(
+            evaluate(&settings, "buy strategy=fancy"),
+            "strategy \"fancy\", rule \"slow close\": CloseDeal 1234567 after 60s"
+        );
+        assert_eq!(evaluate(&settings, "buy strategy=plain"), "rejected: Unknown strategy \"plain\"");
+        assert!(evaluate(&settings, "blah").starts_with("rejected: Can't parse signal"));
+    }
+}
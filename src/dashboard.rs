@@ -0,0 +1,23 @@
+use warp::{filters::BoxedFilter, http::header::CONTENT_TYPE, reply, Filter, Reply};
+
+// Built into the binary, so there's nothing to deploy alongside it
+const INDEX_HTML: &str = include_str!("../assets/dashboard/index.html");
+const DASHBOARD_JS: &str = include_str!("../assets/dashboard/dashboard.js");
+const DASHBOARD_CSS: &str = include_str!("../assets/dashboard/dashboard.css");
+
+fn asset(body: &'static str, content_type: &'static str) -> impl Reply {
+    reply::with_header(body, CONTENT_TYPE, content_type)
+}
+
+/// The read-only signal history page, under `/admin/dashboard`. It gets its
+/// data from `/admin/signals`.
+pub fn dashboard() -> BoxedFilter<(impl Reply,)> {
+    let index = warp::path::end().map(|| reply::html(INDEX_HTML));
+    let js = warp::path!("dashboard.js").map(|| asset(DASHBOARD_JS, "application/javascript; charset=utf-8"));
+    let css = warp::path!("dashboard.css").map(|| asset(DASHBOARD_CSS, "text/css; charset=utf-8"));
+
+    warp::path("dashboard")
+        .and(warp::get())
+        .and(index.or(js).or(css))
+        .boxed()
+}
@@ -0,0 +1,183 @@
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use log::{error, info};
+use std::{collections::HashMap, sync::Mutex};
+use crate::{incoming::{IncomingSignal, SignalError}, journal, storage::{self, Storage}};
+
+/// The signal we first saw with a given body, and until when a repeat of it
+/// counts as a duplicate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Seen {
+    pub signal_id: String,
+    pub at: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+}
+
+/// Signals we've had recently, by what they said.
+#[derive(Default)]
+pub struct Dedup {
+    seen: Mutex<HashMap<String, Seen>>,
+}
+
+lazy_static! {
+    pub static ref DEDUP: Dedup = Dedup::load(storage::STORAGE.as_ref(), Utc::now());
+}
+
+/// Everything the signal says, and whose it is. TradingView sends the same body
+/// when an alert fires twice, and `timenow` tells genuinely new alerts apart.
+fn fingerprint(signal_id: &str, signal: &IncomingSignal) -> String {
+    let tenant = journal::tenant_of(signal_id).unwrap_or_default();
+    format!("{} {}", tenant, serde_json::to_string(signal).unwrap_or_default())
+}
+
+impl Dedup {
+    /// Picks up the signals `database` says were still being held off at
+    /// `now`, so a restart doesn't let a repeat of them through.
+    pub fn load(database: Option<&Storage>, now: DateTime<Utc>) -> Self {
+        let seen = database
+            .and_then(|database| {
+                database
+                    .load_seen(now)
+                    .map_err(|e| error!("Can't read recent signals back from the database: {}", e))
+                    .ok()
+            })
+            .unwrap_or_default();
+        if !seen.is_empty() {
+            info!("Holding off repeats of {} recent signals", seen.len());
+        }
+        Dedup { seen: Mutex::new(seen) }
+    }
+
+    /// Holds `signal`'s place for `window`, unless it repeats one we're already
+    /// holding. The place is given up again when the `Reservation` is dropped,
+    /// unless it's `commit`ted first, so a signal that goes nowhere doesn't stop
+    /// TradingView's retry of it.
+    pub fn reserve(
+        &self,
+        signal_id: &str,
+        signal: &IncomingSignal,
+        window: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<Reservation<'_>, SignalError> {
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, seen| seen.until > now);
+
+        let fingerprint = fingerprint(signal_id, signal);
+        if let Some(first) = seen.get(&fingerprint) {
+            return Err(SignalError::Duplicate {
+                signal_id: first.signal_id.clone(),
+                secs_ago: (now - first.at).num_seconds(),
+            });
+        }
+
+        seen.insert(fingerprint.clone(), Seen {
+            signal_id: signal_id.into(),
+            at: now,
+            until: now + window,
+        });
+        Ok(Reservation {
+            dedup: self,
+            signal_id: signal_id.into(),
+            fingerprint: Some(fingerprint),
+        })
+    }
+
+    fn keep(&self, fingerprint: &str) {
+        if let Some(seen) = self.seen.lock().unwrap().get(fingerprint) {
+            storage::save_seen(fingerprint, seen);
+        }
+    }
+
+    fn release(&self, signal_id: &str, fingerprint: &str) {
+        let mut seen = self.seen.lock().unwrap();
+        if seen.get(fingerprint).is_some_and(|seen| seen.signal_id == signal_id) {
+            seen.remove(fingerprint);
+        }
+    }
+}
+
+/// A signal's place in `Dedup`, until it's either sent on its way or dropped.
+pub struct Reservation<'a> {
+    dedup: &'a Dedup,
+    signal_id: String,
+    fingerprint: Option<String>,
+}
+
+impl Reservation<'_> {
+    /// Keeps the place for the rest of the window, across a restart too: the
+    /// signal is being acted on.
+    pub fn commit(mut self) {
+        if let Some(fingerprint) = self.fingerprint.take() {
+            self.dedup.keep(&fingerprint);
+        }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if let Some(fingerprint) = &self.fingerprint {
+            self.dedup.release(&self.signal_id, fingerprint);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incoming::parse_signal;
+
+    #[test]
+    fn it_turns_away_repeats_inside_the_window() {
+        let dedup = Dedup::default();
+        let signal = parse_signal(None, br#"{"strategy": "fancy v1", "action": "buy"}"#).unwrap();
+        let other = parse_signal(None, br#"{"strategy": "fancy v1", "action": "sell"}"#).unwrap();
+        let window = Duration::seconds(60);
+        let now: DateTime<Utc> = "2021-06-02T12:00:00Z".parse().unwrap();
+
+        dedup.reserve("a", &signal, window, now).unwrap().commit();
+        dedup.reserve("b", &other, window, now).unwrap().commit();
+        assert!(matches!(
+            dedup.reserve("c", &signal, window, now + Duration::seconds(30)),
+            Err(SignalError::Duplicate { signal_id, secs_ago: 30 }) if signal_id == "a"
+        ));
+        dedup.reserve("d", &signal, window, now + Duration::seconds(60)).unwrap().commit();
+        assert!(dedup.reserve("alice:e", &signal, window, now + Duration::seconds(60)).is_ok());
+    }
+
+    #[test]
+    fn it_remembers_signals_across_a_restart() {
+        let database = Storage::in_memory().unwrap();
+        let signal = parse_signal(None, br#"{"strategy": "fancy v1", "action": "buy"}"#).unwrap();
+        let now: DateTime<Utc> = "2021-06-02T12:00:00Z".parse().unwrap();
+        let fingerprint = fingerprint("a", &signal);
+        database.save_seen(&fingerprint, &Seen {
+            signal_id: "a".into(),
+            at: now,
+            until: now + Duration::seconds(60),
+        }).unwrap();
+
+        let dedup = Dedup::load(Some(&database), now + Duration::seconds(10));
+        assert!(matches!(
+            dedup.reserve("b", &signal, Duration::seconds(60), now + Duration::seconds(10)),
+            Err(SignalError::Duplicate { signal_id, secs_ago: 10 }) if signal_id == "a"
+        ));
+        assert!(Dedup::load(Some(&database), now + Duration::seconds(60)).seen.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_lets_go_of_signals_that_went_nowhere() {
+        let dedup = Dedup::default();
+        let signal = parse_signal(None, br#"{"strategy": "fancy v1", "action": "buy"}"#).unwrap();
+        let window = Duration::seconds(60);
+        let now: DateTime<Utc> = "2021-06-02T12:00:00Z".parse().unwrap();
+
+        let reservation = dedup.reserve("a", &signal, window, now).unwrap();
+        assert!(dedup.reserve("b", &signal, window, now).is_err());
+        drop(reservation);
+        dedup.reserve("c", &signal, window, now).unwrap().commit();
+        assert!(matches!(
+            dedup.reserve("d", &signal, window, now),
+            Err(SignalError::Duplicate { signal_id, .. }) if signal_id == "c"
+        ));
+    }
+}
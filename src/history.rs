@@ -0,0 +1,279 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use crate::journal::{JournalEntry, JournalEvent};
+
+/// Which signals to show, from the query string.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct SignalQuery {
+    pub strategy: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only signals that were rejected or had a request fail
+    pub failed: bool,
+    pub limit: Option<usize>,
+}
+
+const DEFAULT_LIMIT: usize = 100;
+
+/// How things stand for a signal.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignalStatus {
+    /// Planned, but not everything has been sent yet
+    Pending,
+    /// Waiting on a risk limit
+    Deferred,
+    /// Waiting until it's time to send the requests
+    Scheduled,
+    /// Planned, and not sent on purpose
+    DryRun,
+    Rejected,
+    /// Everything was sent and got a 2xx back
+    Succeeded,
+    /// At least one request or webhook didn't work out
+    Failed,
+}
+
+/// Everything the journal says about one signal.
+#[derive(Serialize, Debug, Clone)]
+pub struct SignalHistory {
+    pub signal_id: String,
+    pub received_at: DateTime<Utc>,
+    pub strategy: String,
+    pub signal: Value,
+    pub status: SignalStatus,
+    /// Why it was rejected or deferred
+    pub reason: Option<String>,
+    /// Every journal entry for the signal after it was received, oldest first
+    pub events: Vec<JournalEntry>,
+}
+
+impl SignalHistory {
+    fn new(entry: &JournalEntry, strategy: &str, signal: &Value) -> Self {
+        SignalHistory {
+            signal_id: entry.signal_id.clone(),
+            received_at: entry.at,
+            strategy: strategy.into(),
+            signal: signal.clone(),
+            status: SignalStatus::Pending,
+            reason: None,
+            events: vec![],
+        }
+    }
+
+    /// Works out the status from the events.
+    fn settle(&mut self) {
+        let mut planned = 0;
+        let mut sent = 0;
+        let mut failed = false;
+        self.status = SignalStatus::Pending;
+
+        for entry in &self.events {
+            match &entry.event {
+                JournalEvent::SignalRejected { reason } => {
+                    self.status = SignalStatus::Rejected;
+                    self.reason = Some(reason.clone());
+                    return;
+                }
+                JournalEvent::SignalDeferred { reason, .. } => {
+                    self.status = SignalStatus::Deferred;
+                    self.reason = Some(reason.clone());
+                }
+                JournalEvent::SignalScheduled { .. } => self.status = SignalStatus::Scheduled,
+                JournalEvent::SignalDryRun => self.status = SignalStatus::DryRun,
+                JournalEvent::SequencePlanned(plan) => {
+                    planned += plan.requests.len() + plan.webhooks.len();
+                    self.status = SignalStatus::Pending;
+                }
+                JournalEvent::RequestExecuted { status, .. } | JournalEvent::WebhookExecuted { status, .. } => {
+                    sent += 1;
+                    failed |= !status.is_some_and(|status| (200..300).contains(&status));
+                }
+                _ => (),
+            }
+        }
+
+        if failed {
+            self.status = SignalStatus::Failed;
+        } else if planned > 0 && sent >= planned {
+            self.status = SignalStatus::Succeeded;
+        }
+    }
+
+    fn matches(&self, query: &SignalQuery) -> bool {
+        query.strategy.as_ref().is_none_or(|strategy| *strategy == self.strategy)
+            && query.since.is_none_or(|since| self.received_at >= since)
+            && query.until.is_none_or(|until| self.received_at < until)
+            && (!query.failed || matches!(self.status, SignalStatus::Failed | SignalStatus::Rejected))
+    }
+}
+
+/// Pieces the signals in `entries` back together, newest first. Entries that
+/// don't belong to a received signal (like reconciliation) are left out.
+pub fn signals(entries: Vec<JournalEntry>, query: &SignalQuery) -> Vec<SignalHistory> {
+    let mut order = vec![];
+    let mut signals: HashMap<String, SignalHistory> = HashMap::new();
+
+    for entry in entries {
+        match &entry.event {
+            JournalEvent::SignalReceived { strategy, signal, .. } => {
+                order.push(entry.signal_id.clone());
+                signals.insert(entry.signal_id.clone(), SignalHistory::new(&entry, strategy, signal));
+            }
+            _ => {
+                if let Some(history) = signals.get_mut(&entry.signal_id) {
+                    history.events.push(entry);
+                }
+            }
+        }
+    }
+
+    order
+        .iter()
+        .rev()
+        .filter_map(|signal_id| signals.remove(signal_id))
+        .map(|mut history| {
+            history.settle();
+            history
+        })
+        .filter(|history| history.matches(query))
+        .take(query.limit.unwrap_or(DEFAULT_LIMIT))
+        .collect()
+}
+
+/// Whether `entries`, from the end of a journal, already hold every signal
+/// `query` could want. A signal's entries all come after it was received, so
+/// the histories of the signals in `entries` are complete.
+pub fn is_enough(entries: &[JournalEntry], query: &SignalQuery) -> bool {
+    let reached_since = query.since.is_some_and(|since| entries.first().is_some_and(|first| first.at < since));
+    reached_since || signals(entries.to_vec(), query).len() >= query.limit.unwrap_or(DEFAULT_LIMIT)
+}
+
+/// Whether `entries` have the moment `signal_id` was received.
+pub fn has_signal(entries: &[JournalEntry], signal_id: &str) -> bool {
+    entries.iter().any(|entry| {
+        entry.signal_id == signal_id && matches!(entry.event, JournalEvent::SignalReceived { .. })
+    })
+}
+
+/// Everything about one signal, if the journal has it.
+pub fn signal(entries: Vec<JournalEntry>, signal_id: &str) -> Option<SignalHistory> {
+    let entries = entries.into_iter().filter(|entry| entry.signal_id == signal_id).collect();
+    signals(entries, &SignalQuery { limit: Some(1), ..Default::default() }).pop()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::SequencePlan;
+    use serde_json::json;
+
+    fn entry(signal_id: &str, at: &str, event: JournalEvent) -> JournalEntry {
+        JournalEntry {
+            at: at.parse().unwrap(),
+            signal_id: signal_id.into(),
+            event,
+        }
+    }
+
+    fn received(signal_id: &str, at: &str, strategy: &str) -> JournalEntry {
+        entry(signal_id, at, JournalEvent::SignalReceived {
+            strategy: strategy.into(),
+            format: Default::default(),
+            signal: json!({"order": {"action": "buy"}}),
+            dry_run: false,
+        })
+    }
+
+    fn executed(signal_id: &str, status: u16) -> JournalEntry {
+        entry(signal_id, "2021-06-02T12:00:05Z", JournalEvent::RequestExecuted {
+            account: "default".into(),
+            action: "StartDeal".into(),
+            bot_id: 1234567,
+            status: Some(status),
+            latency_ms: 120,
+            error: None,
+            attempts: 1,
+        })
+    }
+
+    fn journal() -> Vec<JournalEntry> {
+        let planned = JournalEvent::SequencePlanned(SequencePlan {
+            account: "default".into(),
+            requests: vec![],
+            webhooks: vec!["discord".into()],
+        });
+        vec![
+            received("a", "2021-06-02T12:00:00Z", "fancy"),
+            entry("a", "2021-06-02T12:00:00Z", planned.clone()),
+            executed("a", 200),
+            received("b", "2021-06-02T13:00:00Z", "plain"),
+            entry("b", "2021-06-02T13:00:00Z", JournalEvent::SignalRejected { reason: "Nope".into() }),
+            received("c", "2021-06-02T14:00:00Z", "fancy"),
+            entry("c", "2021-06-02T14:00:00Z", planned),
+            executed("c", 500),
+            entry("reconcile", "2021-06-02T15:00:00Z", JournalEvent::PositionDiverged {
+                account: "default".into(),
+                bot_id: 1234567,
+                expected_open: true,
+                actually_open: false,
+                auto_closed: false,
+            }),
+        ]
+    }
+
+    fn ids_and_statuses(query: &SignalQuery) -> Vec<(String, SignalStatus)> {
+        signals(journal(), query)
+            .into_iter()
+            .map(|history| (history.signal_id, history.status))
+            .collect()
+    }
+
+    #[test]
+    fn it_lists_signals_newest_first() {
+        assert_eq!(
+            ids_and_statuses(&SignalQuery::default()),
+            [
+                ("c".into(), SignalStatus::Failed),
+                ("b".into(), SignalStatus::Rejected),
+                ("a".into(), SignalStatus::Succeeded),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_filters_by_strategy_time_and_failure() {
+        let fancy_before_two = SignalQuery {
+            strategy: Some("fancy".into()),
+            until: Some("2021-06-02T14:00:00Z".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(ids_and_statuses(&fancy_before_two), [("a".into(), SignalStatus::Succeeded)]);
+
+        let failures = SignalQuery { failed: true, limit: Some(1), ..Default::default() };
+        assert_eq!(ids_and_statuses(&failures), [("c".into(), SignalStatus::Failed)]);
+    }
+
+    #[test]
+    fn it_finds_one_signal() {
+        let history = signal(journal(), "b").unwrap();
+        assert_eq!(history.reason.as_deref(), Some("Nope"));
+        assert!(signal(journal(), "reconcile").is_none());
+    }
+
+    #[test]
+    fn it_knows_when_the_tail_is_enough() {
+        let journal = journal();
+        let last_two = &journal[journal.len() - 4..];
+        assert!(!is_enough(last_two, &SignalQuery::default()));
+        assert!(is_enough(last_two, &SignalQuery { limit: Some(1), ..Default::default() }));
+        let since_two = SignalQuery { since: Some("2021-06-02T14:00:00Z".parse().unwrap()), ..Default::default() };
+        assert!(is_enough(&journal, &since_two));
+
+        assert!(has_signal(&journal, "b"));
+        assert!(!has_signal(last_two, "b"));
+    }
+}
@@ -0,0 +1,145 @@
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::str::FromStr;
+use crate::outgoing::deal_and_bot_types::BotType;
+use super::{IncomingSignal, SignalError};
+
+/// A stop-loss or take-profit level, as sent in a signal: either a price
+/// (`95.5`) or a distance from the entry price (`"2.5%"`).
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitLevel {
+    Price(f64),
+    Percent(f64),
+}
+
+impl FromStr for ExitLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, make): (&str, fn(f64) -> ExitLevel) = match s.strip_suffix('%') {
+            Some(percent) => (percent, ExitLevel::Percent),
+            None => (s, ExitLevel::Price),
+        };
+        number
+            .trim()
+            .parse()
+            .map(make)
+            .map_err(|_| format!("{:?} is neither a price nor a percentage", s))
+    }
+}
+
+impl<'de> Deserialize<'de> for ExitLevel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Number(f64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Number(price) => Ok(ExitLevel::Price(price)),
+            Raw::Text(text) => text.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+/// Where a deal should exit, as percentages away from the entry price. That's
+/// how 3commas takes them, so prices get converted here.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Exits {
+    pub stop_loss_percentage: Option<f64>,
+    pub take_profit_percentage: Option<f64>,
+}
+
+/// Works out the exits for a deal on `side`, checking they're on the right side
+/// of the entry price: below it for a long stop-loss, above it for a long
+/// take-profit, and the other way round for shorts.
+pub fn plan_exits(signal: &IncomingSignal, side: BotType) -> Result<Exits, SignalError> {
+    let entry = signal.order.price;
+    Ok(Exits {
+        stop_loss_percentage: signal
+            .stop_loss
+            .map(|level| distance("stop_loss", level, entry, side, -1.0))
+            .transpose()?,
+        take_profit_percentage: signal
+            .take_profit
+            .map(|level| distance("take_profit", level, entry, side, 1.0))
+            .transpose()?,
+    })
+}
+
+/// How far `level` is from `entry` in percent, which has to be positive when
+/// measured in the direction `profit_sign` (1 for profit, -1 for loss) points.
+fn distance(
+    name: &str,
+    level: ExitLevel,
+    entry: Option<f64>,
+    side: BotType,
+    profit_sign: f64,
+) -> Result<f64, SignalError> {
+    let bad = |why: String| SignalError::BadExits(format!("{} {}", name, why));
+
+    let percent = match level {
+        ExitLevel::Percent(percent) => percent,
+        ExitLevel::Price(price) => {
+            let entry = entry
+                .filter(|entry| *entry > 0.0)
+                .ok_or_else(|| bad("is a price, but there's no order.price to measure it from".into()))?;
+            let side_sign = match side {
+                BotType::Long => 1.0,
+                BotType::Short => -1.0,
+            };
+            (price - entry) / entry * 100.0 * side_sign * profit_sign
+        }
+    };
+
+    if !percent.is_finite() || percent <= 0.0 {
+        return Err(bad(format!("is on the wrong side of the entry for a {:?} deal", side)));
+    }
+    if profit_sign < 0.0 && side == BotType::Long && percent >= 100.0 {
+        return Err(bad(format!("of {}% would never trigger", percent)));
+    }
+    Ok(percent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incoming::parse_signal;
+
+    fn exits(body: &str, side: BotType) -> Result<Exits, SignalError> {
+        plan_exits(&parse_signal(None, body.as_bytes()).unwrap(), side)
+    }
+
+    #[test]
+    fn it_parses_levels() {
+        assert_eq!("95.5".parse(), Ok(ExitLevel::Price(95.5)));
+        assert_eq!(" 2.5% ".parse(), Ok(ExitLevel::Percent(2.5)));
+        assert!("lots".parse::<ExitLevel>().is_err());
+    }
+
+    #[test]
+    fn it_converts_prices_to_percentages() {
+        let long = exits("buy price=100 stop_loss=95 take_profit=110", BotType::Long).unwrap();
+        assert_eq!(long.stop_loss_percentage, Some(5.0));
+        assert_eq!(long.take_profit_percentage, Some(10.0));
+
+        let short = exits(
+            r#"{"action": "sell", "price": 100, "stop_loss": 105, "take_profit": "3%"}"#,
+            BotType::Short,
+        )
+        .unwrap();
+        assert_eq!(short.stop_loss_percentage, Some(5.0));
+        assert_eq!(short.take_profit_percentage, Some(3.0));
+    }
+
+    #[test]
+    fn it_refuses_exits_on_the_wrong_side() {
+        assert!(exits("buy price=100 stop_loss=105", BotType::Long).is_err());
+        assert!(exits("sell price=100 take_profit=105", BotType::Short).is_err());
+        assert!(exits("buy stop_loss=-1%", BotType::Long).is_err());
+        assert!(exits("buy stop_loss=95", BotType::Long).is_err(), "no entry price");
+    }
+}
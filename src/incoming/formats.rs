@@ -0,0 +1,311 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str};
+use super::{ExitLevel, IncomingSignal, IncomingSignalOrder, SignalAction, SignalError};
+
+/// The shapes of webhook body we understand. See doc/signal_formats.md.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SignalFormat {
+    /// Everything TradingView knows about the order, like doc/example_signal.json
+    #[default]
+    Strategy,
+    /// A JSON object with just an `action`, plus optional `strategy`, `ticker`, `timenow`,
+    /// `contracts`, `price`, `market_position`, `stop_loss`, `take_profit`, `delay_secs`
+    /// and `execute_at`
+    Minimal,
+    /// `buy`, or `sell strategy=fancy contracts=2`
+    Text,
+    /// `action=buy&strategy=fancy`
+    Form,
+}
+
+impl SignalFormat {
+    pub fn all() -> Vec<SignalFormat> {
+        vec![
+            SignalFormat::Strategy,
+            SignalFormat::Minimal,
+            SignalFormat::Text,
+            SignalFormat::Form,
+        ]
+    }
+}
+
+impl fmt::Display for SignalFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SignalFormat::Strategy => "strategy",
+            SignalFormat::Minimal => "minimal",
+            SignalFormat::Text => "text",
+            SignalFormat::Form => "form",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How the body is encoded, before we know which format it is.
+#[derive(Debug, PartialEq)]
+enum Encoding {
+    Json,
+    Form,
+    Text,
+}
+
+/// Goes by the Content-Type when it's one we know, and by the look of the body otherwise.
+/// TradingView sends `application/json` when the alert message is valid JSON and
+/// `text/plain` when it isn't, but curl and friends send whatever they like.
+fn sniff(content_type: Option<&str>, body: &str) -> Encoding {
+    let media_type = content_type
+        .and_then(|ct| ct.split(';').next())
+        .map(|ct| ct.trim().to_ascii_lowercase());
+
+    match media_type.as_deref() {
+        Some("application/json") => Encoding::Json,
+        Some("application/x-www-form-urlencoded") => Encoding::Form,
+        _ => {
+            let body = body.trim_start();
+            if body.starts_with('{') {
+                Encoding::Json
+            } else if !body.contains(char::is_whitespace) && body.contains('=') {
+                Encoding::Form
+            } else {
+                Encoding::Text
+            }
+        }
+    }
+}
+
+/// The minimal formats (JSON, text and form) all boil down to this.
+#[derive(Deserialize, Debug)]
+struct MinimalSignal {
+    action: SignalAction,
+    strategy: Option<String>,
+    ticker: Option<String>,
+    timenow: Option<DateTime<Utc>>,
+    contracts: Option<f64>,
+    price: Option<f64>,
+    #[serde(default, deserialize_with = "lowercase")]
+    market_position: Option<String>,
+    stop_loss: Option<ExitLevel>,
+    take_profit: Option<ExitLevel>,
+    delay_secs: Option<u64>,
+    execute_at: Option<DateTime<Utc>>,
+}
+
+impl MinimalSignal {
+    fn from_pairs<I, K, V>(pairs: I) -> Result<Self, SignalError>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let (mut action, mut strategy, mut contracts, mut price) = (None, None, None, None);
+        let (mut ticker, mut timenow, mut market_position) = (None, None, None);
+        let (mut stop_loss, mut take_profit) = (None, None);
+        let (mut delay_secs, mut execute_at) = (None, None);
+
+        for (key, value) in pairs {
+            let value = value.as_ref();
+            match key.as_ref() {
+                "action" => action = Some(parse_action(value)?),
+                "strategy" => strategy = Some(value.into()),
+                "ticker" => ticker = Some(value.into()),
+                "timenow" => timenow = Some(parse_time("timenow", value)?),
+                "contracts" => contracts = Some(parse_number("contracts", value)?),
+                "price" => price = Some(parse_number("price", value)?),
+                "market_position" => market_position = Some(value.to_ascii_lowercase()),
+                "stop_loss" => stop_loss = Some(parse_exit("stop_loss", value)?),
+                "take_profit" => take_profit = Some(parse_exit("take_profit", value)?),
+                "delay_secs" => delay_secs = Some(parse_secs("delay_secs", value)?),
+                "execute_at" => execute_at = Some(parse_time("execute_at", value)?),
+                _ => (),
+            }
+        }
+
+        Ok(MinimalSignal {
+            action: action.ok_or_else(|| SignalError::Unparseable("No action given".into()))?,
+            strategy,
+            ticker,
+            timenow,
+            contracts,
+            price,
+            market_position,
+            stop_loss,
+            take_profit,
+            delay_secs,
+            execute_at,
+        })
+    }
+
+    fn into_signal(self, format: SignalFormat) -> IncomingSignal {
+        IncomingSignal {
+            strategy: self.strategy,
+            ticker: self.ticker,
+            timenow: self.timenow,
+            time: None,
+            position_size: None,
+            order: IncomingSignalOrder {
+                action: self.action,
+                contracts: self.contracts,
+                price: self.price,
+                id: None,
+                comment: None,
+                alert_message: None,
+            },
+            market_position: self.market_position,
+            market_position_size: None,
+            prev_market_position: None,
+            prev_market_position_size: None,
+            stop_loss: self.stop_loss,
+            take_profit: self.take_profit,
+            delay_secs: self.delay_secs,
+            execute_at: self.execute_at,
+            dry_run: false,
+            format,
+        }
+    }
+}
+
+/// TradingView sends `long`, but people typing alerts by hand send `Long`.
+pub(super) fn lowercase<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.map(|value| value.to_ascii_lowercase()))
+}
+
+pub(super) fn parse_action(value: &str) -> Result<SignalAction, SignalError> {
+    let keyword = serde_json::Value::String(value.trim().to_ascii_lowercase());
+    serde_json::from_value(keyword)
+        .map_err(|_| SignalError::Unparseable(format!("Unknown action {:?}", value)))
+}
+
+pub(super) fn parse_number(key: &str, value: &str) -> Result<f64, SignalError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| SignalError::Unparseable(format!("{} isn't a number: {:?}", key, value)))
+}
+
+pub(super) fn parse_secs(key: &str, value: &str) -> Result<u64, SignalError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| SignalError::Unparseable(format!("{} isn't a number of seconds: {:?}", key, value)))
+}
+
+pub(super) fn parse_exit(key: &str, value: &str) -> Result<ExitLevel, SignalError> {
+    value
+        .parse()
+        .map_err(|why| SignalError::Unparseable(format!("{}: {}", key, why)))
+}
+
+fn parse_time(key: &str, value: &str) -> Result<DateTime<Utc>, SignalError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| SignalError::Unparseable(format!("{} isn't a time: {:?}", key, value)))
+}
+
+fn parse_json(body: &str) -> Result<IncomingSignal, SignalError> {
+    let value: serde_json::Value = serde_json::from_str(body)
+        .map_err(|e| SignalError::Unparseable(format!("Bad JSON: {}", e)))?;
+
+    if value.get("order").is_some() {
+        let mut signal: IncomingSignal = serde_json::from_value(value)
+            .map_err(|e| SignalError::Unparseable(format!("Bad strategy signal: {}", e)))?;
+        signal.format = SignalFormat::Strategy;
+        Ok(signal)
+    } else if value.get("action").is_some() {
+        let minimal: MinimalSignal = serde_json::from_value(value)
+            .map_err(|e| SignalError::Unparseable(format!("Bad minimal signal: {}", e)))?;
+        Ok(minimal.into_signal(SignalFormat::Minimal))
+    } else {
+        Err(SignalError::Unparseable("JSON has neither an order nor an action".into()))
+    }
+}
+
+fn parse_form(body: &str) -> Result<IncomingSignal, SignalError> {
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(body.trim())
+        .map_err(|e| SignalError::Unparseable(format!("Bad form data: {}", e)))?;
+    Ok(MinimalSignal::from_pairs(pairs)?.into_signal(SignalFormat::Form))
+}
+
+/// `<action> [key=value ...]`
+fn parse_text(body: &str) -> Result<IncomingSignal, SignalError> {
+    let mut words = body.split_whitespace();
+    let action = words
+        .next()
+        .ok_or_else(|| SignalError::Unparseable("Empty body".into()))?;
+
+    let mut pairs = vec![("action", action)];
+    for word in words {
+        match word.split_once('=') {
+            Some(pair) => pairs.push(pair),
+            None => return Err(SignalError::Unparseable(format!("Expected key=value, got {:?}", word))),
+        }
+    }
+
+    Ok(MinimalSignal::from_pairs(pairs)?.into_signal(SignalFormat::Text))
+}
+
+/// Makes sense of a webhook body in any of the formats we know.
+pub fn parse_signal(content_type: Option<&str>, body: &[u8]) -> Result<IncomingSignal, SignalError> {
+    let body = str::from_utf8(body)
+        .map_err(|_| SignalError::Unparseable("Body isn't UTF-8".into()))?;
+
+    match sniff(content_type, body) {
+        Encoding::Json => parse_json(body),
+        Encoding::Form => parse_form(body),
+        Encoding::Text => parse_text(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content_type: Option<&str>, body: &str) -> IncomingSignal {
+        parse_signal(content_type, body.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn it_sniffs_encodings() {
+        assert_eq!(sniff(Some("application/json; charset=utf-8"), "buy"), Encoding::Json);
+        assert_eq!(sniff(Some("application/x-www-form-urlencoded"), "buy"), Encoding::Form);
+        assert_eq!(sniff(Some("text/plain"), r#" {"action": "buy"}"#), Encoding::Json);
+        assert_eq!(sniff(None, "action=buy&contracts=1"), Encoding::Form);
+        assert_eq!(sniff(None, "buy strategy=fancy"), Encoding::Text);
+    }
+
+    #[test]
+    fn it_parses_minimal_json() {
+        let signal = parse(Some("application/json"), r#"{"action": "sell", "contracts": 1}"#);
+        assert_eq!(signal.format, SignalFormat::Minimal);
+        assert!(matches!(signal.order.action, SignalAction::Sell));
+        assert_eq!(signal.order.contracts, Some(1.0));
+    }
+
+    #[test]
+    fn it_parses_text_commands() {
+        let signal = parse(Some("text/plain"), "BUY strategy=fancy price=0.3\n");
+        assert_eq!(signal.format, SignalFormat::Text);
+        assert!(matches!(signal.order.action, SignalAction::Buy));
+        assert_eq!(signal.strategy.as_deref(), Some("fancy"));
+        assert_eq!(signal.order.price, Some(0.3));
+    }
+
+    #[test]
+    fn it_parses_forms() {
+        let signal = parse(None, "action=sell&strategy=fancy+v1");
+        assert_eq!(signal.format, SignalFormat::Form);
+        assert!(matches!(signal.order.action, SignalAction::Sell));
+        assert_eq!(signal.strategy.as_deref(), Some("fancy v1"));
+    }
+
+    #[test]
+    fn it_rejects_nonsense() {
+        assert!(parse_signal(Some("text/plain"), b"blah blah blah").is_err());
+        assert!(parse_signal(None, b"buy contracts=lots").is_err());
+        assert!(parse_signal(None, b"strategy=fancy").is_err());
+        assert!(parse_signal(None, br#"{"wrong": "json"}"#).is_err());
+        assert!(parse_signal(None, b"").is_err());
+    }
+}
@@ -0,0 +1,361 @@
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use opentelemetry::KeyValue;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use super::outgoing::{OutgoingRequest, deal_and_bot_types::{ActionType, BotType}};
+use crate::{balances::BALANCES, markets::MARKETS, response::ErrorCode, risk::RiskViolation, settings::{OverrideField, Settings, StrategySettings, DEFAULT_STRATEGY}, telemetry};
+pub mod exits;
+pub use exits::ExitLevel;
+pub mod formats;
+pub use formats::{parse_signal, SignalFormat};
+pub mod overrides;
+pub mod rules;
+pub mod sizing;
+pub mod validation;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignalAction {
+    /// Close any short deal and open a long one
+    Buy,
+    /// Close any long deal and open a short one
+    Sell,
+    CloseLong,
+    CloseShort,
+    /// Close both deals
+    Flatten,
+    /// Flip the position given as `market_position`
+    Reverse,
+    StartBots,
+    StopBots,
+    /// Close both deals and stop both bots
+    PanicSell,
+}
+
+/// A trade signal. Only `order.action` is required; what else we get depends on
+/// which format it came in (see `SignalFormat`).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IncomingSignal {
+    pub strategy: Option<String>,
+    /// `{{ticker}}`
+    pub ticker: Option<String>,
+    /// `{{timenow}}`, when the alert fired
+    pub timenow: Option<DateTime<Utc>>,
+    /// `{{time}}`, when the bar opened
+    pub time: Option<DateTime<Utc>>,
+    pub position_size: Option<f64>,
+    pub order: IncomingSignalOrder,
+    #[serde(default, deserialize_with = "formats::lowercase")]
+    pub market_position: Option<String>,
+    pub market_position_size: Option<f64>,
+    #[serde(default, deserialize_with = "formats::lowercase")]
+    pub prev_market_position: Option<String>,
+    pub prev_market_position_size: Option<f64>,
+    /// A price, or a percentage from `order.price` like `"2%"`
+    pub stop_loss: Option<ExitLevel>,
+    /// A price, or a percentage from `order.price` like `"5%"`
+    pub take_profit: Option<ExitLevel>,
+    /// Hold the requests this long before sending them
+    pub delay_secs: Option<u64>,
+    /// Hold the requests until then
+    pub execute_at: Option<DateTime<Utc>>,
+    /// Plan the requests but don't send them, from `alert_message`
+    #[serde(skip)]
+    pub dry_run: bool,
+    #[serde(skip)]
+    pub format: SignalFormat,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IncomingSignalOrder {
+    pub action: SignalAction,
+    pub contracts: Option<f64>,
+    pub price: Option<f64>,
+    pub id: Option<String>,
+    pub comment: Option<String>,
+    pub alert_message: Option<String>,
+}
+
+/// Why we turned a signal away.
+#[derive(Debug)]
+pub enum SignalError {
+    Unparseable(String),
+    /// The webhook body was over `limits.max_body_bytes`
+    TooLarge { max_bytes: usize },
+    UnknownStrategy(String),
+    FormatNotAccepted { strategy: String, format: SignalFormat },
+    /// `alert_message` tried to change something the strategy doesn't let it
+    OverrideNotAllowed { strategy: String, field: OverrideField },
+    BadSize(String),
+    BadExits(String),
+    /// `delay_secs` or `execute_at` is too far off
+    BadSchedule(String),
+    RiskLimit(RiskViolation),
+    FailedChecks(Vec<String>),
+    /// The same as an earlier signal, this many seconds ago
+    Duplicate { signal_id: String, secs_ago: i64 },
+    /// Over a limit on how many signals we take, until there's room in this many seconds
+    RateLimited { limit: String, retry_after_secs: i64 },
+    Paused(String),
+    ShuttingDown,
+}
+
+impl fmt::Display for SignalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignalError::Unparseable(why) => write!(f, "Can't parse signal: {}", why),
+            SignalError::TooLarge { max_bytes } => write!(f, "Signal is over {} bytes", max_bytes),
+            SignalError::UnknownStrategy(name) => write!(f, "Unknown strategy {:?}", name),
+            SignalError::FormatNotAccepted { strategy, format } => {
+                write!(f, "Strategy {:?} doesn't accept {} signals", strategy, format)
+            }
+            SignalError::OverrideNotAllowed { strategy, field } => {
+                write!(f, "Strategy {:?} doesn't take {} from alert_message", strategy, field)
+            }
+            SignalError::BadSize(why) => write!(f, "Refusing deal size: {}", why),
+            SignalError::BadExits(why) => write!(f, "Refusing exits: {}", why),
+            SignalError::BadSchedule(why) => write!(f, "Refusing to wait: {}", why),
+            SignalError::RiskLimit(violation) => write!(f, "Risk limit: {}", violation),
+            SignalError::FailedChecks(failures) => {
+                write!(f, "Failed sanity checks: {}", failures.join("; "))
+            }
+            SignalError::Duplicate { signal_id, secs_ago } => {
+                write!(f, "Same as signal {} from {}s ago", signal_id, secs_ago)
+            }
+            SignalError::RateLimited { limit, retry_after_secs } => {
+                write!(f, "Over the limit of {}, try again in {}s", limit, retry_after_secs)
+            }
+            SignalError::Paused(strategy) => write!(f, "Strategy {:?} is paused", strategy),
+            SignalError::ShuttingDown => write!(f, "Shutting down"),
+        }
+    }
+}
+
+impl SignalError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            SignalError::Unparseable(_) => ErrorCode::Schema,
+            SignalError::TooLarge { .. } => ErrorCode::TooLarge,
+            SignalError::UnknownStrategy(_) => ErrorCode::UnknownStrategy,
+            SignalError::FormatNotAccepted { .. } => ErrorCode::FormatNotAccepted,
+            SignalError::OverrideNotAllowed { .. } => ErrorCode::OverrideNotAllowed,
+            SignalError::BadSize(_) | SignalError::BadExits(_) | SignalError::BadSchedule(_) => {
+                ErrorCode::InvalidOrder
+            }
+            SignalError::RiskLimit(_) => ErrorCode::RiskLimit,
+            SignalError::FailedChecks(_) => ErrorCode::FailedChecks,
+            SignalError::Duplicate { .. } => ErrorCode::Duplicate,
+            SignalError::RateLimited { .. } => ErrorCode::RateLimited,
+            SignalError::Paused(_) => ErrorCode::Paused,
+            SignalError::ShuttingDown => ErrorCode::ShuttingDown,
+        }
+    }
+}
+
+impl warp::reject::Reject for SignalError {}
+
+pub type Action = (ActionType, BotType);
+
+impl IncomingSignal {
+    pub fn strategy_name(&self) -> &str {
+        self.strategy.as_deref().unwrap_or(DEFAULT_STRATEGY)
+    }
+
+    /// The settings for the strategy this signal is for, as long as it takes
+    /// signals in this format.
+    pub fn strategy_settings(&self, settings: &Settings) -> Result<StrategySettings, SignalError> {
+        let strategy = settings
+            .strategy(self.strategy.as_deref())
+            .ok_or_else(|| SignalError::UnknownStrategy(self.strategy_name().into()))?;
+
+        if !strategy.accepts(self.format) {
+            return Err(SignalError::FormatNotAccepted {
+                strategy: self.strategy_name().into(),
+                format: self.format,
+            });
+        }
+
+        Ok(strategy)
+    }
+
+    /// Works out what to ask 3commas to do, or why we shouldn't.
+    pub fn to_requests(&self, strategy: &StrategySettings) -> Result<Vec<OutgoingRequest>, SignalError> {
+        let action = KeyValue::new("signal.action", format!("{:?}", self.order.action));
+        telemetry::in_span_sync("plan_requests", vec![action], || self.plan_requests(strategy))
+    }
+
+    fn plan_requests(&self, strategy: &StrategySettings) -> Result<Vec<OutgoingRequest>, SignalError> {
+        let market = self.ticker.as_deref().and_then(|ticker| MARKETS.find(ticker));
+        let order_size = match &strategy.sizing {
+            Some(sizing) => {
+                let balance = sizing.rule.balance().and_then(|(account_id, currency)| BALANCES.find(account_id, currency));
+                sizing::order_size(&self.order, sizing, market.as_ref(), balance)?
+            }
+            None => None,
+        };
+
+        let actions = match rules::matching(&strategy.rules, self) {
+            Some(rule) => {
+                info!("Following rule {}", rule);
+                telemetry::record(KeyValue::new("rule", rule.to_string()));
+                rules::actions(rule)
+            }
+            None => self.create_actions().into_iter().map(|action| (action, 0)).collect(),
+        };
+
+        actions
+            .into_iter()
+            .filter(|((_, bot_type), _)| strategy.direction.allows(bot_type))
+            .map(|((action_type, bot_type), delay)| {
+                let exits = if ActionType::is_start(&action_type) {
+                    exits::plan_exits(self, bot_type)?
+                } else {
+                    Default::default()
+                };
+                Ok(OutgoingRequest::new((action_type, bot_type))
+                    .sized(order_size.clone())
+                    .with_exits(exits)
+                    .held(delay))
+            })
+            .collect()
+    }
+
+    fn create_actions(&self) -> Vec<Action> {
+        use BotType::*;
+        use ActionType::*;
+        use SignalAction::*;
+
+        // The order of these is important! Have to close the open deal before we try to open one.
+        match self.order.action {
+            Buy => vec![
+                (CloseDeal, Short),
+                (StartDeal, Long),
+            ],
+            Sell => vec![
+                (CloseDeal, Long),
+                (StartDeal, Short),
+            ],
+            CloseLong => vec![(CloseDeal, Long)],
+            CloseShort => vec![(CloseDeal, Short)],
+            Flatten => vec![
+                (CloseDeal, Long),
+                (CloseDeal, Short),
+            ],
+            Reverse => match self.market_position.as_deref() {
+                Some("long") => vec![
+                    (CloseDeal, Long),
+                    (StartDeal, Short),
+                ],
+                Some("short") => vec![
+                    (CloseDeal, Short),
+                    (StartDeal, Long),
+                ],
+                other => {
+                    warn!("Can't reverse position {:?}, doing nothing", other);
+                    vec![]
+                }
+            },
+            StartBots => vec![
+                (StartBot, Long),
+                (StartBot, Short),
+            ],
+            StopBots => vec![
+                (StopBot, Long),
+                (StopBot, Short),
+            ],
+            PanicSell => vec![
+                (CloseDeal, Long),
+                (CloseDeal, Short),
+                (StopBot, Long),
+                (StopBot, Short),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actions_for(body: &str) -> Vec<String> {
+        parse_signal(None, body.as_bytes())
+            .unwrap()
+            .create_actions()
+            .iter()
+            .map(|action| format!("{:?}", action))
+            .collect()
+    }
+
+    #[test]
+    fn it_closes_before_it_opens() {
+        assert_eq!(actions_for("buy"), ["(CloseDeal, Short)", "(StartDeal, Long)"]);
+        assert_eq!(actions_for("sell"), ["(CloseDeal, Long)", "(StartDeal, Short)"]);
+    }
+
+    #[test]
+    fn it_maps_close_only_actions() {
+        assert_eq!(actions_for("close_long"), ["(CloseDeal, Long)"]);
+        assert_eq!(actions_for("close_short"), ["(CloseDeal, Short)"]);
+        assert_eq!(actions_for("flatten"), ["(CloseDeal, Long)", "(CloseDeal, Short)"]);
+    }
+
+    #[test]
+    fn it_reverses_the_current_position() {
+        assert_eq!(
+            actions_for("reverse market_position=short"),
+            ["(CloseDeal, Short)", "(StartDeal, Long)"]
+        );
+        assert_eq!(
+            actions_for(r#"{"action": "reverse", "market_position": "Long"}"#),
+            ["(CloseDeal, Long)", "(StartDeal, Short)"]
+        );
+        assert_eq!(
+            actions_for(r#"{"order": {"action": "reverse"}, "market_position": "SHORT"}"#),
+            ["(CloseDeal, Short)", "(StartDeal, Long)"]
+        );
+        assert!(actions_for("reverse").is_empty());
+    }
+
+    #[test]
+    fn it_leaves_the_disabled_side_alone() {
+        use crate::settings::Direction;
+
+        let requests_for = |body: &str, direction| {
+            let strategy = StrategySettings { direction, ..Default::default() };
+            parse_signal(None, body.as_bytes())
+                .unwrap()
+                .to_requests(&strategy)
+                .unwrap()
+                .iter()
+                .map(|request| format!("{:?} {}", request.action, request.bot_id))
+                .collect::<Vec<_>>()
+        };
+
+        // Long bot is 1234567, short is 7654321
+        assert_eq!(requests_for("buy", Direction::LongOnly), ["StartDeal 1234567"]);
+        assert_eq!(requests_for("sell", Direction::LongOnly), ["CloseDeal 1234567"]);
+        assert_eq!(requests_for("buy", Direction::ShortOnly), ["CloseDeal 7654321"]);
+        assert_eq!(requests_for("stop_bots", Direction::ShortOnly), ["StopBot 7654321"]);
+    }
+
+    #[test]
+    fn it_only_sends_exits_with_new_deals() {
+        let requests = parse_signal(None, b"buy price=100 stop_loss=2% take_profit=104")
+            .unwrap()
+            .to_requests(&StrategySettings::default())
+            .unwrap();
+
+        assert_eq!(requests[0].stop_loss_percentage, None);
+        assert_eq!(requests[1].stop_loss_percentage, Some(2.0));
+        assert_eq!(requests[1].take_profit_percentage, Some(4.0));
+    }
+
+    #[test]
+    fn panic_sell_closes_then_stops() {
+        assert_eq!(
+            actions_for(r#"{"action": "panic_sell"}"#),
+            ["(CloseDeal, Long)", "(CloseDeal, Short)", "(StopBot, Long)", "(StopBot, Short)"]
+        );
+    }
+}
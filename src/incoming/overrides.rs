@@ -0,0 +1,200 @@
+use serde_json::Value;
+use crate::settings::{OverrideField, Settings, StrategySettings};
+use super::{
+    formats::{parse_action, parse_exit, parse_number, parse_secs},
+    ExitLevel, IncomingSignal, SignalAction, SignalError,
+};
+
+/// Changes to an order carried in its `alert_message`, as a JSON object or
+/// `key=value` pairs: `{"contracts": 2, "dry_run": true}` or `contracts=2 dry_run=true`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Overrides {
+    pub strategy: Option<String>,
+    pub action: Option<SignalAction>,
+    pub contracts: Option<f64>,
+    pub stop_loss: Option<ExitLevel>,
+    pub take_profit: Option<ExitLevel>,
+    pub delay_secs: Option<u64>,
+    pub dry_run: Option<bool>,
+}
+
+impl Overrides {
+    /// The overrides in `message`, or none if it's just a message. It counts as
+    /// overrides if it's a JSON object or has an `=` in it, and then anything
+    /// we don't understand in it is an error.
+    pub fn parse(message: &str) -> Result<Option<Overrides>, SignalError> {
+        let message = message.trim();
+        let pairs: Vec<(String, String)> = if message.starts_with('{') {
+            let object: serde_json::Map<String, Value> = serde_json::from_str(message)
+                .map_err(|e| SignalError::Unparseable(format!("Bad alert_message JSON: {}", e)))?;
+            object
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Value::String(text) => (key, text),
+                    other => (key, other.to_string()),
+                })
+                .collect()
+        } else if message.contains('=') {
+            message
+                .split_whitespace()
+                .map(|word| match word.split_once('=') {
+                    Some((key, value)) => Ok((key.to_string(), value.to_string())),
+                    None => Err(SignalError::Unparseable(format!("Expected key=value in alert_message, got {:?}", word))),
+                })
+                .collect::<Result<_, _>>()?
+        } else {
+            return Ok(None);
+        };
+
+        let mut overrides = Overrides::default();
+        for (key, value) in pairs {
+            match key.as_str() {
+                "strategy" => overrides.strategy = Some(value),
+                "action" => overrides.action = Some(parse_action(&value)?),
+                "contracts" => overrides.contracts = Some(parse_number("contracts", &value)?),
+                "stop_loss" => overrides.stop_loss = Some(parse_exit("stop_loss", &value)?),
+                "take_profit" => overrides.take_profit = Some(parse_exit("take_profit", &value)?),
+                "delay_secs" => overrides.delay_secs = Some(parse_secs("delay_secs", &value)?),
+                "dry_run" => {
+                    let dry_run = value.trim().parse().map_err(|_| {
+                        SignalError::Unparseable(format!("dry_run should be true or false, not {:?}", value))
+                    })?;
+                    overrides.dry_run = Some(dry_run);
+                }
+                _ => return Err(SignalError::Unparseable(format!("Unknown alert_message key {:?}", key))),
+            }
+        }
+        Ok(Some(overrides))
+    }
+
+    /// The fields this overrides.
+    pub fn fields(&self) -> Vec<OverrideField> {
+        let given = [
+            (self.strategy.is_some(), OverrideField::Strategy),
+            (self.action.is_some(), OverrideField::Action),
+            (self.contracts.is_some(), OverrideField::Contracts),
+            (self.stop_loss.is_some(), OverrideField::StopLoss),
+            (self.take_profit.is_some(), OverrideField::TakeProfit),
+            (self.delay_secs.is_some(), OverrideField::DelaySecs),
+            (self.dry_run.is_some(), OverrideField::DryRun),
+        ];
+        given.iter().filter(|(given, _)| *given).map(|(_, field)| *field).collect()
+    }
+}
+
+impl IncomingSignal {
+    /// Finds the strategy this signal is for, after applying the overrides in
+    /// its `alert_message`. Only strategies with `alert_message_overrides`
+    /// read it, and only those fields may be overridden. When it moves the
+    /// signal to another strategy, that one has to allow the rest of them too.
+    pub fn route(&mut self, settings: &Settings) -> Result<StrategySettings, SignalError> {
+        let strategy = self.strategy_settings(settings)?;
+        if strategy.alert_message_overrides.is_empty() {
+            return Ok(strategy);
+        }
+        let overrides = match self.order.alert_message.as_deref().map(Overrides::parse).transpose()?.flatten() {
+            Some(overrides) => overrides,
+            None => return Ok(strategy),
+        };
+
+        let fields = overrides.fields();
+        let not_allowed = |strategy: &StrategySettings| {
+            fields.iter().copied().find(|field| {
+                *field != OverrideField::Strategy && !strategy.alert_message_overrides.contains(field)
+            })
+        };
+        if let Some(field) = fields.iter().copied().find(|field| !strategy.alert_message_overrides.contains(field)) {
+            return Err(SignalError::OverrideNotAllowed {
+                strategy: self.strategy_name().into(),
+                field,
+            });
+        }
+
+        let strategy = match overrides.strategy {
+            Some(target) if self.strategy.as_ref() != Some(&target) => {
+                self.strategy = Some(target);
+                let target = self.strategy_settings(settings)?;
+                if let Some(field) = not_allowed(&target) {
+                    return Err(SignalError::OverrideNotAllowed {
+                        strategy: self.strategy_name().into(),
+                        field,
+                    });
+                }
+                target
+            }
+            _ => strategy,
+        };
+
+        if let Some(action) = overrides.action {
+            self.order.action = action;
+        }
+        self.order.contracts = overrides.contracts.or(self.order.contracts);
+        self.stop_loss = overrides.stop_loss.or(self.stop_loss);
+        self.take_profit = overrides.take_profit.or(self.take_profit);
+        self.delay_secs = overrides.delay_secs.or(self.delay_secs);
+        self.dry_run = overrides.dry_run.unwrap_or(self.dry_run);
+        Ok(strategy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incoming::parse_signal;
+
+    fn settings() -> Settings {
+        use OverrideField::*;
+        let mut settings = Settings::default();
+        let fancy = StrategySettings {
+            alert_message_overrides: vec![Strategy, Contracts, StopLoss, DryRun],
+            ..StrategySettings::default()
+        };
+        settings.strategies.insert("fancy".into(), fancy);
+        settings.strategies.insert("plain".into(), StrategySettings::default());
+        settings
+    }
+
+    fn route(alert_message: &str) -> Result<IncomingSignal, SignalError> {
+        let body = serde_json::json!({
+            "strategy": "fancy",
+            "order": {"action": "buy", "contracts": 1, "alert_message": alert_message},
+        });
+        let mut signal = parse_signal(None, body.to_string().as_bytes()).unwrap();
+        signal.route(&settings()).map(|_| signal)
+    }
+
+    #[test]
+    fn it_reads_json_and_pairs() {
+        let json = Overrides::parse(r#"{"contracts": 2, "stop_loss": "1.5%", "dry_run": true}"#).unwrap().unwrap();
+        let pairs = Overrides::parse("contracts=2 stop_loss=1.5% dry_run=true").unwrap().unwrap();
+        assert_eq!(json, pairs);
+        assert_eq!(json.fields(), [OverrideField::Contracts, OverrideField::StopLoss, OverrideField::DryRun]);
+
+        assert_eq!(Overrides::parse("Long entry").unwrap(), None);
+        assert!(Overrides::parse("contracts=lots").is_err());
+        assert!(Overrides::parse("leverage=10").is_err());
+    }
+
+    #[test]
+    fn it_overrides_whitelisted_fields() {
+        let signal = route("contracts=3 dry_run=true").unwrap();
+        assert_eq!(signal.order.contracts, Some(3.0));
+        assert!(signal.dry_run);
+        assert_eq!(route("strategy=plain").unwrap().strategy.as_deref(), Some("plain"));
+
+        // plain doesn't take any overrides itself
+        match route("strategy=plain contracts=3 dry_run=true") {
+            Err(SignalError::OverrideNotAllowed { strategy, field }) => {
+                assert_eq!(strategy, "plain");
+                assert_eq!(field, OverrideField::Contracts);
+            }
+            other => panic!("Expected OverrideNotAllowed, got {:?}", other.map(|signal| signal.order.contracts)),
+        }
+
+        assert_eq!(route("Long entry").unwrap().order.contracts, Some(1.0));
+        assert!(matches!(
+            route("action=sell"),
+            Err(SignalError::OverrideNotAllowed { field: OverrideField::Action, .. })
+        ));
+    }
+}
@@ -0,0 +1,95 @@
+use crate::settings::{RuleConditions, RuleSettings, RuleStep};
+use super::{Action, IncomingSignal};
+
+/// An action to take, and how long to hold it back after the first ones.
+pub type DelayedAction = (Action, u64);
+
+fn same_position(wanted: &Option<String>, actual: &Option<String>) -> bool {
+    wanted.as_ref().is_none_or(|wanted| {
+        actual.as_ref().is_some_and(|actual| actual.eq_ignore_ascii_case(wanted))
+    })
+}
+
+fn matches(when: &RuleConditions, signal: &IncomingSignal) -> bool {
+    let order = &signal.order;
+    when.action.is_none_or(|action| action == order.action)
+        && when.comment.as_ref().is_none_or(|comment| order.comment.as_ref() == Some(comment))
+        && when.order_id.as_ref().is_none_or(|id| order.id.as_ref() == Some(id))
+        && when.alert_message.as_ref().is_none_or(|pattern| {
+            order.alert_message.as_deref().is_some_and(|message| pattern.0.is_match(message))
+        })
+        && same_position(&when.market_position, &signal.market_position)
+        && same_position(&when.prev_market_position, &signal.prev_market_position)
+}
+
+/// The first of `rules` that `signal` matches.
+pub fn matching<'a>(rules: &'a [RuleSettings], signal: &IncomingSignal) -> Option<&'a RuleSettings> {
+    rules.iter().find(|rule| matches(&rule.when, signal))
+}
+
+/// What a rule's steps come to. Each `wait` adds to the delay of every request after it.
+pub fn actions(rule: &RuleSettings) -> Vec<DelayedAction> {
+    let mut delay: u64 = 0;
+    let mut actions = vec![];
+    for step in &rule.then {
+        match step {
+            RuleStep::Wait(secs) => delay = delay.saturating_add(*secs),
+            RuleStep::Request { action, bots } => {
+                actions.extend(bots.iter().map(|bot_type| ((action.clone(), *bot_type), delay)));
+            }
+        }
+    }
+    actions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incoming::parse_signal;
+
+    fn rules(yaml: &str) -> Vec<RuleSettings> {
+        let mut config = config::Config::default();
+        config.merge(config::File::from_str(yaml, config::FileFormat::Yaml)).unwrap();
+        config.get("rules").unwrap()
+    }
+
+    fn rule_for<'a>(rules: &'a [RuleSettings], body: &str) -> Option<&'a str> {
+        let signal = parse_signal(None, body.as_bytes()).unwrap();
+        matching(rules, &signal).and_then(|rule| rule.name.as_deref())
+    }
+
+    #[test]
+    fn it_picks_the_first_rule_that_matches() {
+        let rules = rules(r#"
+rules:
+  - name: take profit
+    when:
+      action: sell
+      comment: TP1
+    then: [close_deal long]
+  - name: flip
+    when:
+      alert_message: "^flip to (long|short)"
+      market_position: Long
+    then: [close_deal short, wait 30, start_deal long]
+  - name: anything else sold
+    when:
+      action: sell
+    then: [close_deal both]
+"#);
+
+        assert_eq!(rule_for(&rules, r#"{"order": {"action": "sell", "comment": "TP1"}}"#), Some("take profit"));
+        assert_eq!(rule_for(&rules, r#"{"order": {"action": "sell", "comment": "TP2"}}"#), Some("anything else sold"));
+        assert_eq!(
+            rule_for(&rules, r#"{"order": {"action": "buy", "alert_message": "flip to long"}, "market_position": "long"}"#),
+            Some("flip")
+        );
+        assert_eq!(rule_for(&rules, r#"{"order": {"action": "buy", "alert_message": "flip to long"}}"#), None);
+        assert_eq!(rule_for(&rules, "buy"), None);
+
+        assert_eq!(
+            format!("{:?}", actions(&rules[1])),
+            "[((CloseDeal, Short), 0), ((StartDeal, Long), 30)]"
+        );
+    }
+}
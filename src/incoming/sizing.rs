@@ -0,0 +1,207 @@
+use log::warn;
+use crate::{
+    markets::Market,
+    outgoing::deal_and_bot_types::{CurrencyType, OrderSize},
+    settings::{SizingRule, SizingSettings},
+};
+use super::{IncomingSignalOrder, SignalError};
+
+/// Works out the deal size for `order`, on `market` if we know it, and with the
+/// `balance` of the account sizing goes by, if it goes by one. `None` means
+/// "use the bot's base order".
+pub fn order_size(
+    order: &IncomingSignalOrder,
+    sizing: &SizingSettings,
+    market: Option<&Market>,
+    balance: Option<f64>,
+) -> Result<Option<OrderSize>, SignalError> {
+    use CurrencyType::*;
+    use SizingRule::*;
+
+    let (amount, currency_type) = match &sizing.rule {
+        Contracts | Multiplier { .. } => {
+            let contracts = match order.contracts {
+                Some(contracts) => contracts,
+                None => {
+                    warn!("Signal has no contracts to size by, using the bot's base order");
+                    return Ok(None);
+                }
+            };
+            let factor = match sizing.rule {
+                Multiplier { factor } => factor,
+                _ => 1.0,
+            };
+            (contracts * factor, Base)
+        }
+        FixedQuote { amount } => (*amount, Quote),
+        PercentOfBalance { percent, account_id, currency } => match balance {
+            Some(balance) => (balance * percent / 100.0, Quote),
+            None => {
+                return Err(SignalError::BadSize(format!(
+                    "we don't know how much {} account {} has",
+                    currency, account_id
+                )))
+            }
+        },
+    };
+
+    if !amount.is_finite() || amount <= 0.0 {
+        return Err(SignalError::BadSize(format!("{} isn't a usable deal size", amount)));
+    }
+
+    if let Some(limit) = sizing.refuse_above {
+        if amount > limit {
+            return Err(SignalError::BadSize(format!(
+                "{} is over the limit of {}",
+                amount, limit
+            )));
+        }
+    }
+
+    let amount = match (sizing.min, sizing.max) {
+        (Some(min), _) if amount < min => min,
+        (_, Some(max)) if amount > max => max,
+        _ => amount,
+    };
+
+    let amount = match market {
+        Some(market) => fit_to_market(amount, currency_type, order.price, market)?,
+        None => amount,
+    };
+
+    Ok(Some(OrderSize::new(amount, currency_type)))
+}
+
+/// Rounds a base amount down to `market`'s lot step, and refuses deals under
+/// its minimum. Base amounts need the order's price for that.
+fn fit_to_market(
+    amount: f64,
+    currency_type: CurrencyType,
+    price: Option<f64>,
+    market: &Market,
+) -> Result<f64, SignalError> {
+    let amount = match (currency_type, market.lot_step) {
+        (CurrencyType::Base, Some(step)) if step > 0.0 => round_down(amount, step),
+        _ => amount,
+    };
+    if amount <= 0.0 {
+        return Err(SignalError::BadSize(format!(
+            "it's less than one lot of {} on {}",
+            market.base, market.symbol
+        )));
+    }
+
+    let notional = match currency_type {
+        CurrencyType::Base => price.map(|price| amount * price),
+        CurrencyType::Quote => Some(amount),
+    };
+    match (notional, market.min_notional) {
+        (Some(notional), Some(min)) if notional < min => Err(SignalError::BadSize(format!(
+            "{} {} is under {}'s minimum of {} {}",
+            notional, market.quote, market.symbol, min, market.quote
+        ))),
+        _ => Ok(amount),
+    }
+}
+
+/// `amount` rounded down to a multiple of `step`, without float noise like
+/// 0.30000000000000004.
+fn round_down(amount: f64, step: f64) -> f64 {
+    let steps = (amount / step + 1e-9).floor();
+    let decimals = step.to_string().split('.').nth(1).map_or(0, str::len) as i32;
+    let scale = 10f64.powi(decimals);
+    (steps * step * scale).round() / scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incoming::SignalAction;
+
+    fn order(contracts: Option<f64>) -> IncomingSignalOrder {
+        IncomingSignalOrder {
+            action: SignalAction::Buy,
+            contracts,
+            price: Some(0.3),
+            id: None,
+            comment: None,
+            alert_message: None,
+        }
+    }
+
+    fn sizing(rule: SizingRule) -> SizingSettings {
+        SizingSettings {
+            rule,
+            min: Some(1.0),
+            max: Some(100.0),
+            refuse_above: Some(1000.0),
+        }
+    }
+
+    fn amount(result: Result<Option<OrderSize>, SignalError>) -> Option<String> {
+        result.unwrap().map(|size| size.amount)
+    }
+
+    #[test]
+    fn it_scales_contracts() {
+        let rule = sizing(SizingRule::Multiplier { factor: 2.5 });
+        assert_eq!(amount(order_size(&order(Some(4.0)), &rule, None, None)), Some("10".into()));
+        assert_eq!(amount(order_size(&order(None), &rule, None, None)), None);
+    }
+
+    #[test]
+    fn it_caps_sizes() {
+        let rule = sizing(SizingRule::Contracts);
+        assert_eq!(amount(order_size(&order(Some(0.5)), &rule, None, None)), Some("1".into()));
+        assert_eq!(amount(order_size(&order(Some(500.0)), &rule, None, None)), Some("100".into()));
+    }
+
+    #[test]
+    fn it_sizes_in_quote_currency() {
+        let rule = sizing(SizingRule::FixedQuote { amount: 50.0 });
+        let size = order_size(&order(None), &rule, None, None).unwrap().unwrap();
+        assert_eq!(size, OrderSize::new(50.0, CurrencyType::Quote));
+    }
+
+    #[test]
+    fn it_sizes_by_balance() {
+        let rule = SizingRule::PercentOfBalance { percent: 2.0, account_id: 1, currency: "USDT".into() };
+        let rule = sizing(rule);
+        let size = order_size(&order(None), &rule, None, Some(2500.0)).unwrap().unwrap();
+        assert_eq!(size, OrderSize::new(50.0, CurrencyType::Quote));
+        // Capped like any other size
+        assert_eq!(amount(order_size(&order(None), &rule, None, Some(10000.0))), Some("100".into()));
+        assert!(order_size(&order(None), &rule, None, None).is_err());
+        assert!(order_size(&order(None), &rule, None, Some(0.0)).is_err());
+    }
+
+    #[test]
+    fn it_refuses_absurd_sizes() {
+        let rule = sizing(SizingRule::Contracts);
+        assert!(order_size(&order(Some(5000.0)), &rule, None, None).is_err());
+        assert!(order_size(&order(Some(-1.0)), &rule, None, None).is_err());
+        assert!(order_size(&order(Some(f64::NAN)), &rule, None, None).is_err());
+    }
+
+    #[test]
+    fn it_fits_sizes_to_the_market() {
+        let market = Market {
+            symbol: "BTCUSDT".into(),
+            base: "BTC".into(),
+            quote: "USDT".into(),
+            min_notional: Some(0.05),
+            lot_step: Some(0.1),
+            price_tick: None,
+        };
+        let rule = SizingSettings { min: None, ..sizing(SizingRule::Contracts) };
+        assert_eq!(amount(order_size(&order(Some(2.37)), &rule, Some(&market), None)), Some("2.3".into()));
+        assert_eq!(amount(order_size(&order(Some(0.3)), &rule, Some(&market), None)), Some("0.3".into()));
+        assert!(order_size(&order(Some(0.05)), &rule, Some(&market), None).is_err());
+        // 0.1 at 0.3 is 0.03, under the minimum
+        assert!(order_size(&order(Some(0.1)), &rule, Some(&market), None).is_err());
+
+        let rule = sizing(SizingRule::FixedQuote { amount: 0.01 });
+        let rule = SizingSettings { min: None, ..rule };
+        assert!(order_size(&order(None), &rule, Some(&market), None).is_err());
+    }
+}
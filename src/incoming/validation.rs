@@ -0,0 +1,276 @@
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Client;
+use serde_json::Value;
+use std::time::Duration as StdDuration;
+use crate::{
+    markets::{MarketCache, MARKETS},
+    outgoing::webhook::percent_encode,
+    settings::{ReferencePriceSettings, SanitySettings},
+};
+use super::{IncomingSignal, SignalAction, SignalError};
+
+/// Runs every check `sanity` turns on, and reports all the ones that fail.
+pub async fn check(
+    signal: &IncomingSignal,
+    sanity: &SanitySettings,
+    now: DateTime<Utc>,
+) -> Result<(), SignalError> {
+    let mut failures = vec![];
+
+    if let Some(max_age_secs) = sanity.max_age_secs {
+        failures.extend(check_age(signal, max_age_secs, now));
+    }
+
+    if sanity.check_market_position {
+        failures.extend(check_market_position(signal));
+    }
+
+    if sanity.check_position_size {
+        failures.extend(check_position_size(signal));
+    }
+
+    if sanity.check_market {
+        failures.extend(check_market(signal, &MARKETS));
+    }
+
+    if let (Some(max_pct), Some(reference)) = (sanity.max_price_deviation_pct, &sanity.reference_price) {
+        failures.extend(check_price(signal, max_pct, reference).await);
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(SignalError::FailedChecks(failures))
+    }
+}
+
+fn check_age(signal: &IncomingSignal, max_age_secs: u64, now: DateTime<Utc>) -> Option<String> {
+    let (name, sent_at) = match (signal.timenow, signal.time) {
+        (Some(timenow), _) => ("timenow", timenow),
+        (None, Some(time)) => ("time", time),
+        (None, None) => return None,
+    };
+
+    let age = now - sent_at;
+    if age > Duration::seconds(max_age_secs as i64) {
+        Some(format!(
+            "{} is {}s old, the limit is {}s",
+            name,
+            age.num_seconds(),
+            max_age_secs
+        ))
+    } else {
+        None
+    }
+}
+
+fn check_market_position(signal: &IncomingSignal) -> Option<String> {
+    let position = signal.market_position.as_deref()?;
+    let contradiction = match signal.order.action {
+        SignalAction::Buy => "short",
+        SignalAction::Sell => "long",
+        _ => return None,
+    };
+
+    if position.eq_ignore_ascii_case(contradiction) {
+        Some(format!(
+            "market_position is {} after a {:?} order",
+            position, signal.order.action
+        ))
+    } else {
+        None
+    }
+}
+
+fn check_position_size(signal: &IncomingSignal) -> Vec<String> {
+    [
+        ("market_position_size", signal.market_position_size),
+        ("prev_market_position_size", signal.prev_market_position_size),
+    ]
+    .iter()
+    .filter(|(_, size)| size.is_some_and(|size| size < 0.0))
+    .map(|(name, size)| format!("{} is negative: {}", name, size.unwrap()))
+    .collect()
+}
+
+fn check_market(signal: &IncomingSignal, markets: &MarketCache) -> Option<String> {
+    let ticker = match &signal.ticker {
+        Some(ticker) => ticker,
+        None => return Some("No ticker to look the market up by".into()),
+    };
+    if markets.loaded_at().is_none() {
+        return Some("The markets haven't loaded yet".into());
+    }
+    let market = match markets.find(ticker) {
+        Some(market) => market,
+        None => return Some(format!("{} isn't a market we know", ticker)),
+    };
+
+    let (price, tick) = match (signal.order.price, market.price_tick) {
+        (Some(price), Some(tick)) if tick > 0.0 => (price, tick),
+        _ => return None,
+    };
+    let ticks = price / tick;
+    if (ticks - ticks.round()).abs() > 1e-6 {
+        Some(format!(
+            "order.price {} isn't a multiple of {}'s price tick {}",
+            price, market.symbol, tick
+        ))
+    } else {
+        None
+    }
+}
+
+async fn check_price(
+    signal: &IncomingSignal,
+    max_pct: f64,
+    reference: &ReferencePriceSettings,
+) -> Option<String> {
+    let price = signal.order.price?;
+    let ticker = match &signal.ticker {
+        Some(ticker) => ticker,
+        None => return Some("No ticker to check the price against".into()),
+    };
+
+    let reference_price = match fetch_reference_price(reference, ticker).await {
+        Ok(reference_price) => reference_price,
+        Err(why) => return Some(format!("Couldn't get a reference price for {}: {}", ticker, why)),
+    };
+
+    let deviation_pct = (price - reference_price).abs() / reference_price * 100.0;
+    if deviation_pct > max_pct {
+        Some(format!(
+            "order.price {} is {:.2}% from the reference price {}, the limit is {}%",
+            price, deviation_pct, reference_price, max_pct
+        ))
+    } else {
+        None
+    }
+}
+
+async fn fetch_reference_price(reference: &ReferencePriceSettings, ticker: &str) -> Result<f64, String> {
+    // Encoded, so a `/` or `&` in the ticker can't change the path or query
+    let url = reference.url.replace("{ticker}", &percent_encode(ticker));
+    let json: Value = Client::new()
+        .get(&url)
+        .timeout(StdDuration::from_secs(reference.timeout_secs))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Plenty of price APIs send numbers as strings
+    let price = match json.pointer(&reference.json_pointer) {
+        Some(Value::Number(n)) => n.as_f64(),
+        Some(Value::String(s)) => s.parse().ok(),
+        _ => None,
+    };
+
+    match price {
+        Some(price) if price > 0.0 => Ok(price),
+        _ => Err(format!("no price at {} in the response", reference.json_pointer)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+    use crate::{incoming::parse_signal, markets::Market};
+
+    const SIGNAL: &str = r#"{
+        "ticker": "BTCUSDT",
+        "timenow": "2021-06-02T12:00:00Z",
+        "order": {"action": "buy", "price": 110},
+        "market_position": "short",
+        "market_position_size": -1
+    }"#;
+
+    fn failures(result: Result<(), SignalError>) -> Vec<String> {
+        match result {
+            Err(SignalError::FailedChecks(failures)) => failures,
+            other => panic!("Expected failed checks, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_reports_every_failed_check() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method("GET").path("/price").query_param("symbol", "BTCUSDT");
+            then.status(200).body(r#"{"symbol": "BTCUSDT", "price": "100.0"}"#);
+        });
+
+        let sanity = SanitySettings {
+            max_age_secs: Some(60),
+            max_price_deviation_pct: Some(5.0),
+            reference_price: Some(ReferencePriceSettings {
+                url: format!("{}/price?symbol={{ticker}}", server.base_url()),
+                json_pointer: "/price".into(),
+                timeout_secs: 5,
+            }),
+            check_market_position: true,
+            check_position_size: true,
+            check_market: false,
+        };
+
+        let signal = parse_signal(None, SIGNAL.as_bytes()).unwrap();
+        let now = "2021-06-02T12:05:00Z".parse().unwrap();
+        let failures = failures(check(&signal, &sanity, now).await);
+
+        assert_eq!(failures.len(), 4, "{:?}", failures);
+        assert!(failures[0].contains("300s old"));
+        assert!(failures[3].contains("10.00%"));
+    }
+
+    #[tokio::test]
+    async fn it_encodes_the_ticker_in_the_reference_url() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method("GET").path("/price").query_param("symbol", "BTC/USDT&symbol=ETHUSDT");
+            then.status(200).body(r#"{"price": 100}"#);
+        });
+        let reference = ReferencePriceSettings {
+            url: format!("{}/price?symbol={{ticker}}", server.base_url()),
+            json_pointer: "/price".into(),
+            timeout_secs: 5,
+        };
+
+        assert_eq!(fetch_reference_price(&reference, "BTC/USDT&symbol=ETHUSDT").await, Ok(100.0));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn it_passes_when_checks_are_off() {
+        let signal = parse_signal(None, SIGNAL.as_bytes()).unwrap();
+        assert!(check(&signal, &SanitySettings::default(), Utc::now()).await.is_ok());
+    }
+
+    #[test]
+    fn it_checks_the_market() {
+        let markets = MarketCache::default();
+        let signal = parse_signal(None, SIGNAL.as_bytes()).unwrap();
+        assert!(check_market(&signal, &markets).unwrap().contains("haven't loaded"));
+
+        markets.replace(vec![Market {
+            symbol: "BTCUSDT".into(),
+            base: "BTC".into(),
+            quote: "USDT".into(),
+            min_notional: None,
+            lot_step: None,
+            price_tick: Some(0.1),
+        }], &Default::default(), Utc::now());
+        assert_eq!(check_market(&signal, &markets), None);
+
+        let off_tick = SIGNAL.replace("110", "110.05");
+        let signal = parse_signal(None, off_tick.as_bytes()).unwrap();
+        assert!(check_market(&signal, &markets).unwrap().contains("price tick"));
+
+        let elsewhere = SIGNAL.replace("BTCUSDT", "ETHUSDT");
+        let signal = parse_signal(None, elsewhere.as_bytes()).unwrap();
+        assert!(check_market(&signal, &markets).unwrap().contains("ETHUSDT isn't a market"));
+    }
+}
@@ -0,0 +1,335 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+use uuid::Uuid;
+use crate::{
+    incoming::SignalFormat,
+    outgoing::{OutgoingRequest, deal_and_bot_types::OrderSize, sequence::Sequence},
+    settings::{get_settings, TENANT_SEPARATOR},
+    storage,
+};
+
+/// Something that happened to a signal, for the record.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JournalEvent {
+    /// A signal came in for a strategy that takes it. What's next is one of
+    /// `signal_rejected`, `signal_deferred`, `signal_scheduled`, `signal_dry_run` or
+    /// `sequence_planned`.
+    SignalReceived {
+        strategy: String,
+        /// Which format the webhook body was in, for replaying it
+        #[serde(default)]
+        format: SignalFormat,
+        /// As it came in, before its `alert_message` overrides
+        signal: Value,
+        /// Its `alert_message` asked for a dry run
+        #[serde(default)]
+        dry_run: bool,
+    },
+    /// We turned the signal away
+    SignalRejected { reason: String },
+    /// A risk limit is holding the signal back, for up to this long
+    SignalDeferred { reason: String, wait_secs: i64 },
+    /// The signal asked for its requests to wait until then (see `schedule`)
+    SignalScheduled { run_at: DateTime<Utc> },
+    /// The signal's `alert_message` asked for its requests to be planned but not sent
+    SignalDryRun,
+    /// What we're going to send to one account, and which webhooks follow
+    SequencePlanned(SequencePlan),
+    /// The stop-loss and take-profit we asked for on a new deal
+    ExitsPlanned {
+        bot_id: u64,
+        entry_price: Option<f64>,
+        stop_loss_percentage: Option<f64>,
+        take_profit_percentage: Option<f64>,
+    },
+    /// A bot's deal wasn't where we thought it was (see `reconcile`)
+    PositionDiverged {
+        #[serde(default)]
+        account: String,
+        bot_id: u64,
+        expected_open: bool,
+        actually_open: bool,
+        auto_closed: bool,
+    },
+    /// We sent a request to 3commas, and this is how it went
+    RequestExecuted {
+        #[serde(default)]
+        account: String,
+        action: String,
+        bot_id: u64,
+        status: Option<u16>,
+        latency_ms: u64,
+        error: Option<String>,
+        #[serde(default)]
+        attempts: u32,
+    },
+    /// We sent a signal on to a webhook, and this is how it went
+    WebhookExecuted {
+        name: String,
+        method: String,
+        /// Only the scheme, host and port, since the rest may hold a token
+        url: String,
+        status: Option<u16>,
+        latency_ms: u64,
+        error: Option<String>,
+        attempts: u32,
+    },
+}
+
+/// An `OutgoingRequest`, minus the token.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlannedRequest {
+    pub action: String,
+    pub bot_id: u64,
+    pub order: Option<OrderSize>,
+    pub stop_loss_percentage: Option<f64>,
+    pub take_profit_percentage: Option<f64>,
+}
+
+impl From<&OutgoingRequest> for PlannedRequest {
+    fn from(request: &OutgoingRequest) -> Self {
+        PlannedRequest {
+            action: format!("{:?}", request.action),
+            bot_id: request.bot_id,
+            order: request.order.clone(),
+            stop_loss_percentage: request.stop_loss_percentage,
+            take_profit_percentage: request.take_profit_percentage,
+        }
+    }
+}
+
+/// What a sequence will send to its account, and which webhooks follow.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SequencePlan {
+    pub account: String,
+    pub requests: Vec<PlannedRequest>,
+    pub webhooks: Vec<String>,
+}
+
+impl From<&Sequence> for SequencePlan {
+    fn from(sequence: &Sequence) -> Self {
+        SequencePlan {
+            account: sequence.account.clone(),
+            requests: sequence.requests.iter().map(PlannedRequest::from).collect(),
+            webhooks: sequence.webhooks.iter().map(|webhook| webhook.name.clone()).collect(),
+        }
+    }
+}
+
+/// One line of the journal.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub at: DateTime<Utc>,
+    pub signal_id: String,
+    #[serde(flatten)]
+    pub event: JournalEvent,
+}
+
+/// An append-only JSON Lines file of everything we did with each signal.
+pub struct Journal {
+    path: Option<PathBuf>,
+    lock: Mutex<()>,
+}
+
+impl Journal {
+    /// A journal that writes to `path`, or nowhere when there isn't one.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Journal { path, lock: Mutex::new(()) }
+    }
+
+    pub fn record(&self, signal_id: &str, event: JournalEvent) {
+        let entry = JournalEntry {
+            at: Utc::now(),
+            signal_id: signal_id.into(),
+            event,
+        };
+        if let Err(e) = self.append(&entry) {
+            error!("Can't write to the journal at {:?}: {} ({:?})", self.path, e, entry);
+        }
+        storage::record(entry);
+    }
+
+    /// The entries in the last `max_bytes` of the journal, oldest first, and
+    /// whether that's all of it. Lines that don't make sense (say, from a crash
+    /// halfway through writing one, or the one `max_bytes` cuts in half) are skipped.
+    pub fn tail(&self, max_bytes: u64) -> io::Result<(Vec<JournalEntry>, bool)> {
+        let mut file = match &self.path {
+            Some(path) => match File::open(path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((vec![], true)),
+                Err(e) => return Err(e),
+            },
+            None => return Ok((vec![], true)),
+        };
+
+        let start = file.metadata()?.len().saturating_sub(max_bytes);
+        file.seek(SeekFrom::Start(start))?;
+        let mut reader = BufReader::new(file);
+        if start > 0 {
+            reader.read_until(b'\n', &mut vec![])?;
+        }
+
+        let mut entries = vec![];
+        for line in reader.lines() {
+            match serde_json::from_str(&line?) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => warn!("Skipping a journal line that doesn't parse: {}", e),
+            }
+        }
+        Ok((entries, start == 0))
+    }
+
+    fn append(&self, entry: &JournalEntry) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().unwrap();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(line.as_bytes())
+    }
+}
+
+lazy_static! {
+    pub static ref JOURNAL: Journal = Journal::new(get_settings().journal_path.as_ref().map(PathBuf::from));
+    /// Each tenant's journal, by name
+    static ref TENANT_JOURNALS: HashMap<String, Journal> = get_settings()
+        .tenants
+        .iter()
+        .map(|(name, tenant)| (name.clone(), Journal::new(tenant.journal_path.as_ref().map(PathBuf::from))))
+        .collect();
+}
+
+/// A fresh id to file a signal's journal entries under. A tenant's signal ids
+/// start with the tenant's name, `alice:<uuid>`, so everything that happens to
+/// the signal later, logs included, can tell whose it was.
+pub fn new_signal_id(tenant: Option<&str>) -> String {
+    match tenant {
+        Some(tenant) => format!("{}{}{}", tenant, TENANT_SEPARATOR, Uuid::new_v4()),
+        None => Uuid::new_v4().to_string(),
+    }
+}
+
+/// The tenant whose signal this is, if it's a tenant's.
+pub fn tenant_of(signal_id: &str) -> Option<&str> {
+    signal_id.split_once(TENANT_SEPARATOR).map(|(tenant, _)| tenant)
+}
+
+/// The journal `tenant`'s signals go in, or the top-level one.
+pub fn journal(tenant: Option<&str>) -> &'static Journal {
+    tenant.and_then(|tenant| TENANT_JOURNALS.get(tenant)).unwrap_or(&JOURNAL)
+}
+
+pub fn record(signal_id: &str, event: JournalEvent) {
+    journal(tenant_of(signal_id)).record(signal_id, event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn it_appends_one_line_per_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+        let journal = Journal::new(Some(path.clone()));
+
+        journal.record("abc", JournalEvent::ExitsPlanned {
+            bot_id: 1234567,
+            entry_price: Some(100.0),
+            stop_loss_percentage: Some(2.0),
+            take_profit_percentage: None,
+        });
+        journal.record("abc", JournalEvent::RequestExecuted {
+            account: "default".into(),
+            action: "StartDeal".into(),
+            bot_id: 1234567,
+            status: Some(200),
+            latency_ms: 12,
+            error: None,
+            attempts: 1,
+        });
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let (entries, _) = journal.tail(u64::MAX).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert!(contents.starts_with(r#"{"at":"#));
+        assert!(contents.contains(r#""signal_id":"abc","event":"exits_planned""#));
+        assert!(matches!(entries[1].event, JournalEvent::RequestExecuted { status: Some(200), .. }));
+    }
+
+    #[test]
+    fn it_reads_just_the_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(Some(dir.path().join("journal.jsonl")));
+        for signal_id in &["a", "b", "c"] {
+            journal.record(signal_id, JournalEvent::SignalDryRun);
+        }
+        let line_len = fs::read_to_string(journal.path.as_ref().unwrap()).unwrap().lines().next().unwrap().len() as u64;
+
+        let (entries, whole) = journal.tail(line_len + 5).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].signal_id, "c");
+        assert!(!whole);
+
+        let (entries, whole) = journal.tail(u64::MAX).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(whole);
+    }
+
+    #[test]
+    fn it_keeps_plans_flat() {
+        let planned = JournalEvent::SequencePlanned(SequencePlan {
+            account: "default".into(),
+            requests: vec![],
+            webhooks: vec!["discord".into()],
+        });
+        let json = serde_json::to_string(&planned).unwrap();
+
+        assert_eq!(json, r#"{"event":"sequence_planned","account":"default","requests":[],"webhooks":["discord"]}"#);
+        assert_eq!(serde_json::from_str::<JournalEvent>(&json).unwrap(), planned);
+    }
+
+    #[test]
+    fn it_files_tenants_signals_under_their_name() {
+        let signal_id = new_signal_id(Some("alice"));
+        assert!(signal_id.starts_with("alice:"));
+        assert_eq!(tenant_of(&signal_id), Some("alice"));
+        assert_eq!(tenant_of(&new_signal_id(None)), None);
+    }
+
+    #[test]
+    fn it_writes_nothing_without_a_path() {
+        let files_here = || fs::read_dir(".").unwrap().count();
+        let before = files_here();
+        let journal = Journal::new(None);
+        journal.record("abc", JournalEvent::SignalReceived {
+            strategy: "default".into(),
+            format: SignalFormat::Minimal,
+            signal: Value::Null,
+            dry_run: false,
+        });
+        assert!(journal.tail(u64::MAX).unwrap().0.is_empty());
+        assert_eq!(files_here(), before);
+    }
+}
@@ -0,0 +1,119 @@
+use flexi_logger::{DeferredNow, FormatFunction, Record};
+use serde_json::json;
+use std::{future::Future, io::Write};
+use crate::{journal, settings::LogFormat};
+
+tokio::task_local! {
+    /// The journal id of the signal the current task is working on.
+    static SIGNAL_ID: String;
+}
+
+/// Runs `f` with everything it logs tagged with `signal_id`, across awaits.
+pub async fn for_signal<F: Future>(signal_id: &str, f: F) -> F::Output {
+    SIGNAL_ID.scope(signal_id.into(), f).await
+}
+
+/// Like `for_signal`, for code that doesn't await.
+pub fn for_signal_sync<R>(signal_id: &str, f: impl FnOnce() -> R) -> R {
+    SIGNAL_ID.sync_scope(signal_id.into(), f)
+}
+
+/// The signal we're working on, if any.
+pub fn current_signal_id() -> Option<String> {
+    SIGNAL_ID.try_with(|signal_id| signal_id.clone()).ok()
+}
+
+pub fn format(log_format: LogFormat) -> FormatFunction {
+    match log_format {
+        LogFormat::Text => text_format,
+        LogFormat::Json => json_format,
+    }
+}
+
+/// `2021-06-02 12:00:00.000 +00:00 INFO  [tradeproxy::outgoing] [signal id] message`
+fn text_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+    write!(
+        w,
+        "{} {:<5} [{}] ",
+        now.now().format("%Y-%m-%d %H:%M:%S%.3f %:z"),
+        record.level(),
+        record.module_path().unwrap_or("<unnamed>"),
+    )?;
+    if let Some(signal_id) = current_signal_id() {
+        write!(w, "[{}] ", signal_id)?;
+    }
+    write!(w, "{}", record.args())
+}
+
+/// One JSON object per record, with `signal_id` when there is one, and `tenant`
+/// when it's a tenant's signal.
+fn json_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+    let signal_id = current_signal_id();
+    let line = json!({
+        "at": now.now().to_rfc3339(),
+        "level": record.level().as_str(),
+        "module": record.module_path(),
+        "tenant": signal_id.as_deref().and_then(journal::tenant_of),
+        "signal_id": signal_id,
+        "message": record.args().to_string(),
+    });
+    write!(w, "{}", line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flexi_logger::Level;
+    use serde_json::Value;
+
+    fn formatted(log_format: LogFormat) -> String {
+        let mut out = vec![];
+        format(log_format)(
+            &mut out,
+            &mut DeferredNow::new(),
+            &Record::builder()
+                .args(format_args!("Sending {} requests", 2))
+                .level(Level::Info)
+                .module_path(Some("tradeproxy::outgoing"))
+                .build(),
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn it_tags_records_with_the_signal_id() {
+        let line = for_signal_sync("abc", || formatted(LogFormat::Text));
+        assert!(line.ends_with("INFO  [tradeproxy::outgoing] [abc] Sending 2 requests"), "{}", line);
+
+        let line = formatted(LogFormat::Text);
+        assert!(line.ends_with("INFO  [tradeproxy::outgoing] Sending 2 requests"), "{}", line);
+    }
+
+    #[test]
+    fn it_writes_json_lines() {
+        let line: Value = serde_json::from_str(&for_signal_sync("abc", || formatted(LogFormat::Json))).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["module"], "tradeproxy::outgoing");
+        assert_eq!(line["signal_id"], "abc");
+        assert_eq!(line["message"], "Sending 2 requests");
+        assert!(line["tenant"].is_null());
+
+        let line: Value = serde_json::from_str(&for_signal_sync("alice:abc", || formatted(LogFormat::Json))).unwrap();
+        assert_eq!(line["tenant"], "alice");
+
+        let line: Value = serde_json::from_str(&formatted(LogFormat::Json)).unwrap();
+        assert!(line["signal_id"].is_null());
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_signal_id_across_awaits() {
+        let signal_id = for_signal("abc", async {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            current_signal_id()
+        })
+        .await;
+        assert_eq!(signal_id.as_deref(), Some("abc"));
+        assert_eq!(current_signal_id(), None);
+    }
+}
@@ -5,23 +5,57 @@ extern crate chrono;
 extern crate lazy_static;
 extern crate serde_derive;
 
+mod admin;
+mod alert;
+mod balances;
+mod config_check;
+mod dashboard;
+mod dedup;
+mod history;
 pub mod incoming;
+mod journal;
+mod logging;
+mod markets;
 mod outgoing;
+mod positions;
+mod rate_limit;
+mod reconcile;
+mod replay;
+mod response;
+mod risk;
+mod schedule;
+mod server;
 pub mod settings;
+mod shutdown;
+mod storage;
+mod telemetry;
 
-use chrono::prelude::Local;
+use chrono::{prelude::{DateTime, Utc}, Duration};
 use flexi_logger::{Age, Cleanup, Criterion, Duplicate, LogTarget, Logger, Naming};
-use incoming::IncomingSignal;
+use bytes::{Buf, BufMut};
+use dedup::DEDUP;
+use incoming::{IncomingSignal, SignalError, parse_signal, validation};
 use log::{error, info};
-use outgoing::{OutgoingRequest, deal_and_bot_types::BotType};
-pub use settings::{get_settings, Settings, SETTINGS};
-use tokio::time::{Duration, sleep};
-use std::{collections::HashSet, convert::Infallible, result::Result};
-use warp::{Filter, Rejection, Reply, filters::BoxedFilter, http::{HeaderMap, Method, StatusCode}, reply};
+use journal::{JournalEvent, SequencePlan};
+use outgoing::{OutgoingRequest, account::Account, deal_and_bot_types::BotType, sequence::{execute_sequence, Sequence}, webhook};
+pub use settings::{get_settings, Settings, StrategySettings, SETTINGS};
+use response::{Decision, ErrorCode, SignalResponse};
+use risk::{Admission, RiskCheck, RISK};
+use schedule::{Command, Job, SCHEDULER};
+use serde::Deserialize;
+use serde_json::Value;
+use shutdown::IN_FLIGHT;
+use tokio::{sync::watch, task::JoinHandle};
+use std::{collections::HashSet, convert::Infallible, io, net::{IpAddr, SocketAddr}, path::PathBuf, result::Result};
+use warp::{Filter, Rejection, Reply, filters::BoxedFilter, http::{HeaderMap, HeaderValue, Method, StatusCode}, reject::MethodNotAllowed, reply};
 use clap::{AppSettings, Clap};
+use futures::{future, Stream, StreamExt};
 
-const AUTHORS: &'static str = env!("CARGO_PKG_AUTHORS");
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Tells the caller which signal id its webhook got, to find it in the logs and the journal.
+const SIGNAL_ID_HEADER: &str = "x-signal-id";
 
 /// Tradeproxy listens for signals to trade and passes them on to 3commas bots.
 #[derive(Clap)]
@@ -35,20 +69,139 @@ struct Opts {
     /// Stops both bots and exits.
     #[clap(long)]
     stop_bots: bool,
+
+    /// Reads settings from this file instead of the config directory's config.yaml.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Option<SubCommand>,
+}
+
+#[derive(Clap)]
+enum SubCommand {
+    /// Runs recorded signals through the settings against a paper account, and
+    /// says what would have happened. See doc/replay.md.
+    Replay(ReplayOpts),
+    /// Looks after the settings.
+    Config(ConfigOpts),
+    /// Exports from or prunes the database. See doc/storage.md.
+    Db(DbOpts),
+}
+
+#[derive(Clap)]
+struct ConfigOpts {
+    #[clap(subcommand)]
+    command: ConfigCommand,
+}
+
+#[derive(Clap)]
+enum ConfigCommand {
+    /// Loads the settings and reports anything wrong with them. Given a file of
+    /// sample signals, one per line, says what each would turn into. See doc/rules.md.
+    Check(CheckOpts),
+}
+
+#[derive(Clap)]
+struct CheckOpts {
+    samples: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+struct DbOpts {
+    #[clap(subcommand)]
+    command: DbCommand,
 }
 
-fn get_real_remote_ip(headers: &'_ HeaderMap) -> &str {
+#[derive(Clap)]
+enum DbCommand {
+    /// Prints a table's rows as JSON lines, oldest first. The tables are signals,
+    /// requests, webhook_calls, positions and config_audit.
+    Export(ExportOpts),
+    /// Deletes old signals, with their requests and webhook calls.
+    Prune(PruneOpts),
+}
+
+#[derive(Clap)]
+struct ExportOpts {
+    table: String,
+
+    /// Only rows from this time on (RFC 3339)
+    #[clap(long)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only rows from before this time (RFC 3339)
+    #[clap(long)]
+    until: Option<DateTime<Utc>>,
+}
+
+#[derive(Clap)]
+struct PruneOpts {
+    /// Deletes what's older than this many days
+    #[clap(long)]
+    older_than_days: i64,
+}
+
+#[derive(Clap)]
+struct ReplayOpts {
+    /// A journal, or a TradingView alert log export ending in .csv
+    file: PathBuf,
+
+    /// Prints the timeline and summary as JSON
+    #[clap(long)]
+    json: bool,
+
+    /// Replays with this tenant's settings instead of the top-level ones
+    #[clap(long)]
+    tenant: Option<String>,
+}
+
+/// The client's address: nginx's `x-real-ip` when we're behind it, otherwise the
+/// address of the connection itself. The header only counts when the connection
+/// is from one of `trusted_proxies`, as anyone can send it.
+fn get_real_remote_ip(headers: &HeaderMap, remote: Option<SocketAddr>, trusted_proxies: &[IpAddr]) -> String {
     let error_message = "[Remote address unknown]";
 
-    let real_ip_header = headers.get("x-real-ip");
+    let real_ip_header = headers
+        .get("x-real-ip")
+        .filter(|_| remote.is_some_and(|addr| trusted_proxies.contains(&addr.ip())));
     if let Some(h) = real_ip_header {
         match h.to_str() {
-            Ok(s) => &s,
-            Err(_) => error_message,
+            Ok(s) => s.into(),
+            Err(_) => error_message.into(),
         }
+    } else if let Some(addr) = remote {
+        addr.ip().to_string()
     } else {
-        error_message
+        error_message.into()
+    }
+}
+
+/// Headers whose values we never want to see in a log file.
+const SENSITIVE_HEADERS: [&str; 6] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-auth-token",
+];
+
+fn is_sensitive_header(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SENSITIVE_HEADERS.contains(&name.as_str())
+        || ["token", "secret", "password"].iter().any(|word| name.contains(word))
+}
+
+/// A copy of `headers` that's safe to log: sensitive values are masked.
+fn redacted_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut redacted = headers.clone();
+    for (name, value) in redacted.iter_mut() {
+        if is_sensitive_header(name.as_str()) {
+            *value = HeaderValue::from_static("[REDACTED]");
+        }
     }
+    redacted
 }
 
 fn is_tradingview_ip(remote_ip: &str) -> bool {
@@ -63,58 +216,419 @@ fn log_remote_source(remote_ip: &str) {
     }
 }
 
-fn get_json() -> BoxedFilter<(IncomingSignal,)> {
-    warp::path!("trade")
-        .and(warp::path::full())
+/// A signal we turned down, with the id it was logged under.
+#[derive(Debug)]
+struct Rejected {
+    signal_id: String,
+    error: SignalError,
+}
+
+impl warp::reject::Reject for Rejected {}
+
+/// The settings a webhook's signal sees: the top-level ones on `/trade`, or a
+/// tenant's on `/trade/<token>`. A token that isn't any tenant's is just not found.
+fn settings_for_path() -> BoxedFilter<(Settings,)> {
+    let top_level = warp::path!("trade").map(|| get_settings().clone());
+    let tenant = warp::path!("trade" / String).and_then(|token: String| {
+        let settings = get_settings();
+        let tenant = settings.tenant_with_token(&token).and_then(|tenant| settings.for_tenant(tenant));
+        future::ready(tenant.ok_or_else(warp::reject::not_found))
+    });
+    top_level.or(tenant).unify().boxed()
+}
+
+/// Every webhook gets a signal id as soon as it arrives, so the logs for
+/// parsing it can be told apart from everything else's.
+fn get_json() -> BoxedFilter<(String, IncomingSignal, Value, StrategySettings, Settings)> {
+    settings_for_path()
+        .map(|settings: Settings| (journal::new_signal_id(settings.tenant.as_deref()), settings))
+        .untuple_one()
         .and(warp::method())
         .and(warp::header::headers_cloned())
+        .and(warp::addr::remote())
         .map(
-            |path: warp::path::FullPath, method: Method, headers: HeaderMap| {
-                let remote_ip = get_real_remote_ip(&headers);
-                info!(
-                    "[{:?}] Oho, a {:?} request from {} to {:?}: {:?}",
-                    Local::now(),
-                    method,
-                    get_real_remote_ip(&headers),
-                    path,
-                    headers
-                );
-                log_remote_source(remote_ip);
+            |signal_id: String, settings: Settings, method: Method, headers: HeaderMap, remote: Option<SocketAddr>| {
+                let remote_ip = get_real_remote_ip(&headers, remote, &settings.limits.trusted_proxies);
+                logging::for_signal_sync(&signal_id, || {
+                    // Not the path, which has the tenant's token in it
+                    let webhook = match &settings.tenant {
+                        Some(tenant) => format!("tenant {:?}'s webhook", tenant),
+                        None => "/trade".into(),
+                    };
+                    info!(
+                        "Oho, a {:?} request from {} to {}: {:?}",
+                        method,
+                        remote_ip,
+                        webhook,
+                        redacted_headers(&headers)
+                    );
+                    log_remote_source(&remote_ip);
+                });
+                (signal_id, settings, remote_ip)
             },
         )
         .untuple_one()
         .and(warp::post())
-        .and(warp::body::json())
-        .map(|signal: IncomingSignal| {
-            info!("Got signal {:?}...", signal);
-            signal
-        })
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::body::stream())
+        .and_then(receive_signal)
+        .untuple_one()
         .boxed()
 }
 
-async fn handle_signal(signal: IncomingSignal, server: String) -> Result<impl Reply, Infallible> {
-    tokio::spawn(async move {
-        info!("[{:?}] Handling signal: {:?}", Local::now(), signal);
-        let requests = signal.to_requests();
-        info!("[{:?}] Signal results in requests: {:?}", Local::now(), requests);
-        for request in requests {
-            info!("[{:?}] Executing request {:?}...", Local::now(), &request);
-            let er = request.execute_with_server(server.clone()).await;
-            er.log();
-            info!("[{:?}] Sleeping for 5s...", Local::now());
-            sleep(Duration::from_secs(5)).await;
-            info!("Done sleeping!");
+/// Checks the rate limits, reads the body and finds a strategy for the signal in it.
+async fn receive_signal<B: Buf>(
+    signal_id: String,
+    settings: Settings,
+    remote_ip: String,
+    content_type: Option<String>,
+    content_length: Option<u64>,
+    body: impl Stream<Item = Result<B, warp::Error>>,
+) -> Result<(String, IncomingSignal, Value, StrategySettings, Settings), Rejection> {
+    let accepted = async {
+        // Before reading the body, so a flood costs us as little as possible
+        rate_limit::admit(&remote_ip, settings.tenant.as_deref(), Utc::now())?;
+        let body = read_body(body, content_length, settings.limits.max_body_bytes).await?;
+        logging::for_signal_sync(&signal_id, || {
+            telemetry::in_root_span(&signal_id, "receive_signal", || {
+                accept_signal(&settings, content_type.as_deref(), &body)
+            })
+        })
+    };
+    match accepted.await {
+        Ok((signal, received, strategy)) => Ok((signal_id, signal, received, strategy, settings)),
+        Err(error) => Err(warp::reject::custom(Rejected { signal_id, error })),
+    }
+}
+
+/// Reads a webhook body, as long as it's no more than `max_bytes`. One that says
+/// up front that it's bigger isn't read at all.
+async fn read_body<B: Buf>(
+    body: impl Stream<Item = Result<B, warp::Error>>,
+    content_length: Option<u64>,
+    max_bytes: usize,
+) -> Result<Vec<u8>, SignalError> {
+    if content_length.is_some_and(|length| length > max_bytes as u64) {
+        return Err(SignalError::TooLarge { max_bytes });
+    }
+
+    tokio::pin!(body);
+    let mut read = vec![];
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| SignalError::Unparseable(format!("Can't read the body: {}", e)))?;
+        if read.len() + chunk.remaining() > max_bytes {
+            return Err(SignalError::TooLarge { max_bytes });
         }
+        read.put(chunk);
+    }
+    Ok(read)
+}
+
+/// Parses a webhook body, and finds the strategy in `settings` that will take it.
+/// Also gives the signal as it came, before its `alert_message` overrides, for
+/// the journal: replaying it applies them again.
+fn accept_signal(
+    settings: &Settings,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<(IncomingSignal, Value, StrategySettings), SignalError> {
+    let mut signal = parse_signal(content_type, body)?;
+    let received = serde_json::to_value(&signal).unwrap_or_default();
+    let strategy = signal.route(settings)?;
+    info!("Got {} signal {:?}...", signal.format, signal);
+    Ok((signal, received, strategy))
+}
+
+/// How the sender wants the signal handled, from the query string.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct TradeOptions {
+    /// Wait for the requests to go through, and say how they went
+    sync: bool,
+}
+
+async fn handle_signal(
+    signal_id: String,
+    signal: IncomingSignal,
+    received: Value,
+    strategy: StrategySettings,
+    settings: Settings,
+    options: TradeOptions,
+    server: String,
+) -> Result<impl Reply, Rejection> {
+    let handling = telemetry::in_signal_span(&signal_id, "handle_signal", vec![], async {
+        if !IN_FLIGHT.is_accepting() {
+            error!("Shutting down, ignoring signal {:?}", signal);
+            return Err(SignalError::ShuttingDown);
+        }
+        let _handling = rate_limit::start_handling(settings.limits.max_concurrent_signals)?;
+
+        journal::record(&signal_id, JournalEvent::SignalReceived {
+            strategy: signal.strategy_name().into(),
+            format: signal.format,
+            signal: received,
+            dry_run: signal.dry_run,
+        });
+
+        act_on_signal(&signal_id, signal, strategy, &settings, options.sync, server).await.inspect_err(|e| {
+            journal::record(&signal_id, JournalEvent::SignalRejected { reason: e.to_string() });
+            telemetry::record_error(e.to_string());
+        })
     });
-    info!("Generating OK result...");
-    Ok(StatusCode::OK)
+    match logging::for_signal(&signal_id, handling).await {
+        Ok(response) => Ok(reply::with_header(response, SIGNAL_ID_HEADER, signal_id)),
+        Err(error) => Err(warp::reject::custom(Rejected { signal_id, error })),
+    }
+}
+
+/// Checks the signal, works out the requests and sends them off, unless a risk
+/// limit or a delay says to wait. In `sync` mode, waits for them to go through.
+/// `settings` are the tenant's, for a tenant's signal.
+async fn act_on_signal(
+    signal_id: &str,
+    signal: IncomingSignal,
+    strategy: StrategySettings,
+    settings: &Settings,
+    sync: bool,
+    server: String,
+) -> Result<SignalResponse, SignalError> {
+    info!("Handling signal: {:?}", signal);
+    let strategy_name = signal.strategy_name().to_string();
+    if strategy.paused {
+        return Err(SignalError::Paused(strategy_name));
+    }
+    let reservation = match strategy.dedup_window_secs {
+        Some(window_secs) => Some(DEDUP.reserve(signal_id, &signal, Duration::seconds(window_secs as i64), Utc::now())?),
+        None => None,
+    };
+
+    validation::check(&signal, &strategy.sanity, Utc::now()).await?;
+    let requests = signal.to_requests(&strategy)?;
+    info!("Signal results in requests: {:?}", requests);
+
+    let context = webhook::template_context(&signal, signal_id, &strategy_name, &requests);
+    let mut webhooks = Some(webhook::webhooks_for(settings, &strategy, &context));
+    let stages: Vec<(u64, Vec<Sequence>)> = schedule::stages(&requests)?
+        .into_iter()
+        .map(|(hold_secs, requests)| {
+            let webhooks = webhooks.take().unwrap_or_default();
+            (hold_secs, fan_out(signal_id, &strategy_name, &strategy, settings, &requests, webhooks))
+        })
+        .collect();
+    let run_at = schedule::run_at(&signal, &strategy, Utc::now())?;
+    let mut response = SignalResponse {
+        signal_id: signal_id.into(),
+        strategy: strategy_name.clone(),
+        decision: Decision::Accepted,
+        plan: stages.iter().flat_map(|(_, sequences)| sequences).map(SequencePlan::from).collect(),
+        run_at,
+        results: None,
+    };
+    if signal.dry_run {
+        info!("Dry run, not sending anything");
+        journal::record(signal_id, JournalEvent::SignalDryRun);
+        response.decision = Decision::DryRun;
+        return Ok(response);
+    }
+
+    // Tenants' strategies are kept apart from everyone else's with the same name
+    let mut risk = RiskCheck {
+        strategy: settings.qualified(&strategy_name),
+        limits: strategy.risk,
+        requests,
+        entry_price: signal.order.price,
+        give_up_at: None,
+    };
+
+    // The risk limits are for when the requests go out, so they're checked when it comes due
+    if let Some(run_at) = run_at {
+        if let Some(reservation) = reservation {
+            reservation.commit();
+        }
+        journal::record(signal_id, JournalEvent::SignalScheduled { run_at });
+        hold_signal(signal_id, stages, run_at, Some(Box::new(risk)));
+        response.decision = Decision::Scheduled;
+        return Ok(response);
+    }
+
+    let now = Utc::now();
+    match risk.admit(&RISK, now) {
+        Admission::Admitted => (),
+        Admission::Deferred(retry_at, violation) => {
+            risk::alert_violation(&risk.strategy, &violation, true);
+            journal::record(signal_id, JournalEvent::SignalDeferred {
+                reason: violation.to_string(),
+                wait_secs: (retry_at - now).num_seconds(),
+            });
+            if let Some(reservation) = reservation {
+                reservation.commit();
+            }
+            // On the schedule, so it's still there after a restart
+            hold_signal(signal_id, stages, retry_at, Some(Box::new(risk)));
+            response.decision = Decision::Deferred;
+            return Ok(response);
+        }
+        Admission::Rejected(violation) => {
+            risk::alert_violation(&risk.strategy, &violation, false);
+            return Err(SignalError::RiskLimit(violation));
+        }
+    }
+
+    if let Some(reservation) = reservation {
+        reservation.commit();
+    }
+    record_exits(signal_id, risk.entry_price, &risk.requests);
+
+    let running = dispatch_stages(signal_id, stages, server);
+    if sync {
+        let mut results = vec![];
+        for sequence in running {
+            results.extend(sequence.await.unwrap_or_default());
+        }
+        response.decision = Decision::Executed;
+        response.results = Some(results);
+    }
+    Ok(response)
+}
+
+/// One copy of `requests` for each account the strategy trades on. The webhooks
+/// go out once, after the first account's requests.
+fn fan_out(
+    signal_id: &str,
+    strategy_name: &str,
+    strategy: &StrategySettings,
+    settings: &Settings,
+    requests: &[OutgoingRequest],
+    webhooks: Vec<webhook::WebhookRequest>,
+) -> Vec<Sequence> {
+    let accounts = Account::for_strategy(settings, strategy);
+    if accounts.is_empty() {
+        error!("Strategy {:?} has no accounts to trade on", strategy_name);
+    }
+
+    let mut webhooks = Some(webhooks);
+    accounts
+        .iter()
+        .map(|account| {
+            let requests = requests.iter().map(|request| account.adapt(request)).collect();
+            Sequence::new(signal_id, strategy_name, requests)
+                .on_account(&account.name)
+                .with_webhooks(webhooks.take().unwrap_or_default())
+        })
+        .collect()
+}
+
+/// Notes the stop loss and take profit of each request that has them, once
+/// the requests are on their way.
+fn record_exits(signal_id: &str, entry_price: Option<f64>, requests: &[OutgoingRequest]) {
+    for request in requests.iter().filter(|request| request.has_exits()) {
+        journal::record(signal_id, JournalEvent::ExitsPlanned {
+            bot_id: request.bot_id,
+            entry_price,
+            stop_loss_percentage: request.stop_loss_percentage,
+            take_profit_percentage: request.take_profit_percentage,
+        });
+    }
+}
+
+/// Sends the first stage of a signal's sequences off now, and puts each stage
+/// after a rule's `wait` on the schedule for that much later. Returns the
+/// sequences that went straight off.
+fn dispatch_stages(
+    signal_id: &str,
+    stages: Vec<(u64, Vec<Sequence>)>,
+    server: String,
+) -> Vec<JoinHandle<Vec<JournalEvent>>> {
+    let now = Utc::now();
+    let mut running = vec![];
+    for (hold_secs, sequences) in stages {
+        match hold_secs {
+            0 => running.extend(spawn_sequences(sequences, server.clone())),
+            _ => {
+                let run_at = now + Duration::seconds(hold_secs as i64);
+                info!("Holding the requests after a {}s wait until {}", hold_secs, run_at);
+                journal::record(signal_id, JournalEvent::SignalScheduled { run_at });
+                let job_id = format!("{}+{}s", signal_id, hold_secs);
+                SCHEDULER.add(Job::once(&job_id, Command::signal(sequences), run_at));
+            }
+        }
+    }
+    running
+}
+
+/// Puts a whole signal on the schedule for `run_at`, under its own id. The
+/// stages after a rule's `wait` are only dispatched once the first goes out,
+/// and that only happens if it passes `risk` then.
+fn hold_signal(signal_id: &str, stages: Vec<(u64, Vec<Sequence>)>, run_at: DateTime<Utc>, risk: Option<Box<RiskCheck>>) {
+    info!("Holding the requests until {}", run_at);
+    let (first, then): (Vec<_>, Vec<_>) = stages.into_iter().partition(|(hold_secs, _)| *hold_secs == 0);
+    let sequences = first.into_iter().flat_map(|(_, sequences)| sequences).collect();
+    SCHEDULER.add(Job::once(signal_id, Command::Signal { sequences, risk, then }, run_at));
+}
+
+/// Sends off a held signal that's come due, if it passes its risk limits.
+/// One that doesn't goes back on the schedule, or is rejected, the same way
+/// as when it first came in.
+pub fn release(
+    signal_id: &str,
+    job_id: &str,
+    sequences: Vec<Sequence>,
+    risk: Option<Box<RiskCheck>>,
+    then: Vec<(u64, Vec<Sequence>)>,
+    server: String,
+) {
+    let now = Utc::now();
+    if let Some(mut risk) = risk {
+        match risk.admit(&RISK, now) {
+            Admission::Admitted => record_exits(signal_id, risk.entry_price, &risk.requests),
+            Admission::Deferred(retry_at, violation) => {
+                risk::alert_violation(&risk.strategy, &violation, true);
+                journal::record(signal_id, JournalEvent::SignalDeferred {
+                    reason: violation.to_string(),
+                    wait_secs: (retry_at - now).num_seconds(),
+                });
+                let command = Command::Signal { sequences, risk: Some(risk), then };
+                SCHEDULER.add(Job::once(job_id, command, retry_at));
+                return;
+            }
+            Admission::Rejected(violation) => {
+                risk::alert_violation(&risk.strategy, &violation, false);
+                journal::record(signal_id, JournalEvent::SignalRejected { reason: violation.to_string() });
+                return;
+            }
+        }
+    }
+
+    info!("Sending held requests");
+    let mut stages = vec![(0, sequences)];
+    stages.extend(then);
+    dispatch_stages(signal_id, stages, server);
+}
+
+/// Runs each account's sequence on its own, so one account's trouble doesn't hold up the rest.
+fn spawn_sequences(sequences: Vec<Sequence>, server: String) -> Vec<JoinHandle<Vec<JournalEvent>>> {
+    sequences
+        .into_iter()
+        .map(|sequence| {
+            journal::record(&sequence.signal_id, JournalEvent::SequencePlanned(SequencePlan::from(&sequence)));
+            tokio::spawn(execute_sequence(sequence, server.clone()))
+        })
+        .collect()
 }
 
 fn entire_api(server: String) -> BoxedFilter<(impl Reply,)> {
+    let (admin_token, tenant_admin_tokens) = {
+        let settings = get_settings();
+        let tenants = settings.tenants.iter().map(|(name, tenant)| (name.clone(), tenant.admin_token.clone()));
+        (settings.admin_token.clone(), tenants.collect())
+    };
     get_json()
-        .and_then(move |signal| {
-            handle_signal(signal, server.clone())
+        .and(warp::query::<TradeOptions>())
+        .and_then(move |signal_id, signal, received, strategy, settings, options| {
+            handle_signal(signal_id, signal, received, strategy, settings, options, server.clone())
         })
+        .or(admin::admin_api(admin_token, tenant_admin_tokens))
         .recover(handle_error)
         .boxed()
 }
@@ -122,18 +636,32 @@ fn entire_api(server: String) -> BoxedFilter<(impl Reply,)> {
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opts: Opts = Opts::parse();
-    let log_path = &get_settings().log_path;
+    if let Some(config) = opts.config {
+        settings::use_config_file(config);
+    }
+    match opts.command {
+        Some(SubCommand::Replay(replay_opts)) => return run_replay(replay_opts).await,
+        Some(SubCommand::Config(ConfigOpts { command: ConfigCommand::Check(check_opts) })) => {
+            return check_config(check_opts);
+        }
+        Some(SubCommand::Db(db_opts)) => return run_db(db_opts),
+        None => (),
+    }
+
+    let log_path = get_settings().log_path.clone();
+    let log_format = get_settings().log_format;
 
     // Start the logger!
     let logger = Logger::with_str("info")
         .log_target(LogTarget::File)
-        .directory(log_path)
+        .directory(&log_path)
         .rotate(
             Criterion::AgeOrSize(Age::Day, 1000000),
             Naming::Timestamps,
             Cleanup::KeepLogFiles(8),
         )
         .duplicate_to_stderr(Duplicate::Info)
+        .format(logging::format(log_format))
         .start()?;
 
     info!("Tradeproxy {} starting up! Logging to {}", VERSION, log_path);
@@ -147,46 +675,249 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         start_bots().await
     }
 
-    // Start the server!
+    // Trace signals, if there's somewhere to send the traces
+    if let Some(tracing) = &get_settings().tracing {
+        if let Err(e) = telemetry::start(tracing) {
+            error!("Couldn't start tracing: {}", e);
+        }
+    }
+
+    // Note which config we're running with, if it's changed
+    let config_path = get_settings().config_path.clone();
+    if let (Some(storage), Some(config_path)) = (storage::STORAGE.as_ref(), config_path) {
+        match storage.audit_config(&config_path, VERSION, Utc::now()) {
+            Ok(true) => info!("Noted the new settings from {:?} in the database", config_path),
+            Ok(false) => (),
+            Err(e) => error!("Can't note the settings in the database: {}", e),
+        }
+    }
+
+    // Pick up where the last shutdown left off
     let server = get_settings().request_server.clone();
-    warp::serve(entire_api(server))
-        .run(([0, 0, 0, 0], get_settings().listen_port))
-        .await;
+    for sequence in shutdown::take_pending() {
+        info!("Resuming unfinished requests: {:?}", sequence);
+        tokio::spawn(execute_sequence(sequence, server.clone()));
+    }
+
+    // Know each market's steps before the first signal, and keep them fresh
+    let market_settings = get_settings().markets.clone();
+    if let Some(market_settings) = market_settings {
+        markets::MARKETS.refresh(&market_settings).await;
+        if market_settings.refresh_secs > 0 {
+            tokio::spawn(markets::refresh_periodically(market_settings));
+        }
+    }
+
+    // Know the balances strategies size deals by, and keep them fresh
+    let (reconcile_settings, wanted) = {
+        let settings = get_settings();
+        (settings.reconcile.clone(), balances::wanted(&settings))
+    };
+    if let (Some(reconcile_settings), false) = (reconcile_settings, wanted.is_empty()) {
+        let api = reconcile::ThreeCommasApi::new(&reconcile_settings);
+        balances::BALANCES.refresh(&api, &wanted).await;
+        if reconcile_settings.interval_secs > 0 {
+            tokio::spawn(balances::refresh_periodically(api, wanted, reconcile_settings.interval_secs));
+        }
+    }
+
+    // Send delayed signals and run scheduled commands when they're due
+    tokio::spawn(schedule::run_scheduled(server.clone()));
+
+    // Keep an eye on what 3commas thinks is open
+    let reconcile_settings = get_settings().reconcile.clone();
+    if let Some(reconcile_settings) = reconcile_settings {
+        tokio::spawn(reconcile::reconcile_periodically(reconcile_settings, both_bots(), server.clone()));
+    }
+
+    // Stop taking signals on SIGINT/SIGTERM
+    let (stop_tx, stop_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown::signal_received().await;
+        info!("Shutting down, no more signals!");
+        IN_FLIGHT.stop_accepting();
+        let _ = stop_tx.send(true);
+    });
+
+    // Start the server!
+    let served = server::serve(entire_api(server), stop_rx).await;
+    if let Err(e) = &served {
+        error!("{}", e);
+    }
 
+    // Let whatever's running finish before we go
+    shutdown::drain_in_flight().await;
+    if get_settings().shutdown.stop_bots_on_exit {
+        stop_bots().await;
+    }
+    let _ = tokio::task::spawn_blocking(storage::flush).await;
+
+    telemetry::shutdown();
+    info!("Bye!");
     logger.shutdown();
+    served.map_err(Into::into)
+}
+
+/// Replays a file of signals, without logging or sending anything.
+async fn run_replay(opts: ReplayOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let recorded = replay::read(&opts.file)?;
+    let settings = match &opts.tenant {
+        Some(tenant) => get_settings().for_tenant(tenant).ok_or_else(|| format!("No tenant called {:?}", tenant))?,
+        None => get_settings().clone(),
+    };
+    if let Some(market_settings) = &settings.markets {
+        markets::MARKETS.refresh(market_settings).await;
+    }
+    let report = replay::replay(&settings, recorded).await;
+
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        replay::write_text(&report, &mut io::stdout())?;
+    }
     Ok(())
 }
 
+/// Loads the settings without panicking if they're broken, and tries them out.
+fn check_config(opts: CheckOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let settings = settings::Settings::new().map_err(|e| format!("Can't load the settings: {}", e))?;
+    let problems = config_check::problems(&settings);
+    for problem in &problems {
+        println!("Problem: {}", problem);
+    }
+
+    if let Some(samples) = opts.samples {
+        let samples = std::fs::read_to_string(&samples).map_err(|e| format!("Can't read {:?}: {}", samples, e))?;
+        for sample in samples.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            println!("{}\n    {}", sample, config_check::evaluate(&settings, sample));
+        }
+    }
+
+    match problems.len() {
+        0 => Ok(()),
+        n => Err(format!("{} problem(s) with the settings", n).into()),
+    }
+}
+
+/// Opens the database without starting up, and exports from or prunes it.
+fn run_db(opts: DbOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let path = get_settings().database_path.clone().ok_or("There's no database_path")?;
+    let storage = storage::Storage::open(&path).map_err(|e| format!("Can't open the database at {}: {}", path, e))?;
+    match opts.command {
+        DbCommand::Export(export) => {
+            let count = storage.export(&export.table, export.since, export.until, &mut io::stdout().lock())?;
+            eprintln!("Exported {} rows", count);
+        }
+        DbCommand::Prune(prune) => {
+            let before = Utc::now() - Duration::days(prune.older_than_days);
+            let deleted = storage.prune(before)?;
+            println!("Deleted {} rows from before {}", deleted, before.to_rfc3339());
+        }
+    }
+    Ok(())
+}
+
+/// Both bots, unless the default strategy only trades one way.
 fn both_bots() -> Vec<BotType> {
+    let direction = get_settings()
+        .strategy(None)
+        .map(|strategy| strategy.direction)
+        .unwrap_or_default();
+
     vec![BotType::Long, BotType::Short]
+        .into_iter()
+        .filter(|bot_type| direction.allows(bot_type))
+        .collect()
+}
+
+/// `request` for both bots on every account, tenants' included. Bots that
+/// aren't set up are left out.
+fn on_every_bot(request: fn(BotType) -> OutgoingRequest) -> Vec<OutgoingRequest> {
+    let accounts = Account::all(&get_settings());
+    accounts
+        .iter()
+        .flat_map(|account| {
+            vec![BotType::Long, BotType::Short]
+                .into_iter()
+                .filter(move |bot_type| account.bot_id(*bot_type) != 0)
+                .map(move |bot_type| OutgoingRequest {
+                    bot_id: account.bot_id(bot_type),
+                    ..account.adapt(&request(bot_type))
+                })
+        })
+        .collect()
 }
 
 async fn start_bots() {
-    for bot_type in both_bots() {
-        let er = OutgoingRequest::start(bot_type).execute().await;
+    for request in on_every_bot(OutgoingRequest::start) {
+        let er = request.execute().await;
         er.log();
     };
 }
 
 async fn stop_bots() {
-    for bot_type in both_bots() {
-        let er = OutgoingRequest::stop(bot_type).execute().await;
+    for request in on_every_bot(OutgoingRequest::stop) {
+        let er = request.execute().await;
         er.log();
     };
 }
 
 async fn handle_error(err: Rejection) -> Result<impl Reply, Infallible> {
-    let err_text = format!("Rejected: {:?}", err);
+    let rejected = err.find::<Rejected>();
+    let (code, message, status) = match rejected.map(|rejected| &rejected.error) {
+        Some(signal_error) => {
+            let status = match signal_error {
+                SignalError::RiskLimit(_) | SignalError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+                SignalError::Duplicate { .. } => StatusCode::CONFLICT,
+                SignalError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                SignalError::Paused(_) | SignalError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            (signal_error.code(), signal_error.to_string(), status)
+        }
+        None if admin::is_unauthorized(&err) => {
+            (ErrorCode::Auth, "Unauthorized".into(), StatusCode::UNAUTHORIZED)
+        }
+        None if admin::is_journal_unreadable(&err) => {
+            (ErrorCode::Internal, "Can't read the journal".into(), StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        None if err.is_not_found() => (ErrorCode::BadRequest, "Not found".into(), StatusCode::BAD_REQUEST),
+        None if err.find::<MethodNotAllowed>().is_some() => {
+            (ErrorCode::BadRequest, "Method not allowed".into(), StatusCode::BAD_REQUEST)
+        }
+        None => (ErrorCode::BadRequest, "Bad request".into(), StatusCode::BAD_REQUEST),
+    };
 
-    error!("{}", err_text);
+    let signal_id = rejected.map(|rejected| rejected.signal_id.as_str());
+    match signal_id {
+        Some(signal_id) => logging::for_signal_sync(signal_id, || error!("Rejected: {}", message)),
+        None => error!("Rejected: {}", message),
+    }
 
-    Ok(reply::with_status(err_text, StatusCode::BAD_REQUEST))
+    let mut response = response::error(signal_id, code, message, status);
+    if let Some(signal_id) = signal_id.and_then(|signal_id| HeaderValue::from_str(signal_id).ok()) {
+        response.headers_mut().insert(SIGNAL_ID_HEADER, signal_id);
+    }
+    if let Some(SignalError::RateLimited { retry_after_secs, .. }) = rejected.map(|rejected| &rejected.error) {
+        response.headers_mut().insert("retry-after", HeaderValue::from(*retry_after_secs));
+    }
+    if status == StatusCode::UNAUTHORIZED {
+        // Lets a browser ask for the admin token, for the dashboard
+        response.headers_mut().insert(
+            "www-authenticate",
+            HeaderValue::from_static(r#"Basic realm="tradeproxy""#),
+        );
+    }
+    Ok(response)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bytes::Bytes;
     use httpmock::MockServer;
+    use settings::LimitSettings;
+    use tokio::time::{Duration, sleep};
     use warp::test::{request, RequestBuilder};
     const GOOD_SIGNAL_JSON: &str = r#"{
     "strategy": "fancy v1",
@@ -235,6 +966,49 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn it_accepts_minimal_and_text_signals() {
+        assert!(mock_request()
+                .header("content-type", "application/json")
+                .body(r#"{"action": "buy", "contracts": 1}"#)
+                .matches(&get_json())
+                .await
+        );
+
+        assert!(mock_request()
+                .header("content-type", "text/plain")
+                .body("sell")
+                .matches(&get_json())
+                .await
+        );
+    }
+
+    #[test]
+    fn it_checks_the_strategy_takes_the_format() {
+        use incoming::SignalFormat;
+
+        let mut settings = Settings::default();
+        settings.strategies.insert("fancy v1".into(), StrategySettings {
+            formats: vec![SignalFormat::Strategy],
+            ..Default::default()
+        });
+
+        let full = parse_signal(None, GOOD_SIGNAL_JSON.as_bytes()).unwrap();
+        assert!(full.strategy_settings(&settings).is_ok());
+
+        let form = parse_signal(None, b"action=buy&strategy=fancy+v1").unwrap();
+        assert!(matches!(
+            form.strategy_settings(&settings),
+            Err(SignalError::FormatNotAccepted { .. })
+        ));
+
+        let unknown = parse_signal(None, b"buy strategy=plain").unwrap();
+        assert!(matches!(
+            unknown.strategy_settings(&settings),
+            Err(SignalError::UnknownStrategy(_))
+        ));
+    }
+
     #[tokio::test]
     async fn it_accepts_unnecesary_fields_in_json() {
         const GOOD_BUT_WITH_EXTRA_FIELD: &str = r#"{
@@ -284,7 +1058,7 @@ mod tests {
         let _mock = mock_remote_server(&server);
         assert_eq!(
             mock_request()
-                .body(&GOOD_SIGNAL_JSON)
+                .body(GOOD_SIGNAL_JSON)
                 .filter(&entire_api(server.base_url()))
                 .await
                 .unwrap()
@@ -294,6 +1068,223 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn it_returns_the_signal_id() {
+        let server = MockServer::start();
+        let _mock = mock_remote_server(&server);
+
+        for body in [GOOD_SIGNAL_JSON, "blah blah blah"] {
+            let response = mock_request()
+                .body(body)
+                .filter(&entire_api(server.base_url()))
+                .await
+                .unwrap()
+                .into_response();
+            let signal_id = response.headers().get(SIGNAL_ID_HEADER).unwrap();
+            assert_eq!(signal_id.len(), 36);
+        }
+    }
+
+    async fn reply_json(response: warp::reply::Response) -> serde_json::Value {
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_says_what_it_decided() {
+        let server = MockServer::start();
+        let _mock = mock_remote_server(&server);
+        let response = mock_request()
+            .body(GOOD_SIGNAL_JSON)
+            .filter(&entire_api(server.base_url()))
+            .await
+            .unwrap()
+            .into_response();
+        let signal_id = response.headers()[SIGNAL_ID_HEADER].to_str().unwrap().to_string();
+        let body = reply_json(response).await;
+
+        assert_eq!(body["signal_id"], signal_id.as_str());
+        assert_eq!(body["strategy"], "fancy v1");
+        assert_eq!(body["decision"], "accepted");
+        assert_eq!(body["plan"][0]["account"], "default");
+        assert_eq!(body["plan"][0]["requests"][0]["action"], "CloseDeal");
+        assert_eq!(body["plan"][0]["requests"][1]["action"], "StartDeal");
+        assert!(body.get("results").is_none());
+    }
+
+    #[tokio::test]
+    async fn it_says_why_it_rejected() {
+        let server = MockServer::start();
+        let response = mock_request()
+            .body("blah blah blah")
+            .filter(&entire_api(server.base_url()))
+            .await
+            .unwrap()
+            .into_response();
+        let body = reply_json(response).await;
+
+        assert_eq!(body["signal_id"].as_str().unwrap().len(), 36);
+        assert_eq!(body["error"]["code"], "schema");
+        assert!(body["error"]["message"].as_str().unwrap().starts_with("Can't parse signal"));
+    }
+
+    #[tokio::test]
+    async fn it_waits_for_the_results_in_sync_mode() {
+        let server = MockServer::start();
+        let mock = mock_remote_server(&server);
+        let response = request()
+            .path("/trade?sync=true")
+            .method("POST")
+            .body(r#"{"action": "close_long"}"#)
+            .filter(&entire_api(server.base_url()))
+            .await
+            .unwrap()
+            .into_response();
+        let body = reply_json(response).await;
+
+        mock.assert_hits(1);
+        assert_eq!(body["decision"], "executed");
+        assert_eq!(body["results"][0]["event"], "request_executed");
+        assert_eq!(body["results"][0]["status"], 200);
+    }
+
+    #[tokio::test]
+    async fn it_turns_signals_away_while_paused() {
+        let signal = parse_signal(None, GOOD_SIGNAL_JSON.as_bytes()).unwrap();
+        let paused = StrategySettings { paused: true, ..Default::default() };
+
+        let result = act_on_signal("abc", signal, paused, &Settings::default(), false, "http://localhost:1".into()).await;
+        assert!(matches!(result, Err(SignalError::Paused(strategy)) if strategy == "fancy v1"));
+    }
+
+    #[tokio::test]
+    async fn it_plans_dry_runs_without_sending_them() {
+        let server = MockServer::start();
+        let mock = mock_remote_server(&server);
+        let mut signal = parse_signal(None, GOOD_SIGNAL_JSON.as_bytes()).unwrap();
+        signal.dry_run = true;
+
+        let settings = get_settings().clone();
+        let response = act_on_signal("abc", signal, StrategySettings::default(), &settings, true, server.base_url())
+            .await
+            .unwrap();
+        mock.assert_hits(0);
+        assert_eq!(response.decision, Decision::DryRun);
+        assert_eq!(response.plan[0].requests.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_keeps_tenants_to_their_own_accounts() {
+        use settings::{AccountSettings, TenantSettings};
+
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method("POST")
+                .path("/trade_signal/trading_view")
+                .body_contains(r#""email_token":"alice-token""#)
+                .body_contains(r#""bot_id":111"#);
+            then.status(200);
+        });
+        let mut alice = TenantSettings::default();
+        alice.accounts.insert("default".into(), AccountSettings {
+            email_token: "alice-token".into(),
+            long_bot_id: 111,
+            short_bot_id: 222,
+            ..Default::default()
+        });
+        let mut settings = get_settings().clone();
+        settings.tenants.insert("alice".into(), alice);
+        let settings = settings.for_tenant("alice").unwrap();
+
+        let mut signal = parse_signal(None, br#"{"action": "close_long"}"#).unwrap();
+        let strategy = signal.route(&settings).unwrap();
+        let response = act_on_signal("alice:abc", signal, strategy, &settings, true, server.base_url())
+            .await
+            .unwrap();
+        mock.assert_hits(1);
+        assert_eq!(response.plan[0].account, "alice:default");
+
+        let unknown = request()
+            .path("/trade/not-a-tenant")
+            .method("POST")
+            .body(GOOD_SIGNAL_JSON)
+            .filter(&entire_api(server.base_url()))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(reply_json(unknown).await["error"]["message"], "Not found");
+    }
+
+    #[tokio::test]
+    async fn it_turns_away_big_bodies() {
+        let server = MockServer::start();
+        let big = format!("buy {}", "x".repeat(get_settings().limits.max_body_bytes));
+        let response = mock_request()
+            .body(&big)
+            .filter(&entire_api(server.base_url()))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(reply_json(response).await["error"]["code"], "too_large");
+
+        // Without a Content-Length, it's read up to the limit and no further
+        let chunks = futures::stream::iter(vec![Ok(Bytes::from("buy ")), Ok(Bytes::from("x".repeat(16)))]);
+        assert!(matches!(read_body(chunks, None, 16).await, Err(SignalError::TooLarge { max_bytes: 16 })));
+    }
+
+    #[tokio::test]
+    async fn it_says_when_to_retry_after_a_rate_limit() {
+        let error = SignalError::RateLimited { limit: "10 signals a minute".into(), retry_after_secs: 6 };
+        let rejection = warp::reject::custom(Rejected { signal_id: "abc".into(), error });
+        let response = handle_error(rejection).await.unwrap().into_response();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "6");
+        assert_eq!(reply_json(response).await["error"]["code"], "rate_limited");
+    }
+
+    #[tokio::test]
+    async fn it_holds_delayed_signals_until_theyre_due() {
+        let server = MockServer::start();
+        let mock = mock_remote_server(&server);
+        let response = mock_request()
+            .body(r#"{"action": "close_short", "delay_secs": 600}"#)
+            .filter(&entire_api(server.base_url()))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = reply_json(response).await;
+
+        mock.assert_hits(0);
+        assert_eq!(body["decision"], "scheduled");
+        let signal_id = body["signal_id"].as_str().unwrap();
+        let job = SCHEDULER.all().into_iter().find(|job| job.id == signal_id).unwrap();
+        assert_eq!(serde_json::to_value(job.run_at).unwrap(), body["run_at"]);
+        // Its risk limits are checked when it's due, not now
+        assert!(matches!(job.command, Command::Signal { sequences, risk: Some(_), .. } if sequences.len() == 1));
+    }
+
+    #[tokio::test]
+    async fn it_checks_held_signals_against_the_risk_limits_when_theyre_due() {
+        let server = MockServer::start();
+        let mock = mock_remote_server(&server);
+        let request = OutgoingRequest::new((outgoing::deal_and_bot_types::ActionType::StartDeal, BotType::Long));
+        let risk = RiskCheck {
+            strategy: "held".into(),
+            limits: settings::RiskSettings { max_signals: Some(0), ..Default::default() },
+            requests: vec![request.clone()],
+            entry_price: None,
+            give_up_at: None,
+        };
+
+        let sequences = vec![Sequence::new("held", "held", vec![request])];
+        release("held", "held", sequences, Some(Box::new(risk)), vec![], server.base_url());
+        sleep(Duration::from_millis(100)).await;
+        mock.assert_hits(0);
+    }
+
     #[tokio::test]
     async fn it_returns_bad_request_for_get_request() {
         let server = MockServer::start();
@@ -319,7 +1310,7 @@ mod tests {
             request()
                 .path("/trade")
                 .method("PUT")
-                .body(&GOOD_SIGNAL_JSON)
+                .body(GOOD_SIGNAL_JSON)
                 .filter(&entire_api(server.base_url()))
                 .await
                 .unwrap()
@@ -337,7 +1328,7 @@ mod tests {
 
         // Simulate the incoming signal
         let incoming_signal_request = mock_request()
-            .body(&GOOD_SIGNAL_JSON)
+            .body(GOOD_SIGNAL_JSON)
             .filter(&entire_api(server.base_url()))
             .await;
 
@@ -347,6 +1338,31 @@ mod tests {
         mock.assert_hits(2);
     }
 
+    #[test]
+    fn it_redacts_sensitive_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer hunter2"));
+        headers.insert("x-tradeproxy-token", HeaderValue::from_static("hunter2"));
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+
+        let logged = format!("{:?}", redacted_headers(&headers));
+        assert!(!logged.contains("hunter2"));
+        assert!(logged.contains("application/json"));
+    }
+
+    #[test]
+    fn it_only_believes_x_real_ip_from_trusted_proxies() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", HeaderValue::from_static("203.0.113.7"));
+        let proxies = LimitSettings::default().trusted_proxies;
+
+        let nginx = "127.0.0.1:50000".parse().ok();
+        assert_eq!(get_real_remote_ip(&headers, nginx, &proxies), "203.0.113.7");
+        let stranger = "198.51.100.1:50000".parse().ok();
+        assert_eq!(get_real_remote_ip(&headers, stranger, &proxies), "198.51.100.1");
+        assert_eq!(get_real_remote_ip(&headers, nginx, &[]), "127.0.0.1");
+    }
+
     fn mock_remote_server<'a>(server: &'a MockServer) -> httpmock::MockRef<'a> {
         server.mock(move |when, then| {
             when.method("POST")
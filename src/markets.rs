@@ -0,0 +1,289 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{error, info};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, path::Path, sync::RwLock};
+use tokio::time::{interval, Duration};
+use crate::settings::MarketSettings;
+
+/// What one market trades in, and the smallest steps it takes.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Market {
+    pub symbol: String,
+    pub base: String,
+    pub quote: String,
+    /// The smallest order, in the quote currency
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub min_notional: Option<f64>,
+    /// Order amounts, in the base currency, have to be a multiple of this
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub lot_step: Option<f64>,
+    /// Prices have to be a multiple of this
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub price_tick: Option<f64>,
+}
+
+/// Exchange APIs often send numbers as strings.
+fn number_or_string<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(f64),
+        String(String),
+    }
+
+    match Option::<NumberOrString>::deserialize(deserializer)? {
+        Some(NumberOrString::Number(n)) => Ok(Some(n)),
+        Some(NumberOrString::String(s)) if s.trim().is_empty() => Ok(None),
+        Some(NumberOrString::String(s)) => s.trim().parse().map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// `ticker` the way markets are kept: TradingView's `BINANCE:BTCUSDT`,
+/// `BTCUSDT.P` and `btc/usdt` are all `BTCUSDT`.
+pub fn symbol_key(ticker: &str) -> String {
+    let symbol = ticker.rsplit(':').next().unwrap_or(ticker);
+    let symbol = symbol.strip_suffix(".P").unwrap_or(symbol);
+    symbol
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// `ticker` with its exchange, if it has one: `bitmex:xbtusd.P` is `BITMEX:XBTUSD`.
+fn exchange_key(ticker: &str) -> Option<String> {
+    let (exchange, _) = ticker.rsplit_once(':')?;
+    Some(format!("{}:{}", exchange.trim().to_ascii_uppercase(), symbol_key(ticker)))
+}
+
+/// Markets by `symbol_key`, the `symbol_key`s `markets.symbols` points
+/// elsewhere (by `exchange_key` for the ones with an exchange), and when they
+/// were loaded.
+struct Loaded {
+    markets: HashMap<String, Market>,
+    aliases: HashMap<String, String>,
+    at: DateTime<Utc>,
+}
+
+impl Loaded {
+    fn find(&self, ticker: &str) -> Option<&Market> {
+        let key = symbol_key(ticker);
+        let alias = exchange_key(ticker)
+            .and_then(|exchange_key| self.aliases.get(&exchange_key))
+            .or_else(|| self.aliases.get(&key));
+        self.markets.get(alias.unwrap_or(&key))
+    }
+}
+
+/// The last markets we loaded, if any.
+#[derive(Default)]
+pub struct MarketCache {
+    loaded: RwLock<Option<Loaded>>,
+}
+
+lazy_static! {
+    /// The markets from `markets.source`, once they've loaded
+    pub static ref MARKETS: MarketCache = MarketCache::default();
+}
+
+impl MarketCache {
+    /// The market `ticker` is for, if we know of it.
+    pub fn find(&self, ticker: &str) -> Option<Market> {
+        let loaded = self.loaded.read().unwrap();
+        loaded.as_ref().and_then(|loaded| loaded.find(ticker).cloned())
+    }
+
+    /// When the markets were last loaded, if they ever were.
+    pub fn loaded_at(&self) -> Option<DateTime<Utc>> {
+        self.loaded.read().unwrap().as_ref().map(|loaded| loaded.at)
+    }
+
+    /// Swaps in `markets`, loaded at `now`, and the `symbols` that stand for them.
+    pub fn replace(&self, markets: Vec<Market>, symbols: &HashMap<String, String>, now: DateTime<Utc>) {
+        let markets = markets.into_iter().map(|market| (symbol_key(&market.symbol), market)).collect();
+        let aliases = symbols
+            .iter()
+            .map(|(alias, symbol)| (exchange_key(alias).unwrap_or_else(|| symbol_key(alias)), symbol_key(symbol)))
+            .collect();
+        *self.loaded.write().unwrap() = Some(Loaded { markets, aliases, at: now });
+    }
+
+    /// Loads the markets again. If that doesn't work, the ones we had stay.
+    pub async fn refresh(&self, settings: &MarketSettings) {
+        match load(settings).await {
+            Ok(markets) => {
+                info!("Loaded {} markets from {}", markets.len(), settings.source);
+                self.replace(markets, &settings.symbols, Utc::now());
+            }
+            Err(e) => error!("Couldn't load markets from {}, keeping what we had: {}", settings.source, e),
+        }
+    }
+}
+
+/// Reads every market from `settings.source`, giving up on a URL after
+/// `settings.timeout_secs`.
+pub async fn load(settings: &MarketSettings) -> Result<Vec<Market>, String> {
+    let source = &settings.source;
+    let json: Value = if source.starts_with("http://") || source.starts_with("https://") {
+        let timeout = Duration::from_secs(settings.timeout_secs);
+        Client::builder()
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .build()
+            .map_err(|e| e.to_string())?
+            .get(source)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?
+    } else if Path::new(source).extension().is_some_and(|extension| extension == "csv") {
+        let mut csv = csv::Reader::from_path(source).map_err(|e| e.to_string())?;
+        return csv.deserialize().collect::<Result<_, _>>().map_err(|e| e.to_string());
+    } else {
+        let file = std::fs::read_to_string(source).map_err(|e| e.to_string())?;
+        serde_json::from_str(&file).map_err(|e| e.to_string())?
+    };
+
+    let list = match &settings.json_pointer {
+        Some(pointer) => json.pointer(pointer).ok_or_else(|| format!("nothing at {}", pointer))?,
+        None => &json,
+    };
+    Vec::<Market>::deserialize(list).map_err(|e| e.to_string())
+}
+
+/// Loads `MARKETS` again every `refresh_secs`, after the first load at startup.
+pub async fn refresh_periodically(settings: MarketSettings) {
+    info!("Refreshing markets every {}s", settings.refresh_secs);
+    let mut ticks = interval(Duration::from_secs(settings.refresh_secs));
+    // The first tick is straight away
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        MARKETS.refresh(&settings).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+    use std::io::Write;
+
+    fn settings(source: &str) -> MarketSettings {
+        MarketSettings {
+            source: source.into(),
+            ..Default::default()
+        }
+    }
+
+    fn file(suffix: &str, contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn it_finds_markets_by_tradingview_ticker() {
+        assert_eq!(symbol_key("BINANCE:BTCUSDT"), "BTCUSDT");
+        assert_eq!(symbol_key("BTCUSDT.P"), "BTCUSDT");
+        assert_eq!(symbol_key("btc/usdt"), "BTCUSDT");
+        assert_eq!(symbol_key("ETH-PERP"), "ETHPERP");
+    }
+
+    #[test]
+    fn it_finds_markets_by_alias() {
+        let btc = Market {
+            symbol: "BTCUSD".into(),
+            base: "BTC".into(),
+            quote: "USD".into(),
+            min_notional: None,
+            lot_step: None,
+            price_tick: None,
+        };
+        let eth = Market { symbol: "ETHUSD".into(), base: "ETH".into(), ..btc.clone() };
+        let symbols = vec![("XBTUSD", "BTCUSD"), ("kraken:ETHXBT", "ETHUSD")]
+            .into_iter()
+            .map(|(alias, symbol)| (alias.to_string(), symbol.to_string()))
+            .collect();
+        let cache = MarketCache::default();
+        cache.replace(vec![btc, eth], &symbols, Utc::now());
+
+        assert_eq!(cache.find("BITMEX:XBTUSD").unwrap().symbol, "BTCUSD");
+        assert_eq!(cache.find("xbtusd.P").unwrap().symbol, "BTCUSD");
+        assert_eq!(cache.find("KRAKEN:ETHXBT").unwrap().symbol, "ETHUSD");
+        assert_eq!(cache.find("BINANCE:ETHXBT"), None);
+        assert_eq!(cache.find("BTCUSD").unwrap().symbol, "BTCUSD");
+    }
+
+    #[tokio::test]
+    async fn it_gives_up_on_slow_sources() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method("GET").path("/markets");
+            then.status(200).delay(std::time::Duration::from_secs(3)).body("[]");
+        });
+        let settings = MarketSettings {
+            source: format!("{}/markets", server.base_url()),
+            timeout_secs: 1,
+            ..Default::default()
+        };
+        assert!(load(&settings).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_loads_json_and_csv_files() {
+        let json = file(".json", r#"[
+            {"symbol": "BTCUSDT", "base": "BTC", "quote": "USDT", "min_notional": "10", "lot_step": 0.001}
+        ]"#);
+        let markets = load(&settings(json.path().to_str().unwrap())).await.unwrap();
+        assert_eq!(markets[0].min_notional, Some(10.0));
+        assert_eq!(markets[0].lot_step, Some(0.001));
+        assert_eq!(markets[0].price_tick, None);
+
+        let csv = file(".csv", "symbol,base,quote,min_notional,lot_step,price_tick\nETHUSDT,ETH,USDT,5,,0.01\n");
+        let markets = load(&settings(csv.path().to_str().unwrap())).await.unwrap();
+        assert_eq!(markets[0].base, "ETH");
+        assert_eq!(markets[0].lot_step, None);
+        assert_eq!(markets[0].price_tick, Some(0.01));
+
+        assert!(load(&settings("/nonexistent/markets.json")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_keeps_what_it_had_when_a_refresh_fails() {
+        let server = MockServer::start();
+        let mut mock = server.mock(|when, then| {
+            when.method("GET").path("/markets");
+            then.status(200)
+                .body(r#"{"symbols": [{"symbol": "BTCUSDT", "base": "BTC", "quote": "USDT"}]}"#);
+        });
+        let settings = MarketSettings {
+            source: format!("{}/markets", server.base_url()),
+            json_pointer: Some("/symbols".into()),
+            ..Default::default()
+        };
+
+        let cache = MarketCache::default();
+        assert_eq!(cache.find("BTCUSDT"), None);
+        cache.refresh(&settings).await;
+        assert_eq!(cache.find("BINANCE:BTCUSDT").unwrap().quote, "USDT");
+        let loaded_at = cache.loaded_at();
+        assert!(loaded_at.is_some());
+
+        mock.delete();
+        server.mock(|when, then| {
+            when.method("GET").path("/markets");
+            then.status(500);
+        });
+        cache.refresh(&settings).await;
+        assert!(cache.find("BTCUSDT").is_some());
+        assert_eq!(cache.loaded_at(), loaded_at);
+    }
+}
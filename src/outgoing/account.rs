@@ -0,0 +1,203 @@
+use log::warn;
+use crate::settings::{AccountSettings, Secret, Settings, StrategySettings, DEFAULT_ACCOUNT, TENANT_SEPARATOR};
+use super::{OutgoingRequest, deal_and_bot_types::{BotType, OrderSize}};
+
+/// A 3commas account we send requests to: the top-level one, or one from `accounts`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account {
+    /// A tenant's accounts are called `<tenant>:<name>`, to keep them apart from everyone else's
+    pub name: String,
+    pub email_token: Secret,
+    pub long_bot_id: u64,
+    pub short_bot_id: u64,
+    pub size_multiplier: f64,
+}
+
+impl Account {
+    /// The account called `name`. `default` is the top-level account, unless
+    /// `accounts` has one by that name. A tenant's settings only have the
+    /// tenant's accounts, and the top-level ones can find them as `<tenant>:<name>`.
+    pub fn named(settings: &Settings, name: &str) -> Option<Account> {
+        if settings.tenant.is_some() {
+            let account = settings.accounts.get(name)?;
+            return Some(Account::from_settings(&settings.qualified(name), account));
+        }
+
+        match settings.accounts.get(name) {
+            Some(account) => Some(Account::from_settings(name, account)),
+            None if name == DEFAULT_ACCOUNT => Some(Account {
+                name: DEFAULT_ACCOUNT.into(),
+                email_token: settings.email_token.clone(),
+                long_bot_id: settings.long_bot_id,
+                short_bot_id: settings.short_bot_id,
+                size_multiplier: 1.0,
+            }),
+            None => {
+                let (tenant, account) = name.split_once(TENANT_SEPARATOR)?;
+                let account = settings.tenants.get(tenant)?.accounts.get(account)?;
+                Some(Account::from_settings(name, account))
+            }
+        }
+    }
+
+    fn from_settings(name: &str, account: &AccountSettings) -> Account {
+        Account {
+            name: name.into(),
+            email_token: account.email_token.clone(),
+            long_bot_id: account.long_bot_id,
+            short_bot_id: account.short_bot_id,
+            size_multiplier: account.size_multiplier,
+        }
+    }
+
+    /// Every account: `default`, the ones in `accounts` and every tenant's.
+    pub fn all(settings: &Settings) -> Vec<Account> {
+        let mut names: Vec<String> = settings.accounts.keys().cloned().collect();
+        if !settings.accounts.contains_key(DEFAULT_ACCOUNT) {
+            names.push(DEFAULT_ACCOUNT.into());
+        }
+        names.sort_unstable();
+
+        let mut tenants: Vec<_> = settings.tenants.iter().collect();
+        tenants.sort_by_key(|(name, _)| name.as_str());
+        for (tenant, tenant_settings) in tenants {
+            let mut tenant_accounts: Vec<_> = tenant_settings
+                .accounts
+                .keys()
+                .map(|name| format!("{}{}{}", tenant, TENANT_SEPARATOR, name))
+                .collect();
+            tenant_accounts.sort_unstable();
+            names.extend(tenant_accounts);
+        }
+
+        names.iter().filter_map(|name| Account::named(settings, name)).collect()
+    }
+
+    /// The accounts `strategy` trades on. Ones that aren't configured are left out.
+    pub fn for_strategy(settings: &Settings, strategy: &StrategySettings) -> Vec<Account> {
+        if strategy.accounts.is_empty() {
+            return Account::named(settings, DEFAULT_ACCOUNT).into_iter().collect();
+        }
+
+        strategy
+            .accounts
+            .iter()
+            .filter_map(|name| {
+                let account = Account::named(settings, name);
+                if account.is_none() {
+                    warn!("No account called {:?}, skipping it", name);
+                }
+                account
+            })
+            .collect()
+    }
+
+    pub fn bot_id(&self, bot_type: BotType) -> u64 {
+        match bot_type {
+            BotType::Long => self.long_bot_id,
+            BotType::Short => self.short_bot_id,
+        }
+    }
+
+    /// Which of this account's bots has this id, if either.
+    pub fn bot_type(&self, bot_id: u64) -> Option<BotType> {
+        [BotType::Long, BotType::Short]
+            .iter()
+            .copied()
+            .find(|bot_type| self.bot_id(*bot_type) == bot_id)
+    }
+
+    /// `request`, made for the default account, as it should go to this one:
+    /// this account's token and bot, and its deal size scaled.
+    pub fn adapt(&self, request: &OutgoingRequest) -> OutgoingRequest {
+        let mut adapted = request.clone();
+        adapted.email_token = self.email_token.clone();
+        if let Some(bot_type) = BotType::from_bot_id(request.bot_id) {
+            adapted.bot_id = self.bot_id(bot_type);
+        }
+
+        if (self.size_multiplier - 1.0).abs() > f64::EPSILON {
+            adapted.order = request.order.as_ref().and_then(|order| {
+                let amount: f64 = order.amount.parse().ok()?;
+                Some(OrderSize::new(amount * self.size_multiplier, order.currency_type))
+            });
+        }
+        adapted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outgoing::deal_and_bot_types::{ActionType, CurrencyType};
+
+    fn settings() -> Settings {
+        let mut settings = Settings::default();
+        settings.accounts.insert("family".into(), AccountSettings {
+            email_token: "family-token".into(),
+            long_bot_id: 111,
+            short_bot_id: 222,
+            size_multiplier: 0.5,
+            ..Default::default()
+        });
+        settings
+    }
+
+    #[test]
+    fn it_lists_every_account() {
+        let mut settings = settings();
+        let mut alice = crate::settings::TenantSettings::default();
+        alice.accounts.insert("main".into(), AccountSettings { long_bot_id: 333, ..Default::default() });
+        settings.tenants.insert("alice".into(), alice);
+
+        let names: Vec<_> = Account::all(&settings).into_iter().map(|account| account.name).collect();
+        assert_eq!(names, ["default", "family", "alice:main"]);
+    }
+
+    #[test]
+    fn it_picks_the_strategys_accounts() {
+        let settings = settings();
+        let names = |accounts: &[&str]| {
+            let strategy = StrategySettings {
+                accounts: accounts.iter().map(|name| name.to_string()).collect(),
+                ..Default::default()
+            };
+            Account::for_strategy(&settings, &strategy)
+                .into_iter()
+                .map(|account| account.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(&[]), ["default"]);
+        assert_eq!(names(&["default", "family", "nobody"]), ["default", "family"]);
+    }
+
+    #[test]
+    fn it_adapts_requests_to_the_account() {
+        let family = Account::named(&settings(), "family").unwrap();
+        let request = OutgoingRequest::new((ActionType::StartDeal, BotType::Short))
+            .sized(Some(OrderSize::new(3.0, CurrencyType::Quote)));
+
+        let adapted = family.adapt(&request);
+        assert_eq!(adapted.bot_id, 222);
+        assert_eq!(adapted.email_token.expose(), "family-token");
+        assert_eq!(adapted.order, Some(OrderSize::new(1.5, CurrencyType::Quote)));
+        assert_eq!(family.bot_type(222), Some(BotType::Short));
+    }
+
+    #[test]
+    fn it_keeps_tenants_to_their_own_accounts() {
+        let mut settings = settings();
+        let mut alice = crate::settings::TenantSettings::default();
+        alice.accounts.insert("main".into(), AccountSettings { long_bot_id: 333, ..Default::default() });
+        settings.tenants.insert("alice".into(), alice);
+
+        let tenant = settings.for_tenant("alice").unwrap();
+        assert_eq!(Account::named(&tenant, "main").unwrap().name, "alice:main");
+        assert_eq!(Account::named(&tenant, "family"), None);
+        assert!(Account::for_strategy(&tenant, &StrategySettings::default()).is_empty());
+
+        assert_eq!(Account::named(&settings, "alice:main").unwrap().long_bot_id, 333);
+        assert_eq!(Account::named(&settings, "bob:main"), None);
+    }
+}
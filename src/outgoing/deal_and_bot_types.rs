@@ -1,30 +1,53 @@
 use serde::{Serialize, Deserialize};
 use crate::settings::get_settings;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub enum ActionType {
     #[serde(rename = "start_bot")]
     StartBot,
     #[serde(rename = "stop_bot")]
     StopBot,
+    #[default]
     StartDeal,
     #[serde(rename = "close_at_market_price")]
     CloseDeal,
 }
 
-impl Default for ActionType {
-    fn default() -> Self {
-        ActionType::StartDeal
-    }
-}
-
 impl ActionType {
     pub fn is_start(d: &ActionType) -> bool {
         matches!(d, ActionType::StartDeal)
     }
+
+    /// Opens or closes a deal, so sending it twice could trade twice.
+    pub fn is_trade(d: &ActionType) -> bool {
+        matches!(d, ActionType::StartDeal | ActionType::CloseDeal)
+    }
+}
+
+/// How big a deal 3commas should open, instead of the bot's base order size.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OrderSize {
+    pub amount: String,
+    pub currency_type: CurrencyType,
 }
 
-#[derive(Debug)]
+impl OrderSize {
+    pub fn new(amount: f64, currency_type: CurrencyType) -> Self {
+        OrderSize {
+            amount: amount.to_string(),
+            currency_type,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CurrencyType {
+    Base,
+    Quote,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BotType {
     Long,
     Short,
@@ -38,4 +61,12 @@ impl BotType {
             BotType::Short => settings.short_bot_id,
         }
     }
+
+    /// Which of our bots has this id, if either.
+    pub fn from_bot_id(bot_id: u64) -> Option<BotType> {
+        [BotType::Long, BotType::Short]
+            .iter()
+            .copied()
+            .find(|bot_type| bot_type.get_bot_id() == bot_id)
+    }
 }
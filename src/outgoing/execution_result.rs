@@ -1,41 +1,111 @@
-use log::{debug, info};
+use log::{debug, error, info};
 use reqwest::Response;
-use super::{OutgoingRequest, deal_and_bot_types::ActionType};
+use std::time::Duration;
+use super::{OutgoingRequest, webhook::{redact_url, WebhookRequest}};
+use crate::journal::JournalEvent;
 
 pub type ReqwestResult = Result<Response, reqwest::Error>;
 
+/// `e` without the path and query of the URL it was for, where webhooks
+/// tend to keep their tokens.
+pub fn describe_error(e: &reqwest::Error) -> String {
+    match e.url() {
+        Some(url) => e.to_string().replace(url.as_str(), &redact_url(url.as_str())),
+        None => e.to_string(),
+    }
+}
+
+/// What we sent, and where.
+pub enum Sent {
+    Bot(OutgoingRequest),
+    Webhook(WebhookRequest),
+}
+
+impl Sent {
+    fn describe(&self) -> String {
+        match self {
+            Sent::Bot(request) => format!("{:?} request to bot {:?}", request.action, request.bot_id),
+            Sent::Webhook(webhook) => format!("Webhook {:?}", webhook.name),
+        }
+    }
+}
+
 pub struct ExecutionResult {
-    request: OutgoingRequest,
+    sent: Sent,
     result: ReqwestResult,
+    latency: Duration,
+    attempts: u32,
 }
 
 impl ExecutionResult {
-    pub fn new(result: ReqwestResult, request: OutgoingRequest) -> Self {
+    pub fn new(result: ReqwestResult, sent: Sent, latency: Duration, attempts: u32) -> Self {
         ExecutionResult {
-            request,
+            sent,
             result,
+            latency,
+            attempts,
         }
     }
 
-    pub fn status(&self) -> reqwest::StatusCode {
-        self.result.as_ref().unwrap().status()
+    /// The 3commas request, if that's what this was.
+    pub fn bot_request(&self) -> Option<&OutgoingRequest> {
+        match &self.sent {
+            Sent::Bot(request) => Some(request),
+            Sent::Webhook(_) => None,
+        }
+    }
+
+    /// The response's status, or `None` if we never got a response.
+    pub fn status(&self) -> Option<reqwest::StatusCode> {
+        self.result.as_ref().ok().map(Response::status)
     }
 
     pub fn is_success(&self) -> bool {
-        self.status().is_success()
+        self.status().is_some_and(|status| status.is_success())
+    }
+
+    /// How this went, for the journal. `account` is the one the sequence went to.
+    pub fn journal_event(&self, account: &str) -> JournalEvent {
+        let status = self.status().map(|status| status.as_u16());
+        let latency_ms = self.latency.as_millis() as u64;
+        let error = self.result.as_ref().err().map(describe_error);
+
+        match &self.sent {
+            Sent::Bot(request) => JournalEvent::RequestExecuted {
+                account: account.into(),
+                action: format!("{:?}", request.action),
+                bot_id: request.bot_id,
+                status,
+                latency_ms,
+                error,
+                attempts: self.attempts,
+            },
+            Sent::Webhook(webhook) => JournalEvent::WebhookExecuted {
+                name: webhook.name.clone(),
+                method: webhook.method.clone(),
+                url: webhook.origin(),
+                status,
+                latency_ms,
+                error,
+                attempts: self.attempts,
+            },
+        }
     }
 
     pub fn log(&self) {
-        let action: &ActionType = &self.request.action;
-        let bot_id: u64 = self.request.bot_id;
-        let result: &ReqwestResult = &self.result;
-        let result_bytes = &result.as_ref().unwrap();
-
-        if self.is_success() {
-            info!("{:?} request to bot {:?} successful", action, bot_id);
-        } else {
-            info!("{:?} request to bot {:?} failed :(", action, bot_id);
-            debug!("Result content: {:?}", result_bytes);
+        let what = self.sent.describe();
+
+        match &self.result {
+            Ok(_) if self.is_success() => {
+                info!("{} successful", what);
+            }
+            Ok(response) => {
+                info!("{} failed :(", what);
+                debug!("Result status: {}, headers: {:?}", response.status(), response.headers());
+            }
+            Err(e) => {
+                error!("{} couldn't be sent: {}", what, describe_error(e));
+            }
         }
     }
 }
@@ -1,22 +1,42 @@
 use log::info;
+use opentelemetry::KeyValue;
 use serde::{Deserialize, Serialize};
 use reqwest::Client;
-use super::{get_settings, incoming::Action};
-use crate::SETTINGS;
+use std::time::Instant;
+use super::{get_settings, incoming::{Action, exits::Exits}};
+use crate::{SETTINGS, settings::Secret, telemetry};
+pub mod account;
 pub mod execution_result;
 use execution_result::*;
 pub mod deal_and_bot_types;
 use deal_and_bot_types::*;
+pub mod retry;
+pub mod sequence;
+pub mod webhook;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OutgoingRequest {
     #[serde(default)]
     #[serde(skip_serializing_if = "ActionType::is_start")]
     pub action: ActionType,
     pub bot_id: u64,
     pub delay_seconds: u64,
-    pub email_token: String,
+    pub email_token: Secret,
     pub message_type: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order: Option<OrderSize>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_loss_percentage: Option<f64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub take_profit_percentage: Option<f64>,
+    /// How long after the signal's first requests this one goes out, from a
+    /// rule's `wait`. We hold it back ourselves (see `schedule::stages`), so
+    /// 3commas never sees this.
+    #[serde(skip)]
+    pub hold_secs: u64,
 }
 
 impl OutgoingRequest {
@@ -27,10 +47,41 @@ impl OutgoingRequest {
         OutgoingRequest {
             message_type: "bot".into(),
             bot_id: bot_type.get_bot_id(),
-            email_token: settings.email_token.to_string(),
+            email_token: settings.email_token.clone(),
             delay_seconds: 0,
             action,
+            order: None,
+            stop_loss_percentage: None,
+            take_profit_percentage: None,
+            hold_secs: 0,
+        }
+    }
+
+    /// Sets the deal size, if this request starts a deal.
+    pub fn sized(mut self, order: Option<OrderSize>) -> Self {
+        if ActionType::is_start(&self.action) {
+            self.order = order;
+        }
+        self
+    }
+
+    /// Sets the stop-loss and take-profit, if this request starts a deal.
+    pub fn with_exits(mut self, exits: Exits) -> Self {
+        if ActionType::is_start(&self.action) {
+            self.stop_loss_percentage = exits.stop_loss_percentage;
+            self.take_profit_percentage = exits.take_profit_percentage;
         }
+        self
+    }
+
+    /// Holds the request back for `secs` after the signal's first requests.
+    pub fn held(mut self, secs: u64) -> Self {
+        self.hold_secs = secs;
+        self
+    }
+
+    pub fn has_exits(&self) -> bool {
+        self.stop_loss_percentage.is_some() || self.take_profit_percentage.is_some()
     }
 
     pub fn start(bot_type: BotType) -> Self {
@@ -53,11 +104,24 @@ impl OutgoingRequest {
             self.action,
             self
         );
-        let request_path = SETTINGS.read().unwrap().request_path.clone();
+        let (request_path, retry) = {
+            let settings = SETTINGS.read().unwrap();
+            (settings.request_path.clone(), settings.retry.clone())
+        };
         let url = format!("{}{}", server, request_path);
         let client: Client = Client::new();
-        let result: ReqwestResult = client.post(url).json(&self).send().await;
-        ExecutionResult::new(result, self)
+        let what = format!("{:?} request to bot {}", self.action, self.bot_id);
+        let attributes = vec![
+            KeyValue::new("bot.id", self.bot_id as i64),
+            KeyValue::new("bot.action", format!("{:?}", self.action)),
+        ];
+        let started = Instant::now();
+        let idempotent = !ActionType::is_trade(&self.action);
+        let sent = retry::send_with_retry(&retry, &what, idempotent, || {
+            client.post(&url).json(&self).send()
+        });
+        let (result, attempts) = telemetry::in_span("execute_request", attributes, sent).await;
+        ExecutionResult::new(result, Sent::Bot(self), started.elapsed(), attempts)
     }
 }
 
@@ -82,6 +146,33 @@ mod data_tests {
         );
     }
 
+    #[test]
+    fn sized_start_json_is_correct() {
+        let request = OutgoingRequest::new((
+            ActionType::StartDeal,
+            BotType::Long
+        )).sized(Some(OrderSize::new(2.5, CurrencyType::Base)));
+        assert_eq!(
+            serde_json::to_string(&request)
+            .unwrap(),
+            CORRECT_LONG_START_JSON.replace("}", r#","order":{"amount":"2.5","currency_type":"base"}}"#)
+        );
+    }
+
+    #[test]
+    fn start_json_with_exits_is_correct() {
+        let exits = Exits { stop_loss_percentage: Some(2.0), take_profit_percentage: Some(4.5) };
+        let request = OutgoingRequest::new((
+            ActionType::StartDeal,
+            BotType::Long
+        )).with_exits(exits);
+        assert_eq!(
+            serde_json::to_string(&request)
+            .unwrap(),
+            CORRECT_LONG_START_JSON.replace("}", r#","stop_loss_percentage":2.0,"take_profit_percentage":4.5}"#)
+        );
+    }
+
     #[test]
     fn close_json_is_correct() {
         let request = OutgoingRequest::new((
@@ -105,7 +196,7 @@ mod request_tests {
 
     fn get_string_from_request(req: &HttpMockRequest) -> String {
         let bytes: &Vec<u8> = req.body.as_ref().unwrap();
-        String::from_utf8_lossy(&bytes).into_owned()
+        String::from_utf8_lossy(bytes).into_owned()
     }
 
     fn request_is_valid_json(req: &HttpMockRequest) -> bool {
@@ -118,8 +209,7 @@ mod request_tests {
             String::from(data_tests::CORRECT_LONG_START_JSON),
         ];
 
-        if known_good_requests.contains(&s) { return true; }
-        return false;
+        known_good_requests.contains(&s)
     }
 
     #[tokio::test]
@@ -133,7 +223,7 @@ mod request_tests {
             when.method("POST")
                 .path("/trade_signal/trading_view")
                 .header("Content-Type", "application/json")
-                .matches(|req| request_is_valid_json(&req));
+                .matches(request_is_valid_json);
             then.status(200)
                 .body("Success!");
         });
@@ -155,7 +245,7 @@ mod request_tests {
             when.method("POST")
                 .path("/trade_signal/trading_view")
                 .header("Content-Type", "application/json")
-                .matches(|req| !request_is_valid_json(&req));
+                .matches(|req| !request_is_valid_json(req));
             then.status(501)
                 .body("Fail!");
         });
@@ -0,0 +1,128 @@
+use log::warn;
+use opentelemetry::KeyValue;
+use std::future::Future;
+use tokio::time::{sleep, Duration};
+use crate::{settings::RetrySettings, telemetry};
+use super::execution_result::{describe_error, ReqwestResult};
+
+/// Worth another go: the request never got there, or, if sending it twice is
+/// harmless, the other end says it's having trouble. Timeouts aren't retried,
+/// and neither are 5xx answers to requests that aren't `idempotent`, since the
+/// first try might have gone through and we don't want to trade twice.
+fn should_retry(result: &ReqwestResult, idempotent: bool) -> bool {
+    match result {
+        Ok(response) => {
+            let status = response.status();
+            idempotent && (status.is_server_error() || status.as_u16() == 429)
+        }
+        Err(e) => e.is_connect(),
+    }
+}
+
+/// Puts how an attempt went on its span.
+fn record_outcome(result: &ReqwestResult) {
+    match result {
+        Ok(response) => {
+            telemetry::record(KeyValue::new("http.status_code", response.status().as_u16() as i64));
+            if !response.status().is_success() {
+                telemetry::record_error(response.status().to_string());
+            }
+        }
+        Err(e) => telemetry::record_error(describe_error(e)),
+    }
+}
+
+/// Calls `send` until it works or we run out of attempts, backing off in
+/// between. Returns the last result and how many attempts it took. Only
+/// `idempotent` requests are tried again after an error status.
+pub async fn send_with_retry<F, Fut>(
+    retry: &RetrySettings,
+    what: &str,
+    idempotent: bool,
+    mut send: F,
+) -> (ReqwestResult, u32)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ReqwestResult>,
+{
+    let attempts = retry.attempts.max(1);
+    let mut backoff = Duration::from_millis(retry.backoff_ms);
+    let mut attempt = 1;
+
+    loop {
+        let result = telemetry::in_span("attempt", vec![KeyValue::new("attempt", attempt as i64)], async {
+            let result = send().await;
+            record_outcome(&result);
+            result
+        })
+        .await;
+        if attempt >= attempts || !should_retry(&result, idempotent) {
+            return (result, attempt);
+        }
+
+        warn!(
+            "{} failed (attempt {} of {}), trying again in {:?}",
+            what, attempt, attempts, backoff
+        );
+        let backoff_ms = KeyValue::new("backoff_ms", backoff.as_millis() as i64);
+        telemetry::in_span("backoff", vec![backoff_ms], sleep(backoff)).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+    use reqwest::Client;
+
+    #[tokio::test]
+    async fn it_gives_up_after_the_last_attempt() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/flaky");
+            then.status(502);
+        });
+
+        let retry = RetrySettings { attempts: 3, backoff_ms: 1 };
+        let url = server.url("/flaky");
+        let (result, attempts) = send_with_retry(&retry, "test", true, || Client::new().post(&url).send()).await;
+
+        mock.assert_hits(3);
+        assert_eq!(attempts, 3);
+        assert!(result.unwrap().status().is_server_error());
+    }
+
+    #[tokio::test]
+    async fn it_takes_no_for_an_answer() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/refused");
+            then.status(400);
+        });
+
+        let retry = RetrySettings { attempts: 3, backoff_ms: 1 };
+        let url = server.url("/refused");
+        let (_, attempts) = send_with_retry(&retry, "test", true, || Client::new().post(&url).send()).await;
+
+        mock.assert_hits(1);
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn it_doesnt_trade_twice_on_a_server_error() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/trade");
+            then.status(502);
+        });
+
+        let retry = RetrySettings { attempts: 3, backoff_ms: 1 };
+        let url = server.url("/trade");
+        let (_, attempts) = send_with_retry(&retry, "test", false, || Client::new().post(&url).send()).await;
+
+        mock.assert_hits(1);
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,157 @@
+use log::info;
+use opentelemetry::KeyValue;
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+use crate::{journal::{self, JournalEvent}, logging, telemetry, positions::POSITIONS, settings::DEFAULT_ACCOUNT, shutdown::{InFlight, IN_FLIGHT}};
+use super::{OutgoingRequest, webhook::WebhookRequest};
+
+/// Gives 3commas time to close one deal before we ask it to open the next.
+const PAUSE_BETWEEN_REQUESTS: Duration = Duration::from_secs(5);
+
+/// The requests one signal turned into, to be sent in order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sequence {
+    /// The journal id of the signal these came from
+    pub signal_id: String,
+    /// The strategy whose position these requests change
+    pub strategy: String,
+    /// The account the requests go to
+    #[serde(default = "default_account")]
+    pub account: String,
+    pub requests: Vec<OutgoingRequest>,
+    /// Sent once the 3commas requests are done
+    #[serde(default)]
+    pub webhooks: Vec<WebhookRequest>,
+}
+
+fn default_account() -> String {
+    DEFAULT_ACCOUNT.into()
+}
+
+impl Sequence {
+    pub fn new(signal_id: &str, strategy: &str, requests: Vec<OutgoingRequest>) -> Self {
+        Sequence {
+            signal_id: signal_id.into(),
+            strategy: strategy.into(),
+            account: DEFAULT_ACCOUNT.into(),
+            requests,
+            webhooks: vec![],
+        }
+    }
+
+    pub fn on_account(mut self, account: &str) -> Self {
+        self.account = account.into();
+        self
+    }
+
+    pub fn with_webhooks(mut self, webhooks: Vec<WebhookRequest>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty() && self.webhooks.is_empty()
+    }
+
+    /// Drops the next request or webhook, once it's been sent.
+    pub fn pop_front(&mut self) {
+        if !self.requests.is_empty() {
+            self.requests.remove(0);
+        } else if !self.webhooks.is_empty() {
+            self.webhooks.remove(0);
+        }
+    }
+}
+
+/// Executes the sequence's requests in order, then its webhooks, keeping `IN_FLIGHT` up to date so
+/// a shutdown can wait for us (or save whatever we didn't get to). Everything logged along the way
+/// carries the signal's id. Returns how each request and webhook went.
+pub async fn execute_sequence(sequence: Sequence, server: String) -> Vec<JournalEvent> {
+    let signal_id = sequence.signal_id.clone();
+    let account = KeyValue::new("account", sequence.account.clone());
+    let run = run(sequence, server, &IN_FLIGHT);
+    let run = telemetry::in_signal_span(&signal_id, "execute_sequence", vec![account], run);
+    logging::for_signal(&signal_id, run).await
+}
+
+/// Stops as soon as `in_flight` has saved the rest of the sequence for later,
+/// so none of it is sent now and again on the next start.
+async fn run(sequence: Sequence, server: String, in_flight: &InFlight) -> Vec<JournalEvent> {
+    let id = in_flight.begin(&sequence);
+    let count = sequence.requests.len();
+    let mut results = vec![];
+
+    for (i, request) in sequence.requests.into_iter().enumerate() {
+        if !in_flight.sending(id) {
+            info!("Shutting down, leaving the rest of the requests for the next start");
+            return results;
+        }
+        info!("Executing {:?} request to bot {} on {:?}...", request.action, request.bot_id, sequence.account);
+        let er = request.execute_with_server(server.clone()).await;
+        er.log();
+        if er.is_success() {
+            if let Some(request) = er.bot_request() {
+                POSITIONS.record(&sequence.account, &sequence.strategy, request);
+            }
+        }
+        let event = er.journal_event(&sequence.account);
+        journal::record(&sequence.signal_id, event.clone());
+        results.push(event);
+        in_flight.advance(id);
+
+        if i + 1 < count {
+            info!("Sleeping for {:?}...", PAUSE_BETWEEN_REQUESTS);
+            telemetry::in_span("pause", vec![], sleep(PAUSE_BETWEEN_REQUESTS)).await;
+            info!("Done sleeping!");
+        }
+    }
+
+    for webhook in sequence.webhooks {
+        if !in_flight.sending(id) {
+            info!("Shutting down, leaving the rest of the webhooks for the next start");
+            return results;
+        }
+        let er = webhook.execute().await;
+        er.log();
+        let event = er.journal_event(&sequence.account);
+        journal::record(&sequence.signal_id, event.clone());
+        results.push(event);
+        in_flight.advance(id);
+    }
+
+    in_flight.finish(id);
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+    use crate::outgoing::deal_and_bot_types::{ActionType, BotType};
+
+    #[tokio::test]
+    async fn it_stops_once_a_drain_has_saved_the_rest() {
+        let server = MockServer::start();
+        let trades = server.mock(|when, then| {
+            when.method("POST").path("/trade_signal/trading_view");
+            then.status(200);
+        });
+        let sequence = Sequence::new("abc", "default", vec![
+            OutgoingRequest::new((ActionType::CloseDeal, BotType::Short)),
+            OutgoingRequest::new((ActionType::StartDeal, BotType::Long)),
+        ]);
+
+        let in_flight = InFlight::new();
+        let drain = async {
+            // While the sequence pauses between its two requests
+            sleep(Duration::from_secs(1)).await;
+            in_flight.drain(Duration::from_millis(100)).await
+        };
+        let (results, unfinished) = tokio::join!(run(sequence, server.base_url(), &in_flight), drain);
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(unfinished[0].requests.len(), 1);
+        assert!(ActionType::is_start(&unfinished[0].requests[0].action));
+        trades.assert_hits(1);
+    }
+}
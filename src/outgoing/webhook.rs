@@ -0,0 +1,350 @@
+use chrono::Utc;
+use log::{info, warn};
+use opentelemetry::KeyValue;
+use reqwest::{Client, Method, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Instant;
+use crate::{
+    incoming::IncomingSignal,
+    telemetry,
+    settings::{get_settings, Secret, Settings, StrategySettings, WebhookSettings},
+};
+use super::{
+    OutgoingRequest,
+    deal_and_bot_types::{ActionType, BotType},
+    execution_result::{ExecutionResult, Sent},
+    retry,
+};
+
+/// A webhook with its templates filled in, ready to send. URLs and header
+/// values often carry tokens, so they're `Secret`s: left out of the logs, but
+/// kept in the pending file so the webhook can still go out after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WebhookRequest {
+    /// Its name under `webhooks` in the config
+    pub name: String,
+    pub method: String,
+    pub url: Secret,
+    pub headers: Vec<(String, Secret)>,
+    pub body: Option<String>,
+    /// Whether it's safe to send again after a server error
+    #[serde(default)]
+    pub idempotent: bool,
+}
+
+impl WebhookRequest {
+    /// Fills in `webhook`'s templates from `context`.
+    pub fn render(name: &str, webhook: &WebhookSettings, context: &Value) -> Result<Self, String> {
+        let method = webhook.method.to_ascii_uppercase();
+        Method::from_bytes(method.as_bytes()).map_err(|_| format!("bad method {:?}", webhook.method))?;
+        if webhook.url.is_empty() {
+            return Err("no url".into());
+        }
+
+        let mut headers: Vec<_> = webhook
+            .headers
+            .iter()
+            .map(|(header, value)| (header.clone(), Secret::new(render_template(value, context, Escape::Nothing))))
+            .collect();
+        headers.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let escape = if is_json(webhook) { Escape::Json } else { Escape::Nothing };
+        Ok(WebhookRequest {
+            name: name.into(),
+            method,
+            url: Secret::new(render_template(&webhook.url, context, Escape::Url)),
+            headers,
+            body: webhook.body.as_ref().map(|body| render_template(body, context, escape)),
+            idempotent: webhook.idempotent,
+        })
+    }
+
+    /// Where the webhook goes, without the path or query, which often hold a token.
+    pub fn origin(&self) -> String {
+        redact_url(self.url.expose())
+    }
+
+    pub async fn execute(self) -> ExecutionResult {
+        info!("Executing webhook {:?}: {} {}", self.name, self.method, self.origin());
+        let retry = get_settings().retry.clone();
+        let method = Method::from_bytes(self.method.as_bytes()).unwrap_or(Method::POST);
+        let client = Client::new();
+        let what = format!("Webhook {:?}", self.name);
+
+        let attributes = vec![KeyValue::new("webhook.name", self.name.clone())];
+        let started = Instant::now();
+        let sent = retry::send_with_retry(&retry, &what, self.idempotent, || {
+            let mut request = client.request(method.clone(), self.url.expose());
+            for (header, value) in &self.headers {
+                request = request.header(header.as_str(), value.expose());
+            }
+            if let Some(body) = &self.body {
+                request = request.body(body.clone());
+            }
+            request.send()
+        });
+        let (result, attempts) = telemetry::in_span("execute_webhook", attributes, sent).await;
+        ExecutionResult::new(result, Sent::Webhook(self), started.elapsed(), attempts)
+    }
+}
+
+/// Just the scheme, host and port of `url`, for the logs and the journal.
+pub fn redact_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(url) if url.has_host() => url.origin().ascii_serialization(),
+        _ => "[REDACTED]".into(),
+    }
+}
+
+/// Whether `webhook`'s body is JSON: it says so in its `Content-Type`, or,
+/// without one, it looks like a JSON object or array.
+fn is_json(webhook: &WebhookSettings) -> bool {
+    let content_type = webhook
+        .headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value);
+    match content_type {
+        Some(content_type) => content_type.to_ascii_lowercase().contains("json"),
+        None => webhook
+            .body
+            .as_deref()
+            .is_some_and(|body| body.trim_start().starts_with('{') || body.trim_start().starts_with('[')),
+    }
+}
+
+/// How values are put into a template, so they can't break out of their place in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escape {
+    Nothing,
+    /// Strings are escaped to go between the template's own quotes
+    Json,
+    /// Everything is percent-encoded, so a `/`, `?` or `&` stays part of the value
+    Url,
+}
+
+/// Every byte of `value` but letters, digits and `-._~` as `%XX`.
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Replaces each `{{path.to.value}}` in `template` with that value from
+/// `context`. Strings go in as they are, other values as JSON, and anything
+/// missing as nothing at all, then escaped by `escape`, so a `"` in a ticker
+/// can't break a JSON body.
+pub fn render_template(template: &str, context: &Value, escape: Escape) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        rendered.push_str(&rest[..start]);
+        let path = rest[start + 2..end].trim();
+        match (path.split('.').try_fold(context, |value, key| value.get(key)), escape) {
+            (Some(Value::String(s)), Escape::Json) => {
+                let quoted = Value::String(s.clone()).to_string();
+                rendered.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            (Some(Value::String(s)), Escape::Url) => rendered.push_str(&percent_encode(s)),
+            (Some(Value::String(s)), _) => rendered.push_str(s),
+            (Some(Value::Null), _) | (None, _) => (),
+            (Some(value), Escape::Url) => rendered.push_str(&percent_encode(&value.to_string())),
+            (Some(value), _) => rendered.push_str(&value.to_string()),
+        }
+        rest = &rest[end + 2..];
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Everything templates can use: the signal's own fields, plus what we made of it.
+pub fn template_context(
+    signal: &IncomingSignal,
+    signal_id: &str,
+    strategy: &str,
+    requests: &[OutgoingRequest],
+) -> Value {
+    let mut context = serde_json::to_value(signal).unwrap_or_else(|_| json!({}));
+    let start = requests.iter().find(|request| ActionType::is_start(&request.action));
+    let side = start
+        .and_then(|request| BotType::from_bot_id(request.bot_id))
+        .map(|bot_type| format!("{:?}", bot_type).to_ascii_lowercase());
+
+    if let Value::Object(fields) = &mut context {
+        fields.insert("signal_id".into(), json!(signal_id));
+        fields.insert("strategy".into(), json!(strategy));
+        fields.insert("format".into(), json!(signal.format.to_string()));
+        fields.insert("received_at".into(), json!(Utc::now()));
+        fields.insert("side".into(), json!(side));
+        fields.insert("bot_id".into(), json!(start.map(|request| request.bot_id)));
+        fields.insert("order_size".into(), json!(start.and_then(|request| request.order.clone())));
+        fields.insert(
+            "stop_loss_percentage".into(),
+            json!(start.and_then(|request| request.stop_loss_percentage)),
+        );
+        fields.insert(
+            "take_profit_percentage".into(),
+            json!(start.and_then(|request| request.take_profit_percentage)),
+        );
+    }
+    context
+}
+
+/// The webhooks `strategy` sends its signals to, filled in from `context`.
+/// Ones that are missing from the config or don't make sense are left out.
+pub fn webhooks_for(settings: &Settings, strategy: &StrategySettings, context: &Value) -> Vec<WebhookRequest> {
+    strategy
+        .webhooks
+        .iter()
+        .filter_map(|name| {
+            let webhook = match settings.webhooks.get(name) {
+                Some(webhook) => webhook,
+                None => {
+                    warn!("No webhook called {:?}, skipping it", name);
+                    return None;
+                }
+            };
+            WebhookRequest::render(name, webhook, context)
+                .map_err(|why| warn!("Skipping webhook {:?}: {}", name, why))
+                .ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+    use crate::{incoming::parse_signal, settings::StrategySettings};
+
+    fn context() -> Value {
+        let signal = parse_signal(None, b"buy strategy=fancy ticker=BTCUSDT price=100 stop_loss=2%").unwrap();
+        let requests = signal.to_requests(&StrategySettings::default()).unwrap();
+        template_context(&signal, "abc", "fancy", &requests)
+    }
+
+    #[test]
+    fn it_fills_in_templates() {
+        let context = context();
+        assert_eq!(
+            render_template("{{ticker}} {{order.action}} at {{order.price}} ({{side}}, {{nope}})", &context, Escape::Nothing),
+            "BTCUSDT buy at 100.0 (long, )"
+        );
+        assert_eq!(
+            render_template(r#"{"sl": {{stop_loss_percentage}}, "id": "{{signal_id}}"} {{"#, &context, Escape::Json),
+            r#"{"sl": 2.0, "id": "abc"} {{"#
+        );
+    }
+
+    #[test]
+    fn it_escapes_strings_in_json_bodies() {
+        let signal = parse_signal(None, br#"{"action": "buy", "ticker": "BTC\"USDT\\"}"#).unwrap();
+        let context = template_context(&signal, "abc", "fancy", &[]);
+        let webhook = WebhookSettings {
+            url: "https://example.com/hook".into(),
+            body: Some(r#"{"content": "{{ticker}}"}"#.into()),
+            ..Default::default()
+        };
+
+        let body = WebhookRequest::render("mine", &webhook, &context).unwrap().body.unwrap();
+        assert_eq!(body, r#"{"content": "BTC\"USDT\\"}"#);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["content"], r#"BTC"USDT\"#);
+    }
+
+    #[test]
+    fn it_encodes_values_in_urls() {
+        let signal = parse_signal(None, br#"{"action": "buy", "ticker": "BTC/USDT?x=1&y 2", "contracts": 1.5}"#).unwrap();
+        let context = template_context(&signal, "abc", "fancy", &[]);
+        let webhook = WebhookSettings {
+            url: "https://example.com/{{ticker}}?size={{order.contracts}}&id={{signal_id}}".into(),
+            ..Default::default()
+        };
+
+        let request = WebhookRequest::render("mine", &webhook, &context).unwrap();
+        assert_eq!(request.url.expose(), "https://example.com/BTC%2FUSDT%3Fx%3D1%26y%202?size=1.5&id=abc");
+    }
+
+    #[test]
+    fn it_keeps_tokens_out_of_the_logs() {
+        let webhook = WebhookSettings {
+            url: "https://discord.com/api/webhooks/123/t0ken".into(),
+            headers: [("Authorization".to_string(), "Bearer s3cret".to_string())].iter().cloned().collect(),
+            ..Default::default()
+        };
+        let request = WebhookRequest::render("discord", &webhook, &context()).unwrap();
+
+        let logged = format!("{:?}", request);
+        assert!(!logged.contains("t0ken"));
+        assert!(!logged.contains("s3cret"));
+        assert_eq!(request.origin(), "https://discord.com");
+        assert_eq!(redact_url("not a url"), "[REDACTED]");
+    }
+
+    #[test]
+    fn it_skips_webhooks_it_cant_send() {
+        let mut settings = Settings::default();
+        settings.webhooks.insert("no url".into(), WebhookSettings::default());
+        let strategy = StrategySettings {
+            webhooks: vec!["no url".into(), "missing".into()],
+            ..Default::default()
+        };
+        assert!(webhooks_for(&settings, &strategy, &context()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_sends_the_filled_in_request() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method("PUT")
+                .path("/signals/fancy")
+                .header("x-signal-id", "abc")
+                .body(r#"{"content": "fancy: buy BTCUSDT"}"#);
+            then.status(204);
+        });
+
+        let webhook = WebhookSettings {
+            url: format!("{}/signals/{{{{strategy}}}}", server.base_url()),
+            method: "put".into(),
+            headers: [("x-signal-id".to_string(), "{{signal_id}}".to_string())].iter().cloned().collect(),
+            body: Some(r#"{"content": "{{strategy}}: {{order.action}} {{ticker}}"}"#.into()),
+            idempotent: false,
+        };
+
+        let result = WebhookRequest::render("mine", &webhook, &context()).unwrap().execute().await;
+        mock.assert();
+        assert!(result.is_success());
+    }
+
+    #[tokio::test]
+    async fn it_only_retries_idempotent_webhooks_after_server_errors() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/hook");
+            then.status(503);
+        });
+        let mut webhook = WebhookSettings {
+            url: format!("{}/hook", server.base_url()),
+            ..Default::default()
+        };
+
+        WebhookRequest::render("mine", &webhook, &context()).unwrap().execute().await;
+        mock.assert_hits(1);
+
+        webhook.idempotent = true;
+        WebhookRequest::render("mine", &webhook, &context()).unwrap().execute().await;
+        mock.assert_hits(1 + get_settings().retry.attempts.max(1) as usize);
+    }
+}
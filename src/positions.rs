@@ -0,0 +1,214 @@
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+use crate::{
+    outgoing::{OutgoingRequest, account::Account, deal_and_bot_types::{ActionType, BotType}},
+    settings::get_settings,
+    storage::{self, Storage},
+};
+
+/// Strategies' positions on one account.
+pub type AccountPositions = HashMap<String, Position>;
+
+/// Which deal we think a strategy has open.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Position {
+    #[default]
+    Flat,
+    Long,
+    Short,
+}
+
+impl Position {
+    /// Where a successful `action` on `bot_type`'s bot leaves us.
+    pub fn after(self, action: &ActionType, bot_type: BotType) -> Position {
+        use Position::*;
+        match (action, bot_type, self) {
+            (ActionType::StartDeal, BotType::Long, _) => Long,
+            (ActionType::StartDeal, BotType::Short, _) => Short,
+            (ActionType::CloseDeal, BotType::Long, Long) => Flat,
+            (ActionType::CloseDeal, BotType::Short, Short) => Flat,
+            _ => self,
+        }
+    }
+
+    /// True if this position means `bot_type`'s bot has a deal open.
+    pub fn holds(self, bot_type: BotType) -> bool {
+        matches!(
+            (self, bot_type),
+            (Position::Long, BotType::Long) | (Position::Short, BotType::Short)
+        )
+    }
+}
+
+/// Every strategy's position on every account, kept up to date as requests
+/// succeed and saved so it survives a restart.
+pub struct PositionBook {
+    path: Option<PathBuf>,
+    positions: Mutex<HashMap<String, AccountPositions>>,
+    /// Whether the positions came from, or have since been saved to, `path`
+    saved: AtomicBool,
+}
+
+impl PositionBook {
+    /// Loads the positions saved at `path`, or in `database` if they aren't
+    /// there, or starts everyone flat.
+    pub fn load(path: Option<PathBuf>, database: Option<&Storage>) -> Self {
+        let from_file = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(json) => serde_json::from_str(&json)
+                    .map_err(|e| error!("Can't read positions from {:?}: {}", path, e))
+                    .ok(),
+                Err(_) => None,
+            },
+            None => None,
+        };
+        let positions = from_file.or_else(|| {
+            let positions = database?
+                .load_positions()
+                .map_err(|e| error!("Can't read positions from the database: {}", e))
+                .ok()
+                .flatten()?;
+            info!("Read the positions back from the database");
+            Some(positions)
+        });
+
+        PositionBook {
+            path,
+            saved: AtomicBool::new(positions.is_some()),
+            positions: Mutex::new(positions.unwrap_or_default()),
+        }
+    }
+
+    /// False until there are positions saved, say on the very first run.
+    /// Until then, everyone being flat only means we don't know any better.
+    pub fn is_saved(&self) -> bool {
+        self.saved.load(Ordering::SeqCst)
+    }
+
+    pub fn all(&self) -> HashMap<String, AccountPositions> {
+        self.positions.lock().unwrap().clone()
+    }
+
+    /// Updates `strategy`'s position on `account` for a request 3commas accepted.
+    pub fn record(&self, account: &str, strategy: &str, request: &OutgoingRequest) {
+        let bot_type = Account::named(&get_settings(), account)
+            .and_then(|account| account.bot_type(request.bot_id));
+        let bot_type = match bot_type {
+            Some(bot_type) => bot_type,
+            None => return,
+        };
+
+        let mut all_positions = self.positions.lock().unwrap();
+        let positions = all_positions.entry(account.into()).or_default();
+        let before = positions.get(strategy).copied().unwrap_or_default();
+        let after = before.after(&request.action, bot_type);
+        if after == before {
+            return;
+        }
+
+        if before != Position::Flat && after != Position::Flat {
+            warn!(
+                "Strategy {:?} went from {:?} to {:?} on {:?} without closing first",
+                strategy, before, after, account
+            );
+        }
+        info!("Strategy {:?} is now {:?} on {:?}", strategy, after, account);
+        positions.insert(strategy.into(), after);
+        self.save(&all_positions);
+    }
+
+    /// True if any strategy thinks `account`'s `bot_type` bot has a deal open.
+    pub fn expects_open(&self, account: &str, bot_type: BotType) -> bool {
+        self.positions
+            .lock()
+            .unwrap()
+            .get(account)
+            .is_some_and(|positions| positions.values().any(|position| position.holds(bot_type)))
+    }
+
+    /// Marks every strategy holding `account`'s `bot_type` deal as flat, for
+    /// when the deal was closed without us.
+    pub fn close_all(&self, account: &str, bot_type: BotType) {
+        let mut all_positions = self.positions.lock().unwrap();
+        if let Some(positions) = all_positions.get_mut(account) {
+            for position in positions.values_mut().filter(|position| position.holds(bot_type)) {
+                *position = Position::Flat;
+            }
+        }
+        self.save(&all_positions);
+    }
+
+    fn save(&self, positions: &HashMap<String, AccountPositions>) {
+        let write = |path: &PathBuf| -> io::Result<()> {
+            fs::write(path, serde_json::to_string_pretty(positions)?)
+        };
+
+        if let Some(path) = &self.path {
+            match write(path) {
+                Ok(()) => self.saved.store(true, Ordering::SeqCst),
+                Err(e) => error!("Can't save positions to {:?}: {}", path, e),
+            }
+        }
+        storage::save_positions(positions);
+    }
+}
+
+lazy_static! {
+    pub static ref POSITIONS: PositionBook = {
+        let path = get_settings().positions_path.as_ref().map(PathBuf::from);
+        PositionBook::load(path, storage::STORAGE.as_ref())
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(action: ActionType, bot_type: BotType) -> OutgoingRequest {
+        OutgoingRequest::new((action, bot_type))
+    }
+
+    #[test]
+    fn it_follows_deals_opening_and_closing() {
+        use ActionType::*;
+        use Position::*;
+
+        assert_eq!(Flat.after(&StartDeal, BotType::Long), Long);
+        assert_eq!(Long.after(&CloseDeal, BotType::Long), Flat);
+        assert_eq!(Long.after(&CloseDeal, BotType::Short), Long);
+        assert_eq!(Short.after(&StopBot, BotType::Short), Short);
+    }
+
+    #[test]
+    fn it_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("positions.json");
+
+        let book = PositionBook::load(Some(path.clone()), None);
+        book.record("default", "fancy", &request(ActionType::StartDeal, BotType::Short));
+        book.record("default", "plain", &request(ActionType::StartDeal, BotType::Long));
+        book.record("default", "plain", &request(ActionType::CloseDeal, BotType::Long));
+
+        let book = PositionBook::load(Some(path), None);
+        assert_eq!(book.all()["default"]["fancy"], Position::Short);
+        assert_eq!(book.all()["default"]["plain"], Position::Flat);
+        assert!(book.expects_open("default", BotType::Short));
+        assert!(!book.expects_open("default", BotType::Long));
+        assert!(!book.expects_open("family", BotType::Short));
+
+        book.close_all("default", BotType::Short);
+        assert_eq!(book.all()["default"]["fancy"], Position::Flat);
+    }
+}
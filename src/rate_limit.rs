@@ -0,0 +1,204 @@
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+use crate::{incoming::SignalError, settings::get_settings, shutdown::IN_FLIGHT};
+
+/// Past this many buckets, the full ones are dropped. A full bucket is the same
+/// as no bucket, so nothing's lost. If that isn't enough, the ones used longest
+/// ago go too, down to half this.
+const MAX_BUCKETS: usize = 10_000;
+
+/// How full one bucket was, when we last looked.
+struct Bucket {
+    tokens: f64,
+    at: DateTime<Utc>,
+}
+
+/// Token buckets by key: each holds up to `per_minute` tokens, and fills back
+/// up at `per_minute` a minute. Every request takes one.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+lazy_static! {
+    /// Every address's, tenant's and everyone's signals a minute, by `ip <address>`,
+    /// `tenant <name>` and `all`
+    pub static ref LIMITS: RateLimiter = RateLimiter::default();
+}
+
+/// Signals being handled right now, up to when their requests start going out
+static HANDLING: AtomicUsize = AtomicUsize::new(0);
+
+impl RateLimiter {
+    /// Takes a token from `key`'s bucket, or says how long until there is one.
+    pub fn take(&self, key: &str, per_minute: u32, now: DateTime<Utc>) -> Result<(), Duration> {
+        if per_minute == 0 {
+            return Err(Duration::minutes(1));
+        }
+
+        let capacity = per_minute as f64;
+        let per_ms = capacity / 60_000.0;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            make_room(&mut buckets, now);
+        }
+        let bucket = buckets.entry(key.into()).or_insert(Bucket { tokens: capacity, at: now });
+
+        let elapsed_ms = (now - bucket.at).num_milliseconds().max(0) as f64;
+        bucket.tokens = (bucket.tokens + elapsed_ms * per_ms).min(capacity);
+        bucket.at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::milliseconds(((1.0 - bucket.tokens) / per_ms).ceil() as i64))
+        }
+    }
+
+    /// Takes a token from `key`'s bucket if there's a `per_minute` limit on it.
+    fn admit(&self, key: &str, per_minute: Option<u32>, now: DateTime<Utc>) -> Result<(), SignalError> {
+        match per_minute {
+            Some(per_minute) => self.take(key, per_minute, now).map_err(|wait| SignalError::RateLimited {
+                limit: format!("{} signals a minute", per_minute),
+                retry_after_secs: (wait.num_milliseconds() + 999) / 1000,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Drops the full buckets, then the ones used longest ago if there are still
+/// more than half of `MAX_BUCKETS`. Halving means a flood of new keys only pays
+/// for this once every so often.
+fn make_room(buckets: &mut HashMap<String, Bucket>, now: DateTime<Utc>) {
+    // Any bucket can fill up from empty in a minute
+    buckets.retain(|_, bucket| now - bucket.at < Duration::minutes(1));
+    if buckets.len() > MAX_BUCKETS / 2 {
+        let mut by_age: Vec<(DateTime<Utc>, String)> =
+            buckets.iter().map(|(key, bucket)| (bucket.at, key.clone())).collect();
+        by_age.sort_unstable();
+        for (_, key) in by_age.iter().take(buckets.len() - MAX_BUCKETS / 2) {
+            buckets.remove(key);
+        }
+    }
+}
+
+/// Counts a signal from `remote_ip`, for `tenant` if it's a tenant's, towards
+/// the limits on signals a minute: `limits.max_signals_per_minute_per_ip`,
+/// `limits.max_signals_per_minute`, and the tenant's `max_signals_per_minute`.
+pub fn admit(remote_ip: &str, tenant: Option<&str>, now: DateTime<Utc>) -> Result<(), SignalError> {
+    let (per_ip, overall, per_tenant) = {
+        let settings = get_settings();
+        let per_tenant = tenant
+            .and_then(|tenant| settings.tenants.get(tenant))
+            .and_then(|tenant| tenant.max_signals_per_minute);
+        (settings.limits.max_signals_per_minute_per_ip, settings.limits.max_signals_per_minute, per_tenant)
+    };
+
+    LIMITS.admit(&format!("ip {}", remote_ip), per_ip, now)?;
+    LIMITS.admit("all", overall, now)?;
+    match tenant {
+        Some(tenant) => LIMITS.admit(&format!("tenant {}", tenant), per_tenant, now),
+        None => Ok(()),
+    }
+}
+
+/// One signal's place among `limits.max_concurrent_signals`, given up when dropped.
+pub struct Handling(&'static AtomicUsize);
+
+impl Drop for Handling {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A place for one more signal, if the ones being handled and the request
+/// sequences still going out leave room for it under `max`.
+pub fn start_handling(max: Option<usize>) -> Result<Handling, SignalError> {
+    take_place(&HANDLING, IN_FLIGHT.len(), max)
+}
+
+fn take_place(handling: &'static AtomicUsize, running: usize, max: Option<usize>) -> Result<Handling, SignalError> {
+    let before = handling.fetch_add(1, Ordering::SeqCst);
+    let place = Handling(handling);
+    match max {
+        Some(max) if before + running >= max => Err(SignalError::RateLimited {
+            limit: format!("{} signals at once", max),
+            retry_after_secs: 1,
+        }),
+        _ => Ok(place),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_allows_bursts_then_refills() {
+        let limiter = RateLimiter::default();
+        let now: DateTime<Utc> = "2021-06-02T12:00:00Z".parse().unwrap();
+
+        assert!(limiter.take("alice", 2, now).is_ok());
+        assert!(limiter.take("alice", 2, now).is_ok());
+        assert_eq!(limiter.take("alice", 2, now), Err(Duration::seconds(30)));
+        assert!(limiter.take("bob", 2, now).is_ok());
+
+        assert!(limiter.take("alice", 2, now + Duration::seconds(30)).is_ok());
+        assert!(limiter.take("alice", 2, now + Duration::seconds(40)).is_err());
+        assert!(limiter.take("alice", 0, now + Duration::hours(1)).is_err());
+    }
+
+    #[test]
+    fn it_forgets_full_buckets() {
+        let limiter = RateLimiter::default();
+        let now: DateTime<Utc> = "2021-06-02T12:00:00Z".parse().unwrap();
+        for n in 0..MAX_BUCKETS {
+            limiter.take(&format!("ip {}", n), 1, now).unwrap();
+        }
+
+        let later = now + Duration::minutes(1);
+        assert!(limiter.take("ip 0", 1, later).is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_forgets_the_oldest_buckets_when_none_are_full() {
+        let limiter = RateLimiter::default();
+        let now: DateTime<Utc> = "2021-06-02T12:00:00Z".parse().unwrap();
+        for n in 0..MAX_BUCKETS {
+            limiter.take(&format!("ip {}", n), 1, now + Duration::milliseconds(n as i64)).unwrap();
+        }
+
+        let later = now + Duration::seconds(30);
+        assert!(limiter.take("ip new", 1, later).is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_BUCKETS / 2 + 1);
+        assert!(!buckets.contains_key("ip 0"));
+        assert!(buckets.contains_key(&format!("ip {}", MAX_BUCKETS - 1)));
+    }
+
+    #[test]
+    fn it_caps_signals_at_once() {
+        static HANDLING: AtomicUsize = AtomicUsize::new(0);
+        let first = take_place(&HANDLING, 0, Some(2)).unwrap();
+        assert!(take_place(&HANDLING, 1, Some(2)).is_err());
+        let second = take_place(&HANDLING, 0, Some(2)).unwrap();
+        assert!(matches!(
+            take_place(&HANDLING, 0, Some(2)),
+            Err(SignalError::RateLimited { retry_after_secs: 1, .. })
+        ));
+
+        drop((first, second));
+        assert_eq!(HANDLING.load(Ordering::SeqCst), 0);
+        assert!(take_place(&HANDLING, 0, None).is_ok());
+    }
+}
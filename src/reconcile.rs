@@ -0,0 +1,508 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use log::{error, info};
+use reqwest::{Client, Method};
+use serde_json::Value;
+use sha2::Sha256;
+use tokio::time::{interval, Duration};
+use crate::{
+    alert,
+    journal::{self, JournalEvent},
+    outgoing::{
+        OutgoingRequest,
+        account::Account,
+        deal_and_bot_types::{ActionType, BotType},
+        sequence::{execute_sequence, Sequence},
+    },
+    positions::{PositionBook, POSITIONS},
+    risk::{RiskGuard, RISK},
+    settings::{get_settings, ReconcileSettings, Settings, TENANT_SEPARATOR},
+    shutdown::{InFlight, IN_FLIGHT},
+};
+
+/// The strategy name reconciliation files its own requests under.
+const RECONCILE_STRATEGY: &str = "reconcile";
+
+/// Just enough of the 3commas API to see which bots have deals open, how
+/// they ended and how much an account has to trade with.
+pub struct ThreeCommasApi {
+    server: String,
+    settings: ReconcileSettings,
+}
+
+impl ThreeCommasApi {
+    pub fn new(settings: &ReconcileSettings) -> Self {
+        ThreeCommasApi {
+            server: settings.api_server.trim_end_matches('/').into(),
+            settings: settings.clone(),
+        }
+    }
+
+    /// 3commas wants every request signed with HMAC-SHA256 of its path and query.
+    fn signature(&self, path_and_query: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.settings.api_secret.expose().as_bytes())
+            .expect("HMAC takes keys of any length");
+        mac.update(path_and_query.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    async fn send(&self, method: Method, path_and_query: &str) -> Result<Value, String> {
+        Client::new()
+            .request(method, format!("{}{}", self.server, path_and_query))
+            .header("APIKEY", self.settings.api_key.expose())
+            .header("Signature", self.signature(path_and_query))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn deals(&self, bot_id: u64, scope: &str) -> Result<Vec<Value>, String> {
+        let path_and_query = format!("/public/api/ver1/deals?bot_id={}&scope={}", bot_id, scope);
+        match self.send(Method::GET, &path_and_query).await? {
+            Value::Array(deals) => Ok(deals),
+            deals => Err(format!("expected a list of deals, got {}", deals)),
+        }
+    }
+
+    /// True if the bot has at least one active deal.
+    pub async fn has_active_deal(&self, bot_id: u64) -> Result<bool, String> {
+        Ok(!self.deals(bot_id, "active").await?.is_empty())
+    }
+
+    /// How much of `currency` the 3commas account `account_id` holds.
+    pub async fn balance(&self, account_id: u64, currency: &str) -> Result<f64, String> {
+        let path = format!("/public/api/ver1/accounts/{}/account_table_data", account_id);
+        let rows = self.send(Method::POST, &path).await?;
+        let row = rows
+            .as_array()
+            .ok_or_else(|| format!("expected a list of balances, got {}", rows))?
+            .iter()
+            .find(|row| row["currency_code"].as_str().is_some_and(|code| code.eq_ignore_ascii_case(currency)))
+            .ok_or_else(|| format!("account {} has no {}", account_id, currency))?;
+        let position = &row["position"];
+        position
+            .as_str()
+            .and_then(|position| position.parse().ok())
+            .or_else(|| position.as_f64())
+            .ok_or_else(|| format!("expected a {} balance, got {}", currency, position))
+    }
+
+    /// The bot's most recently finished deals.
+    pub async fn finished_deals(&self, bot_id: u64) -> Result<Vec<FinishedDeal>, String> {
+        Ok(self.deals(bot_id, "finished").await?.iter().filter_map(FinishedDeal::from_json).collect())
+    }
+}
+
+/// A deal 3commas has closed, and what it made or lost in the quote currency.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FinishedDeal {
+    pub id: u64,
+    pub closed_at: DateTime<Utc>,
+    pub profit: f64,
+}
+
+impl FinishedDeal {
+    fn from_json(deal: &Value) -> Option<Self> {
+        // 3commas sends amounts as strings
+        let profit = &deal["final_profit"];
+        Some(FinishedDeal {
+            id: deal["id"].as_u64()?,
+            closed_at: deal["closed_at"].as_str()?.parse().ok()?,
+            profit: profit.as_str().and_then(|profit| profit.parse().ok()).or_else(|| profit.as_f64())?,
+        })
+    }
+}
+
+/// Counts the results of the bot's deals that closed since we last looked
+/// towards the daily loss of the strategies that opened them.
+async fn count_results(api: &ThreeCommasApi, risk: &RiskGuard, bot_type: BotType, bot_id: u64) {
+    let deals = match api.finished_deals(bot_id).await {
+        Ok(deals) => deals,
+        Err(e) => {
+            error!("Can't get the {:?} bot's finished deals: {}", bot_type, e);
+            return;
+        }
+    };
+    let now = Utc::now();
+    for deal in deals {
+        if let Some((strategy, total)) = risk.record_deal(bot_id, deal.id, deal.profit, deal.closed_at, now) {
+            info!("Deal {} for {:?} closed with {}, {} today", deal.id, strategy, deal.profit, total);
+        }
+    }
+}
+
+/// How our idea of a bot's deal differs from 3commas'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Divergence {
+    /// 3commas has a deal open that no strategy knows about
+    Orphaned,
+    /// We think there's a deal open but 3commas has closed it, like when a
+    /// stop-loss or take-profit hits
+    Missing,
+}
+
+fn compare(expected_open: bool, actually_open: bool) -> Option<Divergence> {
+    match (expected_open, actually_open) {
+        (false, true) => Some(Divergence::Orphaned),
+        (true, false) => Some(Divergence::Missing),
+        _ => None,
+    }
+}
+
+/// An account to reconcile, and an API key that can see its bots.
+pub struct ReconciledAccount {
+    pub account: Account,
+    pub api: ThreeCommasApi,
+}
+
+/// Every account in `settings`, tenants' included, with the API key to check
+/// it with: its own, or for the top-level ones, `reconcile`'s. Tenants'
+/// accounts without a key of their own are left out, since `reconcile`'s
+/// belongs to someone else and can't see their bots.
+pub fn accounts(settings: &Settings, reconcile: &ReconcileSettings) -> Vec<ReconciledAccount> {
+    Account::all(settings)
+        .into_iter()
+        .filter_map(|account| {
+            let account_settings = match account.name.split_once(TENANT_SEPARATOR) {
+                Some((tenant, name)) => settings.tenants.get(tenant)?.accounts.get(name),
+                None => settings.accounts.get(&account.name),
+            };
+            let api = match account_settings {
+                Some(own) if !own.api_key.is_empty() => ThreeCommasApi::new(&ReconcileSettings {
+                    api_key: own.api_key.clone(),
+                    api_secret: own.api_secret.clone(),
+                    ..reconcile.clone()
+                }),
+                _ if journal::tenant_of(&account.name).is_some() => return None,
+                _ => ThreeCommasApi::new(reconcile),
+            };
+            Some(ReconciledAccount { account, api })
+        })
+        .collect()
+}
+
+/// Checks the bots in `bots` on each of `accounts` against `book`. Deals that closed without us are
+/// marked closed; orphaned deals are closed if `auto_close` is on. Either way,
+/// someone gets told. Returns what didn't match, by account. Finished deals on
+/// bots a strategy has opened deals on are counted towards its daily loss in `risk`.
+///
+/// Bots that `in_flight` sequences are still sending to are left for next
+/// time, since 3commas and `book` can disagree until the sequence is done.
+/// Nothing is auto-closed until `book` has been saved at least once: on a
+/// first run, every deal would look orphaned.
+pub async fn reconcile_once(
+    accounts: &[ReconciledAccount],
+    book: &PositionBook,
+    risk: &RiskGuard,
+    in_flight: &InFlight,
+    bots: &[BotType],
+    server: &str,
+) -> Vec<(String, BotType, Divergence)> {
+    let mut divergences = vec![];
+    let owned_bots = risk.owned_bots();
+
+    for ReconciledAccount { account, api } in accounts {
+        for &bot_type in bots {
+            let bot_id = account.bot_id(bot_type);
+            if bot_id == 0 {
+                continue;
+            }
+            if let Some(divergence) = reconcile_bot(api, account, bot_type, book, in_flight, server).await {
+                divergences.push((account.name.clone(), bot_type, divergence));
+            }
+            if owned_bots.contains(&bot_id) {
+                count_results(api, risk, bot_type, bot_id).await;
+            }
+        }
+    }
+
+    divergences
+}
+
+async fn reconcile_bot(
+    api: &ThreeCommasApi,
+    account: &Account,
+    bot_type: BotType,
+    book: &PositionBook,
+    in_flight: &InFlight,
+    server: &str,
+) -> Option<Divergence> {
+    let bot_id = account.bot_id(bot_type);
+    if in_flight.is_busy_with(&account.name, bot_id) {
+        info!("Not reconciling {:?}'s {:?} bot while requests to it are running", account.name, bot_type);
+        return None;
+    }
+    let actually_open = match api.has_active_deal(bot_id).await {
+        Ok(open) => open,
+        Err(e) => {
+            error!("Can't check {:?}'s {:?} bot's deals: {}", account.name, bot_type, e);
+            return None;
+        }
+    };
+    let expected_open = book.expects_open(&account.name, bot_type);
+    let divergence = compare(expected_open, actually_open)?;
+
+    let auto_close = divergence == Divergence::Orphaned && api.settings.auto_close && book.is_saved();
+    let signal_id = journal::new_signal_id(journal::tenant_of(&account.name));
+    journal::record(&signal_id, JournalEvent::PositionDiverged {
+        account: account.name.clone(),
+        bot_id,
+        expected_open,
+        actually_open,
+        auto_closed: auto_close,
+    });
+
+    let bot = format!("{:?}'s {:?} bot", account.name, bot_type);
+    match divergence {
+        Divergence::Missing => {
+            alert::raise(format!("The deal on {} was closed outside tradeproxy, marking it closed", bot));
+            book.close_all(&account.name, bot_type);
+        }
+        Divergence::Orphaned if auto_close => {
+            alert::raise(format!("{} has a deal we didn't open, closing it", bot));
+            let close = account.adapt(&OutgoingRequest::new((ActionType::CloseDeal, bot_type)));
+            let sequence = Sequence::new(&signal_id, RECONCILE_STRATEGY, vec![close]).on_account(&account.name);
+            execute_sequence(sequence, server.into()).await;
+        }
+        Divergence::Orphaned if api.settings.auto_close => {
+            alert::raise(format!(
+                "{} has a deal we didn't open. Not closing it, since we have no saved positions yet",
+                bot
+            ));
+        }
+        Divergence::Orphaned => {
+            alert::raise(format!("{} has a deal we didn't open", bot));
+        }
+    }
+    Some(divergence)
+}
+
+/// Reconciles `bots` on every account now, then every `interval_secs`.
+pub async fn reconcile_periodically(settings: ReconcileSettings, bots: Vec<BotType>, server: String) {
+    if settings.interval_secs == 0 {
+        let accounts = accounts(&get_settings(), &settings);
+        reconcile_once(&accounts, &POSITIONS, &RISK, &IN_FLIGHT, &bots, &server).await;
+        return;
+    }
+
+    info!("Reconciling positions with 3commas every {}s", settings.interval_secs);
+    let mut ticks = interval(Duration::from_secs(settings.interval_secs));
+    loop {
+        ticks.tick().await;
+        let accounts = accounts(&get_settings(), &settings);
+        reconcile_once(&accounts, &POSITIONS, &RISK, &IN_FLIGHT, &bots, &server).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+    use crate::settings::{AccountSettings, DEFAULT_ACCOUNT};
+
+    fn api(server: &MockServer) -> ThreeCommasApi {
+        ThreeCommasApi::new(&ReconcileSettings {
+            api_server: server.base_url(),
+            api_key: "key".into(),
+            api_secret: "secret".into(),
+            ..Default::default()
+        })
+    }
+
+    fn default_account(api: ThreeCommasApi) -> Vec<ReconciledAccount> {
+        let account = Account::named(&get_settings(), DEFAULT_ACCOUNT).unwrap();
+        vec![ReconciledAccount { account, api }]
+    }
+
+    #[test]
+    fn it_signs_the_path_and_query() {
+        let api = ThreeCommasApi::new(&ReconcileSettings {
+            api_secret: "secret".into(),
+            ..Default::default()
+        });
+        assert_eq!(
+            api.signature("/public/api/ver1/deals?bot_id=1&scope=active"),
+            "6ce5a8f679ddb5971932a6b59469b011b7c4f1fd5c6171ea5609145fbd65ec31"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_finds_orphaned_and_missing_deals() {
+        let server = MockServer::start();
+        // Long bot is 1234567, short is 7654321
+        let long = server.mock(|when, then| {
+            when.method("GET")
+                .path("/public/api/ver1/deals")
+                .query_param("bot_id", "1234567")
+                .header("APIKEY", "key")
+                .header_exists("Signature");
+            then.status(200).body(r#"[{"id": 1, "bot_id": 1234567}]"#);
+        });
+        server.mock(|when, then| {
+            when.method("GET").path("/public/api/ver1/deals").query_param("bot_id", "7654321");
+            then.status(200).body("[]");
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let book = PositionBook::load(Some(dir.path().join("positions.json")), None);
+        book.record(DEFAULT_ACCOUNT, "fancy", &OutgoingRequest::new((ActionType::StartDeal, BotType::Short)));
+
+        let in_flight = InFlight::new();
+        let divergences = reconcile_once(
+            &default_account(api(&server)),
+            &book,
+            &RiskGuard::new(),
+            &in_flight,
+            &[BotType::Long, BotType::Short],
+            "",
+        )
+        .await;
+
+        long.assert();
+        assert_eq!(
+            divergences,
+            [
+                ("default".to_string(), BotType::Long, Divergence::Orphaned),
+                ("default".to_string(), BotType::Short, Divergence::Missing),
+            ]
+        );
+        assert!(!book.expects_open(DEFAULT_ACCOUNT, BotType::Short));
+    }
+
+    #[tokio::test]
+    async fn it_only_auto_closes_with_saved_positions_and_nothing_running() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method("GET").path("/public/api/ver1/deals");
+            then.status(200).body(r#"[{"id": 1, "bot_id": 1234567}]"#);
+        });
+        let close = server.mock(|when, then| {
+            when.method("POST");
+            then.status(200);
+        });
+        let accounts = default_account(ThreeCommasApi::new(&ReconcileSettings {
+            api_server: server.base_url(),
+            auto_close: true,
+            ..Default::default()
+        }));
+
+        let dir = tempfile::tempdir().unwrap();
+        let book = PositionBook::load(Some(dir.path().join("positions.json")), None);
+        let risk = RiskGuard::new();
+        let in_flight = InFlight::new();
+        let divergences = reconcile_once(&accounts, &book, &risk, &in_flight, &[BotType::Long], &server.base_url()).await;
+        assert_eq!(divergences, [("default".to_string(), BotType::Long, Divergence::Orphaned)]);
+        close.assert_hits(0);
+
+        book.record(DEFAULT_ACCOUNT, "fancy", &OutgoingRequest::new((ActionType::StartDeal, BotType::Short)));
+        in_flight.begin(&Sequence::new("abc", "fancy", vec![OutgoingRequest::new((ActionType::StartDeal, BotType::Long))]));
+        assert!(reconcile_once(&accounts, &book, &risk, &in_flight, &[BotType::Long], &server.base_url()).await.is_empty());
+        close.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn it_checks_every_account_with_its_own_key() {
+        let server = MockServer::start();
+        let family = server.mock(|when, then| {
+            when.method("GET")
+                .path("/public/api/ver1/deals")
+                .query_param("bot_id", "111")
+                .header("APIKEY", "family-key");
+            then.status(200).body(r#"[{"id": 1, "bot_id": 111}]"#);
+        });
+        let alice = server.mock(|when, then| {
+            when.method("GET")
+                .path("/public/api/ver1/deals")
+                .query_param("bot_id", "333")
+                .header("APIKEY", "alice-key");
+            then.status(200).body(r#"[{"id": 2, "bot_id": 333}]"#);
+        });
+        let reconcile = ReconcileSettings {
+            api_server: server.base_url(),
+            api_key: "key".into(),
+            api_secret: "secret".into(),
+            ..Default::default()
+        };
+
+        let mut settings = Settings::default();
+        settings.accounts.insert("family".into(), AccountSettings {
+            long_bot_id: 111,
+            api_key: "family-key".into(),
+            ..Default::default()
+        });
+        let mut alice_settings = crate::settings::TenantSettings::default();
+        alice_settings.accounts.insert("main".into(), AccountSettings {
+            long_bot_id: 333,
+            api_key: "alice-key".into(),
+            ..Default::default()
+        });
+        let mut bob_settings = crate::settings::TenantSettings::default();
+        bob_settings.accounts.insert("main".into(), AccountSettings { long_bot_id: 444, ..Default::default() });
+        settings.tenants.insert("alice".into(), alice_settings);
+        settings.tenants.insert("bob".into(), bob_settings);
+
+        // Bob's account has no key of its own, and ours can't see it
+        let accounts = accounts(&settings, &reconcile);
+        let names: Vec<_> = accounts.iter().map(|reconciled| reconciled.account.name.as_str()).collect();
+        assert_eq!(names, ["default", "family", "alice:main"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let book = PositionBook::load(Some(dir.path().join("positions.json")), None);
+        let divergences =
+            reconcile_once(&accounts, &book, &RiskGuard::new(), &InFlight::new(), &[BotType::Long], "").await;
+
+        family.assert();
+        alice.assert();
+        assert_eq!(
+            divergences,
+            [
+                ("family".to_string(), BotType::Long, Divergence::Orphaned),
+                ("alice:main".to_string(), BotType::Long, Divergence::Orphaned),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_counts_finished_deals_towards_the_daily_loss() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method("GET").path("/public/api/ver1/deals").query_param("scope", "active");
+            then.status(200).body("[]");
+        });
+        let now = Utc::now();
+        let finished = server.mock(|when, then| {
+            when.method("GET")
+                .path("/public/api/ver1/deals")
+                .query_param("bot_id", "1234567")
+                .query_param("scope", "finished");
+            then.status(200).body(serde_json::json!([
+                {"id": 2, "closed_at": now.to_rfc3339(), "final_profit": "-12.5"},
+                {"id": 1, "closed_at": (now - chrono::Duration::days(2)).to_rfc3339(), "final_profit": "-40.0"},
+            ]).to_string());
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let book = PositionBook::load(Some(dir.path().join("positions.json")), None);
+        let risk = RiskGuard::new();
+        let in_flight = InFlight::new();
+        let bots = [BotType::Long, BotType::Short];
+
+        // Nobody has opened a deal on either bot, so there's nothing to count
+        reconcile_once(&default_account(api(&server)), &book, &risk, &in_flight, &bots, "").await;
+        finished.assert_hits(0);
+
+        let limits = crate::settings::RiskSettings { max_daily_loss: Some(20.0), ..Default::default() };
+        let start = vec![OutgoingRequest::new((ActionType::StartDeal, BotType::Long))];
+        risk.admit("fancy", &limits, &start, now - chrono::Duration::hours(1)).unwrap();
+        reconcile_once(&default_account(api(&server)), &book, &risk, &in_flight, &bots, "").await;
+        reconcile_once(&default_account(api(&server)), &book, &risk, &in_flight, &bots, "").await;
+        finished.assert_hits(2);
+
+        // Only the deal closed today counts, and only once
+        assert_eq!(risk.record_pnl("fancy", -7.5, now), -20.0);
+    }
+}
@@ -0,0 +1,450 @@
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+};
+use crate::{
+    dedup::Dedup,
+    incoming::{parse_signal, validation, IncomingSignal, SignalError},
+    journal::{JournalEntry, JournalEvent, PlannedRequest},
+    outgoing::{OutgoingRequest, deal_and_bot_types::{ActionType, BotType}},
+    risk::RiskGuard,
+    schedule,
+    settings::{OnViolation, Settings},
+};
+
+/// A webhook body we had before, and when it arrived.
+pub struct Recorded {
+    pub at: DateTime<Utc>,
+    pub signal: Result<IncomingSignal, SignalError>,
+}
+
+/// Every `signal_received` in a journal.
+pub fn read_journal(reader: impl Read) -> Result<Vec<Recorded>, String> {
+    let mut recorded = vec![];
+    for (n, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: JournalEntry = serde_json::from_str(&line).map_err(|e| format!("Line {}: {}", n + 1, e))?;
+        if let JournalEvent::SignalReceived { format, signal, dry_run, .. } = entry.event {
+            // Routing it applies its overrides again, but older journals have
+            // them applied already, and don't always say it was a dry run
+            let signal = serde_json::from_value::<IncomingSignal>(signal)
+                .map(|mut signal| {
+                    signal.format = format;
+                    signal.dry_run = dry_run;
+                    signal
+                })
+                .map_err(|e| SignalError::Unparseable(e.to_string()));
+            recorded.push(Recorded { at: entry.at, signal });
+        }
+    }
+    Ok(recorded)
+}
+
+/// A TradingView alert log export: the alert message is the webhook body.
+pub fn read_alert_log(reader: impl Read) -> Result<Vec<Recorded>, String> {
+    let mut csv = csv::Reader::from_reader(reader);
+    let headers = csv.headers().map_err(|e| e.to_string())?.clone();
+    let column = |names: &[&str]| {
+        headers
+            .iter()
+            .position(|header| names.iter().any(|name| header.trim().eq_ignore_ascii_case(name)))
+            .ok_or_else(|| format!("No {} column", names.join(" or ")))
+    };
+    let (time, body) = (column(&["Time"])?, column(&["Description", "Message"])?);
+
+    let mut recorded = vec![];
+    for (n, record) in csv.records().enumerate() {
+        let record = record.map_err(|e| e.to_string())?;
+        let field = |i: usize| record.get(i).unwrap_or_default();
+        let at = parse_time(field(time)).ok_or_else(|| format!("Row {}: bad time {:?}", n + 1, field(time)))?;
+        recorded.push(Recorded { at, signal: parse_signal(None, field(body).as_bytes()) });
+    }
+    recorded.sort_by_key(|recorded| recorded.at);
+    Ok(recorded)
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    let time = time.trim();
+    time.parse().ok().or_else(|| {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S")
+            .ok()
+            .map(|time| DateTime::from_utc(time, Utc))
+    })
+}
+
+/// Reads `path` as an alert log if it's a `.csv`, and as a journal otherwise.
+pub fn read(path: &Path) -> Result<Vec<Recorded>, String> {
+    let file = File::open(path).map_err(|e| format!("Can't open {:?}: {}", path, e))?;
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => read_alert_log(file),
+        _ => read_journal(file),
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Executed,
+    Rejected,
+    /// Planned, but its `alert_message` said not to send anything
+    DryRun,
+}
+
+/// What became of one recorded signal.
+#[derive(Serialize, Debug)]
+pub struct Step {
+    /// When it came in
+    pub at: DateTime<Utc>,
+    /// When its requests went out, after any delay or risk deferral
+    pub executed_at: Option<DateTime<Utc>>,
+    pub strategy: String,
+    pub action: Option<String>,
+    pub price: Option<f64>,
+    pub outcome: Outcome,
+    pub reason: Option<String>,
+    pub requests: Vec<PlannedRequest>,
+    /// Deals these requests closed
+    pub closed: Vec<ClosedDeal>,
+}
+
+/// A deal the paper account opened and closed.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ClosedDeal {
+    pub bot: String,
+    pub opened_at: DateTime<Utc>,
+    pub entry_price: Option<f64>,
+    pub exit_price: Option<f64>,
+    /// Unknown unless both signals had a price
+    pub pnl_pct: Option<f64>,
+}
+
+/// How each strategy did over the replay.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct StrategySummary {
+    pub signals: usize,
+    pub executed: usize,
+    pub rejected: usize,
+    pub deals: usize,
+    pub wins: usize,
+    pub pnl_pct: f64,
+    /// Deals still open at the end
+    pub open: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub steps: Vec<Step>,
+    pub strategies: BTreeMap<String, StrategySummary>,
+}
+
+struct OpenDeal {
+    at: DateTime<Utc>,
+    price: Option<f64>,
+}
+
+/// Stands in for 3commas: every request works, and deals open and close at the
+/// signal's price. Stop-losses and take-profits never trigger, since there's
+/// no price feed to trigger them.
+#[derive(Default)]
+struct PaperAccount {
+    open: HashMap<(String, &'static str), OpenDeal>,
+}
+
+impl PaperAccount {
+    fn execute(&mut self, strategy: &str, request: &OutgoingRequest, at: DateTime<Utc>, price: Option<f64>) -> Option<ClosedDeal> {
+        let bot = match BotType::from_bot_id(request.bot_id)? {
+            BotType::Long => "long",
+            BotType::Short => "short",
+        };
+        let key = (strategy.to_string(), bot);
+        match request.action {
+            ActionType::StartDeal => {
+                self.open.entry(key).or_insert(OpenDeal { at, price });
+                None
+            }
+            ActionType::CloseDeal => {
+                let deal = self.open.remove(&key)?;
+                let pnl_pct = match (deal.price, price) {
+                    (Some(entry), Some(exit)) if entry > 0.0 => {
+                        let change = (exit - entry) / entry * 100.0;
+                        Some(if bot == "long" { change } else { -change })
+                    }
+                    _ => None,
+                };
+                Some(ClosedDeal {
+                    bot: bot.into(),
+                    opened_at: deal.at,
+                    entry_price: deal.price,
+                    exit_price: price,
+                    pnl_pct,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn open_deals(&self, strategy: &str) -> usize {
+        self.open.keys().filter(|(name, _)| name == strategy).count()
+    }
+}
+
+/// Runs recorded signals through parsing, routing, the sanity checks, the risk
+/// limits and planning as if they were arriving again, with `settings`, and
+/// sends whatever comes out to a paper account. Reference price checks are
+/// skipped, since the prices they'd fetch are long gone.
+pub async fn replay(settings: &Settings, recorded: Vec<Recorded>) -> Report {
+    let risk = RiskGuard::new();
+    let dedup = Dedup::default();
+    let mut steps = vec![];
+    let mut planned: Vec<(usize, DateTime<Utc>, Vec<OutgoingRequest>)> = vec![];
+
+    for (n, Recorded { at, signal }) in recorded.into_iter().enumerate() {
+        let mut step = Step {
+            at,
+            executed_at: None,
+            strategy: "?".into(),
+            action: None,
+            price: None,
+            outcome: Outcome::Rejected,
+            reason: None,
+            requests: vec![],
+            closed: vec![],
+        };
+        if let Ok(signal) = &signal {
+            step.strategy = signal.strategy_name().into();
+            step.action = serde_json::to_value(signal.order.action).ok().and_then(|action| action.as_str().map(String::from));
+            step.price = signal.order.price;
+        }
+
+        match plan(settings, &risk, &dedup, &n.to_string(), signal, at).await {
+            Ok(None) => step.outcome = Outcome::DryRun,
+            Ok(Some((executed_at, requests))) => {
+                step.outcome = Outcome::Executed;
+                step.executed_at = Some(executed_at);
+                step.requests = requests.iter().map(PlannedRequest::from).collect();
+                planned.push((n, executed_at, requests));
+            }
+            Err(e) => step.reason = Some(e.to_string()),
+        }
+        steps.push(step);
+    }
+
+    // Delays and deferrals can put requests out of order
+    planned.sort_by_key(|(_, executed_at, _)| *executed_at);
+    let mut paper = PaperAccount::default();
+    for (n, executed_at, requests) in planned {
+        let step = &mut steps[n];
+        for request in &requests {
+            step.closed.extend(paper.execute(&step.strategy, request, executed_at, step.price));
+        }
+    }
+
+    let mut strategies: BTreeMap<String, StrategySummary> = BTreeMap::new();
+    for step in &steps {
+        let summary = strategies.entry(step.strategy.clone()).or_default();
+        summary.signals += 1;
+        match step.outcome {
+            Outcome::Executed => summary.executed += 1,
+            Outcome::Rejected => summary.rejected += 1,
+            Outcome::DryRun => (),
+        }
+        for deal in &step.closed {
+            summary.deals += 1;
+            if let Some(pnl_pct) = deal.pnl_pct {
+                summary.pnl_pct += pnl_pct;
+                summary.wins += (pnl_pct > 0.0) as usize;
+            }
+        }
+    }
+    for (strategy, summary) in strategies.iter_mut() {
+        summary.open = paper.open_deals(strategy);
+    }
+
+    Report { steps, strategies }
+}
+
+/// What `act_on_signal` would do with the signal, minus the side effects: when
+/// its requests would go out, and what they'd be. Nothing, for a dry run.
+async fn plan(
+    settings: &Settings,
+    risk: &RiskGuard,
+    dedup: &Dedup,
+    signal_id: &str,
+    signal: Result<IncomingSignal, SignalError>,
+    at: DateTime<Utc>,
+) -> Result<Option<(DateTime<Utc>, Vec<OutgoingRequest>)>, SignalError> {
+    let mut signal = signal?;
+    let strategy = signal.route(settings)?;
+    let strategy_name = signal.strategy_name();
+    if strategy.paused {
+        return Err(SignalError::Paused(strategy_name.into()));
+    }
+    let reservation = match strategy.dedup_window_secs {
+        Some(window_secs) => Some(dedup.reserve(signal_id, &signal, Duration::seconds(window_secs as i64), at)?),
+        None => None,
+    };
+
+    let mut sanity = strategy.sanity.clone();
+    sanity.reference_price = None;
+    validation::check(&signal, &sanity, at).await?;
+    let requests = signal.to_requests(&strategy)?;
+    if signal.dry_run {
+        return Ok(None);
+    }
+
+    let run_at = schedule::run_at(&signal, &strategy, at)?;
+    let limits = &strategy.risk;
+    let give_up_at = at + Duration::seconds(limits.max_defer_secs as i64);
+    let mut admitted_at = at;
+    while let Err(violation) = risk.admit(strategy_name, limits, &requests, admitted_at) {
+        match violation.retry_after {
+            Some(wait) if limits.on_violation == OnViolation::Defer && admitted_at + wait <= give_up_at => {
+                admitted_at = admitted_at + wait;
+            }
+            _ => return Err(SignalError::RiskLimit(violation)),
+        }
+    }
+
+    if let Some(reservation) = reservation {
+        reservation.commit();
+    }
+    let executed_at = run_at.map_or(admitted_at, |run_at| run_at.max(admitted_at));
+    Ok(Some((executed_at, requests)))
+}
+
+/// One line per signal, then a line per strategy.
+pub fn write_text(report: &Report, out: &mut impl io::Write) -> io::Result<()> {
+    for step in &report.steps {
+        let what = match step.outcome {
+            Outcome::Executed => step
+                .requests
+                .iter()
+                .map(|request| format!("{} {}", request.action, request.bot_id))
+                .collect::<Vec<_>>()
+                .join(", "),
+            Outcome::Rejected => format!("rejected: {}", step.reason.as_deref().unwrap_or_default()),
+            Outcome::DryRun => "dry run".into(),
+        };
+        let delayed = match step.executed_at {
+            Some(executed_at) if executed_at > step.at => format!(" (at {})", executed_at.format("%Y-%m-%d %H:%M:%S")),
+            _ => String::new(),
+        };
+        writeln!(
+            out,
+            "{}  {:<16} {:<11} {}{}",
+            step.at.format("%Y-%m-%d %H:%M:%S"),
+            step.strategy,
+            step.action.as_deref().unwrap_or("?"),
+            what,
+            delayed
+        )?;
+        for deal in &step.closed {
+            match deal.pnl_pct {
+                Some(pnl_pct) => writeln!(out, "    closed {} deal: {:+.2}%", deal.bot, pnl_pct)?,
+                None => writeln!(out, "    closed {} deal: no price", deal.bot)?,
+            }
+        }
+    }
+
+    writeln!(out)?;
+    writeln!(out, "{:<16} {:>7} {:>8} {:>8} {:>5} {:>4} {:>8} {:>4}", "strategy", "signals", "executed", "rejected", "deals", "wins", "pnl %", "open")?;
+    for (strategy, summary) in &report.strategies {
+        writeln!(
+            out,
+            "{:<16} {:>7} {:>8} {:>8} {:>5} {:>4} {:>8.2} {:>4}",
+            strategy,
+            summary.signals,
+            summary.executed,
+            summary.rejected,
+            summary.deals,
+            summary.wins,
+            summary.pnl_pct,
+            summary.open
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{RiskSettings, StrategySettings};
+
+    const ALERT_LOG: &str = "\
+Alert ID,Ticker,Name,Description,Time
+1,BTCUSDT,fancy,\"{\"\"strategy\"\": \"\"fancy\"\", \"\"action\"\": \"\"buy\"\", \"\"price\"\": 100}\",2021-06-02T12:00:00Z
+2,BTCUSDT,fancy,sell strategy=fancy price=110,2021-06-02T13:00:00Z
+3,BTCUSDT,fancy,buy strategy=fancy price=104.5,2021-06-02 13:00:30
+4,BTCUSDT,fancy,blah blah,2021-06-02T14:00:00Z
+";
+
+    fn settings() -> Settings {
+        let risk = RiskSettings { min_secs_between_flips: Some(60), ..RiskSettings::default() };
+        let mut settings = Settings::default();
+        settings.strategies.insert("fancy".into(), StrategySettings { risk, ..StrategySettings::default() });
+        settings
+    }
+
+    #[test]
+    fn it_reads_alert_logs() {
+        let recorded = read_alert_log(ALERT_LOG.as_bytes()).unwrap();
+        assert_eq!(recorded.len(), 4);
+        assert_eq!(recorded[2].at, "2021-06-02T13:00:30Z".parse::<DateTime<Utc>>().unwrap());
+        assert_eq!(recorded[0].signal.as_ref().unwrap().order.price, Some(100.0));
+        assert!(recorded[3].signal.is_err());
+    }
+
+    #[test]
+    fn it_reads_journals() {
+        let journal = r#"{"at":"2021-06-02T12:00:00Z","signal_id":"a","event":"signal_received","strategy":"fancy","format":"text","signal":{"strategy":"fancy","order":{"action":"sell","contracts":null,"price":1.5,"id":null,"comment":null,"alert_message":null}}}
+{"at":"2021-06-02T12:00:01Z","signal_id":"a","event":"signal_rejected","reason":"nope"}
+"#;
+        let recorded = read_journal(journal.as_bytes()).unwrap();
+        assert_eq!(recorded.len(), 1);
+        let signal = recorded[0].signal.as_ref().unwrap();
+        assert_eq!(signal.format, crate::incoming::SignalFormat::Text);
+        assert_eq!(signal.order.price, Some(1.5));
+    }
+
+    #[tokio::test]
+    async fn it_replays_journalled_dry_runs_as_dry_runs() {
+        let journal = r#"{"at":"2021-06-02T12:00:00Z","signal_id":"a","event":"signal_received","strategy":"fancy","signal":{"strategy":"fancy","order":{"action":"buy","price":100,"alert_message":"dry_run=true"}}}
+{"at":"2021-06-02T12:00:01Z","signal_id":"b","event":"signal_received","strategy":"fancy","signal":{"strategy":"fancy","order":{"action":"buy","price":100}},"dry_run":true}
+"#;
+        let recorded = read_journal(journal.as_bytes()).unwrap();
+        let signal = recorded[0].signal.as_ref().unwrap();
+        assert_eq!(signal.order.alert_message.as_deref(), Some("dry_run=true"));
+
+        let mut settings = settings();
+        settings.strategies.get_mut("fancy").unwrap().alert_message_overrides = vec![crate::settings::OverrideField::DryRun];
+        let report = replay(&settings, recorded).await;
+        let outcomes: Vec<Outcome> = report.steps.iter().map(|step| step.outcome).collect();
+        assert_eq!(outcomes, [Outcome::DryRun, Outcome::DryRun]);
+    }
+
+    #[tokio::test]
+    async fn it_replays_a_day_of_trading() {
+        let recorded = read_alert_log(ALERT_LOG.as_bytes()).unwrap();
+        let report = replay(&settings(), recorded).await;
+
+        let outcomes: Vec<Outcome> = report.steps.iter().map(|step| step.outcome).collect();
+        assert_eq!(outcomes, [Outcome::Executed, Outcome::Executed, Outcome::Rejected, Outcome::Rejected]);
+        assert!(report.steps[2].reason.as_ref().unwrap().starts_with("Risk limit"));
+        assert_eq!(report.steps[1].closed[0].pnl_pct, Some(10.0));
+
+        let fancy = &report.strategies["fancy"];
+        assert_eq!((fancy.signals, fancy.executed, fancy.deals, fancy.wins, fancy.open), (3, 2, 1, 1, 1));
+
+        let mut text = vec![];
+        write_text(&report, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("closed long deal: +10.00%"));
+    }
+}
@@ -0,0 +1,147 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use warp::{http::StatusCode, reply::{self, Response}, Reply};
+use crate::journal::{JournalEvent, SequencePlan};
+
+/// Why a request was turned away, for machines.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The admin token was missing or wrong
+    Auth,
+    /// The signal couldn't be parsed
+    Schema,
+    /// The webhook body was too big
+    TooLarge,
+    UnknownStrategy,
+    FormatNotAccepted,
+    OverrideNotAllowed,
+    /// The deal size or exits don't make sense
+    InvalidOrder,
+    FailedChecks,
+    Duplicate,
+    RiskLimit,
+    /// Too many signals, see the `Retry-After` header
+    RateLimited,
+    Paused,
+    ShuttingDown,
+    BadRequest,
+    Internal,
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorDetail {
+    code: ErrorCode,
+    message: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorBody {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signal_id: Option<String>,
+    error: ErrorDetail,
+}
+
+/// `{"signal_id": ..., "error": {"code": ..., "message": ...}}`, with the
+/// signal id when the request got that far.
+pub fn error(signal_id: Option<&str>, code: ErrorCode, message: String, status: StatusCode) -> Response {
+    let body = ErrorBody {
+        signal_id: signal_id.map(String::from),
+        error: ErrorDetail { code, message },
+    };
+    reply::with_status(reply::json(&body), status).into_response()
+}
+
+/// What we did with a signal we took.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    /// The requests are on their way
+    Accepted,
+    /// A risk limit is holding the requests back for now
+    Deferred,
+    /// The requests will go out at `run_at`
+    Scheduled,
+    /// The requests were planned, but `alert_message` said not to send them
+    DryRun,
+    /// The requests have been sent, see `results`
+    Executed,
+}
+
+/// The reply to a signal we took.
+#[derive(Serialize, Debug)]
+pub struct SignalResponse {
+    pub signal_id: String,
+    pub strategy: String,
+    pub decision: Decision,
+    /// What goes to each account
+    pub plan: Vec<SequencePlan>,
+    /// When the requests go out, if they're scheduled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_at: Option<DateTime<Utc>>,
+    /// How each request and webhook went, in sync mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results: Option<Vec<JournalEvent>>,
+}
+
+impl SignalResponse {
+    pub fn status(&self) -> StatusCode {
+        match self.decision {
+            Decision::Deferred | Decision::Scheduled => StatusCode::ACCEPTED,
+            Decision::Accepted | Decision::Executed | Decision::DryRun => StatusCode::OK,
+        }
+    }
+}
+
+impl Reply for SignalResponse {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        reply::with_status(reply::json(&self), status).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    async fn body(response: Response) -> Value {
+        let bytes = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_explains_errors() {
+        let response = error(Some("abc"), ErrorCode::UnknownStrategy, "Unknown strategy \"x\"".into(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body(response).await,
+            json!({"signal_id": "abc", "error": {"code": "unknown_strategy", "message": "Unknown strategy \"x\""}})
+        );
+    }
+
+    #[tokio::test]
+    async fn it_shows_the_plan() {
+        let response = SignalResponse {
+            signal_id: "abc".into(),
+            strategy: "fancy v1".into(),
+            decision: Decision::Deferred,
+            plan: vec![SequencePlan { account: "default".into(), requests: vec![], webhooks: vec![] }],
+            run_at: None,
+            results: None,
+        }
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(
+            body(response).await,
+            json!({
+                "signal_id": "abc",
+                "strategy": "fancy v1",
+                "decision": "deferred",
+                "plan": [{"account": "default", "requests": [], "webhooks": []}],
+            })
+        );
+    }
+}
@@ -0,0 +1,461 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    sync::Mutex,
+};
+use crate::{
+    alert,
+    outgoing::{OutgoingRequest, deal_and_bot_types::ActionType},
+    settings::{OnViolation, RiskSettings, TradingHours},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    TooManySignals,
+    Cooldown,
+    OutsideTradingHours,
+    DailyLossCap,
+    TooManyOpenDeals,
+}
+
+/// A limit a signal would break, and when (if ever) it wouldn't.
+#[derive(Debug, Clone)]
+pub struct RiskViolation {
+    pub kind: ViolationKind,
+    pub message: String,
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for RiskViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// What we remember about a strategy's recent trading.
+#[derive(Default)]
+struct StrategyRisk {
+    signals: VecDeque<DateTime<Utc>>,
+    last_open: Option<DateTime<Utc>>,
+    open_deals: HashSet<u64>,
+    realized: Option<(NaiveDate, f64)>,
+}
+
+impl StrategyRisk {
+    fn realized_on(&self, day: NaiveDate) -> f64 {
+        match self.realized {
+            Some((date, pnl)) if date == day => pnl,
+            _ => 0.0,
+        }
+    }
+}
+
+fn opens_deal(requests: &[OutgoingRequest]) -> bool {
+    requests.iter().any(|request| ActionType::is_start(&request.action))
+}
+
+/// The bots with open deals once `requests` have gone through.
+fn open_deals_after(open_deals: &HashSet<u64>, requests: &[OutgoingRequest]) -> HashSet<u64> {
+    let mut open_deals = open_deals.clone();
+    for request in requests {
+        match request.action {
+            ActionType::StartDeal => { open_deals.insert(request.bot_id); }
+            ActionType::CloseDeal => { open_deals.remove(&request.bot_id); }
+            _ => (),
+        }
+    }
+    open_deals
+}
+
+fn trades_on(hours: &TradingHours, day: chrono::Weekday) -> bool {
+    hours.days.is_empty() || hours.days.contains(&day)
+}
+
+fn in_trading_hours(hours: &TradingHours, now: DateTime<Utc>) -> bool {
+    let time = now.time();
+    let today = now.weekday();
+
+    if hours.start <= hours.end {
+        trades_on(hours, today) && hours.start <= time && time < hours.end
+    } else {
+        // The session runs past midnight, so it might have started yesterday
+        (trades_on(hours, today) && time >= hours.start)
+            || (trades_on(hours, today.pred()) && time < hours.end)
+    }
+}
+
+fn until_trading_hours(hours: &TradingHours, now: DateTime<Utc>) -> Option<Duration> {
+    (0..8)
+        .filter_map(|days| (now.date() + Duration::days(days)).and_time(hours.start))
+        .find(|start| *start > now && trades_on(hours, start.weekday()))
+        .map(|start| start - now)
+}
+
+/// Whose deals each bot has been opening, to put their results down to.
+#[derive(Default)]
+struct DealOwners {
+    /// The strategy that last opened a deal on each bot, and when
+    by_bot: HashMap<u64, (String, DateTime<Utc>)>,
+    /// Deals whose results have already been counted
+    counted: HashSet<u64>,
+}
+
+/// Keeps track of every strategy's trading, to hold it to its `RiskSettings`.
+pub struct RiskGuard {
+    strategies: Mutex<HashMap<String, StrategyRisk>>,
+    owners: Mutex<DealOwners>,
+}
+
+impl RiskGuard {
+    pub fn new() -> Self {
+        RiskGuard {
+            strategies: Mutex::new(HashMap::new()),
+            owners: Mutex::new(DealOwners::default()),
+        }
+    }
+
+    /// Checks `requests` against `strategy`'s limits. If they pass, they're
+    /// counted towards the limits for the next signal.
+    pub fn admit(
+        &self,
+        strategy: &str,
+        limits: &RiskSettings,
+        requests: &[OutgoingRequest],
+        now: DateTime<Utc>,
+    ) -> Result<(), RiskViolation> {
+        let mut strategies = self.strategies.lock().unwrap();
+        let state = strategies.entry(strategy.into()).or_default();
+
+        let window = Duration::seconds(limits.window_secs as i64);
+        while state.signals.front().is_some_and(|at| *at + window <= now) {
+            state.signals.pop_front();
+        }
+
+        if let Some(max_signals) = limits.max_signals {
+            if state.signals.len() >= max_signals {
+                return Err(RiskViolation {
+                    kind: ViolationKind::TooManySignals,
+                    message: format!(
+                        "Already had {} signals in the last {}s",
+                        state.signals.len(),
+                        limits.window_secs
+                    ),
+                    retry_after: state.signals.front().map(|oldest| *oldest + window - now),
+                });
+            }
+        }
+
+        let open_deals = open_deals_after(&state.open_deals, requests);
+
+        // Everything else only matters if we'd be opening a deal. Closing is always fine.
+        if opens_deal(requests) {
+            if let Some(hours) = &limits.trading_hours {
+                if !in_trading_hours(hours, now) {
+                    return Err(RiskViolation {
+                        kind: ViolationKind::OutsideTradingHours,
+                        message: format!("Outside trading hours at {}", now.format("%a %H:%M")),
+                        retry_after: until_trading_hours(hours, now),
+                    });
+                }
+            }
+
+            if let (Some(min_secs), Some(last_open)) = (limits.min_secs_between_flips, state.last_open) {
+                let ready_at = last_open + Duration::seconds(min_secs as i64);
+                if ready_at > now {
+                    return Err(RiskViolation {
+                        kind: ViolationKind::Cooldown,
+                        message: format!("Last deal opened less than {}s ago", min_secs),
+                        retry_after: Some(ready_at - now),
+                    });
+                }
+            }
+
+            if let Some(max_loss) = limits.max_daily_loss {
+                let realized = state.realized_on(now.date().naive_utc());
+                if -realized >= max_loss {
+                    return Err(RiskViolation {
+                        kind: ViolationKind::DailyLossCap,
+                        message: format!("Lost {} today, the limit is {}", -realized, max_loss),
+                        retry_after: None,
+                    });
+                }
+            }
+
+            if let Some(max_open) = limits.max_open_deals {
+                if open_deals.len() > max_open {
+                    return Err(RiskViolation {
+                        kind: ViolationKind::TooManyOpenDeals,
+                        message: format!("Would have {} open deals, the limit is {}", open_deals.len(), max_open),
+                        retry_after: None,
+                    });
+                }
+            }
+
+            state.last_open = Some(now);
+        }
+
+        state.signals.push_back(now);
+        state.open_deals = open_deals;
+
+        let mut owners = self.owners.lock().unwrap();
+        for request in requests.iter().filter(|request| request.action == ActionType::StartDeal) {
+            owners.by_bot.insert(request.bot_id, (strategy.into(), now));
+        }
+        Ok(())
+    }
+
+    /// Counts a closed deal's profit (or loss, if negative) towards today's total.
+    pub fn record_pnl(&self, strategy: &str, pnl: f64, now: DateTime<Utc>) -> f64 {
+        let today = now.date().naive_utc();
+        let mut strategies = self.strategies.lock().unwrap();
+        let state = strategies.entry(strategy.into()).or_default();
+        let total = state.realized_on(today) + pnl;
+        state.realized = Some((today, total));
+        total
+    }
+
+    /// The bots a strategy has opened deals on, whose results we can count.
+    pub fn owned_bots(&self) -> HashSet<u64> {
+        self.owners.lock().unwrap().by_bot.keys().copied().collect()
+    }
+
+    /// Counts the result of a deal on `bot_id` that closed at `closed_at`
+    /// towards the strategy that opened it, once per deal. Deals from before
+    /// that strategy's last open, or from an earlier day, don't count.
+    /// Returns the strategy and its total for today, if it was counted.
+    pub fn record_deal(
+        &self,
+        bot_id: u64,
+        deal_id: u64,
+        pnl: f64,
+        closed_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Option<(String, f64)> {
+        let strategy = {
+            let mut owners = self.owners.lock().unwrap();
+            let (strategy, opened_at) = owners.by_bot.get(&bot_id)?.clone();
+            if closed_at < opened_at || closed_at.date() != now.date() || !owners.counted.insert(deal_id) {
+                return None;
+            }
+            strategy
+        };
+        let total = self.record_pnl(&strategy, pnl, now);
+        Some((strategy, total))
+    }
+}
+
+impl Default for RiskGuard {
+    fn default() -> Self {
+        RiskGuard::new()
+    }
+}
+
+lazy_static! {
+    pub static ref RISK: RiskGuard = RiskGuard::new();
+}
+
+/// Raises an alert about a signal we're not executing right away.
+pub fn alert_violation(strategy: &str, violation: &RiskViolation, deferred: bool) {
+    let what = if deferred { "deferred" } else { "rejected" };
+    alert::raise(format!("Signal for {:?} {}: {}", strategy, what, violation));
+}
+
+/// What a signal held back on the schedule still has to pass before its
+/// requests go out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RiskCheck {
+    /// The strategy's key in `RISK`, qualified for a tenant's strategies
+    pub strategy: String,
+    pub limits: RiskSettings,
+    pub requests: Vec<OutgoingRequest>,
+    /// For the stop-loss and take-profit journalled once it's let through
+    pub entry_price: Option<f64>,
+    /// When to stop deferring it, from the first time it broke a limit
+    #[serde(default)]
+    pub give_up_at: Option<DateTime<Utc>>,
+}
+
+/// What to do with a signal, by its risk limits.
+#[derive(Debug)]
+pub enum Admission {
+    Admitted,
+    /// Try again then
+    Deferred(DateTime<Utc>, RiskViolation),
+    Rejected(RiskViolation),
+}
+
+impl RiskCheck {
+    /// Checks the requests against `risk` at `now`. A signal over a limit is
+    /// deferred until it might not be, if its strategy defers and that's
+    /// within `max_defer_secs` of the first time it was over one.
+    pub fn admit(&mut self, risk: &RiskGuard, now: DateTime<Utc>) -> Admission {
+        let violation = match risk.admit(&self.strategy, &self.limits, &self.requests, now) {
+            Ok(()) => return Admission::Admitted,
+            Err(violation) => violation,
+        };
+        let max_defer = Duration::seconds(self.limits.max_defer_secs as i64);
+        let give_up_at = *self.give_up_at.get_or_insert(now + max_defer);
+        match violation.retry_after {
+            Some(wait) if self.limits.on_violation == OnViolation::Defer && now + wait <= give_up_at => {
+                Admission::Deferred(now + wait, violation)
+            }
+            _ => Admission::Rejected(violation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveTime, TimeZone, Weekday};
+    use crate::outgoing::deal_and_bot_types::BotType;
+
+    fn buy() -> Vec<OutgoingRequest> {
+        vec![
+            OutgoingRequest::new((ActionType::CloseDeal, BotType::Short)),
+            OutgoingRequest::new((ActionType::StartDeal, BotType::Long)),
+        ]
+    }
+
+    fn flatten() -> Vec<OutgoingRequest> {
+        vec![
+            OutgoingRequest::new((ActionType::CloseDeal, BotType::Long)),
+            OutgoingRequest::new((ActionType::CloseDeal, BotType::Short)),
+        ]
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        // A Wednesday
+        Utc.ymd(2021, 6, 2).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn it_limits_signals_per_window() {
+        let guard = RiskGuard::new();
+        let limits = RiskSettings { max_signals: Some(2), window_secs: 60, ..Default::default() };
+
+        assert!(guard.admit("s", &limits, &flatten(), at(12, 0)).is_ok());
+        assert!(guard.admit("s", &limits, &flatten(), at(12, 0)).is_ok());
+        let violation = guard.admit("s", &limits, &flatten(), at(12, 0)).unwrap_err();
+        assert_eq!(violation.kind, ViolationKind::TooManySignals);
+        assert_eq!(violation.retry_after, Some(Duration::seconds(60)));
+        assert!(guard.admit("s", &limits, &flatten(), at(12, 1)).is_ok());
+    }
+
+    #[test]
+    fn it_enforces_a_cooldown_between_flips() {
+        let guard = RiskGuard::new();
+        let limits = RiskSettings { min_secs_between_flips: Some(300), ..Default::default() };
+
+        assert!(guard.admit("s", &limits, &buy(), at(12, 0)).is_ok());
+        let violation = guard.admit("s", &limits, &buy(), at(12, 1)).unwrap_err();
+        assert_eq!(violation.kind, ViolationKind::Cooldown);
+        assert_eq!(violation.retry_after, Some(Duration::minutes(4)));
+
+        // Closing is always allowed
+        assert!(guard.admit("s", &limits, &flatten(), at(12, 1)).is_ok());
+    }
+
+    #[test]
+    fn it_only_trades_in_trading_hours() {
+        let guard = RiskGuard::new();
+        let hours = TradingHours {
+            start: NaiveTime::from_hms(22, 0, 0),
+            end: NaiveTime::from_hms(2, 0, 0),
+            days: vec![Weekday::Tue, Weekday::Wed],
+        };
+        let limits = RiskSettings { trading_hours: Some(hours), ..Default::default() };
+
+        // Tuesday night's session runs into Wednesday morning
+        assert!(guard.admit("s", &limits, &buy(), at(1, 0)).is_ok());
+        let violation = guard.admit("s", &limits, &buy(), at(12, 0)).unwrap_err();
+        assert_eq!(violation.kind, ViolationKind::OutsideTradingHours);
+        assert_eq!(violation.retry_after, Some(Duration::hours(10)));
+    }
+
+    #[test]
+    fn it_stops_opening_deals_after_the_daily_loss_cap() {
+        let guard = RiskGuard::new();
+        let limits = RiskSettings { max_daily_loss: Some(100.0), ..Default::default() };
+
+        guard.record_pnl("s", -60.0, at(9, 0));
+        assert!(guard.admit("s", &limits, &buy(), at(10, 0)).is_ok());
+        guard.record_pnl("s", -40.0, at(11, 0));
+        let violation = guard.admit("s", &limits, &buy(), at(12, 0)).unwrap_err();
+        assert_eq!(violation.kind, ViolationKind::DailyLossCap);
+
+        // A new day
+        assert!(guard.admit("s", &limits, &buy(), at(12, 0) + Duration::days(1)).is_ok());
+    }
+
+    #[test]
+    fn it_counts_each_closed_deal_towards_the_strategy_that_opened_it() {
+        let guard = RiskGuard::new();
+        let limits = RiskSettings::default();
+        let bot_id = BotType::Long.get_bot_id();
+
+        assert_eq!(guard.record_deal(bot_id, 1, -10.0, at(9, 0), at(9, 5)), None);
+        guard.admit("s", &limits, &buy(), at(10, 0)).unwrap();
+        assert_eq!(guard.owned_bots(), [bot_id].iter().copied().collect());
+
+        // Closed before we opened it
+        assert_eq!(guard.record_deal(bot_id, 1, -10.0, at(9, 0), at(11, 0)), None);
+        assert_eq!(guard.record_deal(bot_id, 2, -25.0, at(10, 30), at(11, 0)), Some(("s".into(), -25.0)));
+        assert_eq!(guard.record_deal(bot_id, 2, -25.0, at(10, 30), at(11, 5)), None);
+        assert_eq!(guard.record_deal(bot_id, 3, 5.0, at(11, 0), at(11, 5)), Some(("s".into(), -20.0)));
+    }
+
+    #[test]
+    fn it_limits_open_deals() {
+        let guard = RiskGuard::new();
+        let limits = RiskSettings { max_open_deals: Some(1), ..Default::default() };
+        let both = vec![
+            OutgoingRequest::new((ActionType::StartDeal, BotType::Long)),
+            OutgoingRequest::new((ActionType::StartDeal, BotType::Short)),
+        ];
+
+        assert!(guard.admit("s", &limits, &buy(), at(12, 0)).is_ok());
+        assert_eq!(
+            guard.admit("s", &limits, &both, at(12, 1)).unwrap_err().kind,
+            ViolationKind::TooManyOpenDeals
+        );
+    }
+
+    #[test]
+    fn it_defers_held_signals_until_it_gives_up() {
+        let guard = RiskGuard::new();
+        let hours = TradingHours {
+            start: NaiveTime::from_hms(13, 0, 0),
+            end: NaiveTime::from_hms(17, 0, 0),
+            days: vec![],
+        };
+        let limits = RiskSettings {
+            trading_hours: Some(hours),
+            on_violation: OnViolation::Defer,
+            max_defer_secs: 7200,
+            ..Default::default()
+        };
+        let check = RiskCheck {
+            strategy: "s".into(),
+            limits,
+            requests: buy(),
+            entry_price: None,
+            give_up_at: None,
+        };
+
+        // Held on the schedule, so it has to come back the same
+        let json = serde_json::to_string(&check).unwrap();
+        assert!(json.contains(r#""start":"13:00""#));
+        let mut check: RiskCheck = serde_json::from_str(&json).unwrap();
+
+        assert!(matches!(check.admit(&guard, at(12, 0)), Admission::Deferred(retry_at, _) if retry_at == at(13, 0)));
+        assert_eq!(check.give_up_at, Some(at(14, 0)));
+        assert!(matches!(check.admit(&guard, at(13, 0)), Admission::Admitted));
+
+        let mut late = RiskCheck { give_up_at: None, ..check.clone() };
+        assert!(matches!(late.admit(&guard, at(10, 0)), Admission::Rejected(_)));
+    }
+}
@@ -0,0 +1,502 @@
+use chrono::{DateTime, Duration, Utc};
+use cron::Schedule;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::PathBuf, str::FromStr, sync::Mutex};
+use tokio::{sync::Notify, time::sleep};
+use uuid::Uuid;
+use crate::{
+    incoming::{IncomingSignal, SignalError},
+    journal::{self, JournalEvent},
+    logging,
+    outgoing::{sequence::Sequence, OutgoingRequest},
+    settings::{get_settings, BotCommand, ScheduleSettings, StrategySettings},
+    risk::RiskCheck,
+    shutdown::{self, IN_FLIGHT},
+    storage::{self, Storage},
+};
+
+/// How long to sleep when nothing is scheduled, in case the clock jumps.
+const IDLE_WAIT_SECS: i64 = 3600;
+
+/// The furthest ahead anything can be scheduled, a week.
+pub const MAX_DELAY_SECS: u64 = 7 * 24 * 3600;
+
+/// What a job does when it's due.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// A signal's requests, held back by a delay or a risk limit
+    Signal {
+        sequences: Vec<Sequence>,
+        /// The risk limits they have to pass first, if they haven't yet
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        risk: Option<Box<RiskCheck>>,
+        /// Stages to put on the schedule once these go out, that many seconds later
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        then: Vec<(u64, Vec<Sequence>)>,
+    },
+    StartBots,
+    StopBots,
+}
+
+impl Command {
+    /// A signal's requests that only have to wait for their time to come.
+    pub fn signal(sequences: Vec<Sequence>) -> Self {
+        Command::Signal { sequences, risk: None, then: vec![] }
+    }
+}
+
+impl From<BotCommand> for Command {
+    fn from(command: BotCommand) -> Self {
+        match command {
+            BotCommand::StartBots => Command::StartBots,
+            BotCommand::StopBots => Command::StopBots,
+        }
+    }
+}
+
+/// Something to do later, once or on a cron schedule.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    /// The signal id for a delayed signal, `config:<n>` for the config file's
+    /// `schedules`, and a fresh id for anything added through the admin API
+    pub id: String,
+    #[serde(flatten)]
+    pub command: Command,
+    pub run_at: DateTime<Utc>,
+    /// Runs again after `run_at` when this is set
+    pub cron: Option<String>,
+}
+
+impl Job {
+    /// A job that runs `command` once, at `run_at`.
+    pub fn once(id: &str, command: Command, run_at: DateTime<Utc>) -> Self {
+        Job { id: id.into(), command, run_at, cron: None }
+    }
+
+    /// A job that runs `command` whenever `cron` comes round after `now`.
+    pub fn recurring(id: &str, command: Command, cron: &str, now: DateTime<Utc>) -> Result<Self, String> {
+        Ok(Job {
+            id: id.into(),
+            command,
+            run_at: next_after(cron, now)?,
+            cron: Some(cron.into()),
+        })
+    }
+
+    pub fn new_id() -> String {
+        Uuid::new_v4().to_string()
+    }
+
+    /// The signal a delayed signal's job is for. The steps after a rule's
+    /// `wait` are jobs of their own, with ids like `<signal id>+30s`.
+    pub fn signal_id(&self) -> &str {
+        match &self.command {
+            Command::Signal { sequences, .. } => sequences.first().map_or(&self.id, |sequence| &sequence.signal_id),
+            _ => &self.id,
+        }
+    }
+
+    /// Whether this runs `command`, for bot commands.
+    fn does(&self, command: &Command) -> bool {
+        matches!(
+            (&self.command, command),
+            (Command::StartBots, Command::StartBots) | (Command::StopBots, Command::StopBots)
+        )
+    }
+}
+
+/// The next time `cron` comes round after `after`. Takes the usual five
+/// fields, or six with seconds first.
+pub fn next_after(cron: &str, after: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    let expression = match cron.split_whitespace().count() {
+        5 => format!("0 {}", cron),
+        _ => cron.to_string(),
+    };
+    let schedule = Schedule::from_str(&expression).map_err(|e| format!("Bad cron {:?}: {}", cron, e))?;
+    schedule
+        .after(&after)
+        .next()
+        .ok_or_else(|| format!("Cron {:?} never comes round again", cron))
+}
+
+/// `secs` after `now`, as long as that's no more than `MAX_DELAY_SECS` away.
+pub fn after_delay(secs: u64, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    if secs > MAX_DELAY_SECS {
+        return Err(format!("a delay of {}s is over the limit of {}s", secs, MAX_DELAY_SECS));
+    }
+    Ok(now + Duration::seconds(secs as i64))
+}
+
+/// When a signal's requests should go out, if not straight away: its own
+/// `execute_at` or `delay_secs`, or else the strategy's `delay_secs`. Nothing
+/// waits more than `MAX_DELAY_SECS`.
+pub fn run_at(
+    signal: &IncomingSignal,
+    strategy: &StrategySettings,
+    now: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, SignalError> {
+    let run_at = match (signal.execute_at, signal.delay_secs.or(strategy.delay_secs)) {
+        (Some(execute_at), _) => {
+            if execute_at > now + Duration::seconds(MAX_DELAY_SECS as i64) {
+                return Err(SignalError::BadSchedule(format!(
+                    "execute_at {} is more than {}s away",
+                    execute_at, MAX_DELAY_SECS
+                )));
+            }
+            Some(execute_at)
+        }
+        (None, Some(secs)) => Some(after_delay(secs, now).map_err(SignalError::BadSchedule)?),
+        (None, None) => None,
+    };
+    Ok(run_at.filter(|run_at| *run_at > now))
+}
+
+/// Requests that go out together, and how long after the signal's first ones.
+pub type Stage = (u64, Vec<OutgoingRequest>);
+
+/// Splits `requests` where a rule said to `wait`, keeping their order. There's
+/// always a first stage, even an empty one, for the webhooks to go out with.
+pub fn stages(requests: &[OutgoingRequest]) -> Result<Vec<Stage>, SignalError> {
+    let mut stages: Vec<Stage> = vec![];
+    for request in requests {
+        if request.hold_secs > MAX_DELAY_SECS {
+            return Err(SignalError::BadSchedule(format!(
+                "a rule waits {}s, over the limit of {}s",
+                request.hold_secs, MAX_DELAY_SECS
+            )));
+        }
+        match stages.last_mut() {
+            Some((hold_secs, stage)) if *hold_secs == request.hold_secs => stage.push(request.clone()),
+            _ => stages.push((request.hold_secs, vec![request.clone()])),
+        }
+    }
+    if stages.is_empty() {
+        stages.push((0, vec![]));
+    }
+    Ok(stages)
+}
+
+/// Every job that's waiting, saved so they survive a restart.
+pub struct Scheduler {
+    path: Option<PathBuf>,
+    jobs: Mutex<Vec<Job>>,
+    /// Tells the runner to look again, when a job is added
+    wake: Notify,
+}
+
+impl Scheduler {
+    /// Loads the jobs saved at `path`, or in `database` if they aren't there,
+    /// or starts with none.
+    pub fn load(path: Option<PathBuf>, database: Option<&Storage>) -> Self {
+        let from_file = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(json) => serde_json::from_str(&json)
+                    .map_err(|e| error!("Can't read scheduled jobs from {:?}: {}", path, e))
+                    .ok(),
+                Err(_) => None,
+            },
+            None => None,
+        };
+        let jobs = from_file.or_else(|| {
+            let jobs = database?
+                .load_jobs()
+                .map_err(|e| error!("Can't read scheduled jobs from the database: {}", e))
+                .ok()?;
+            info!("Read {} scheduled jobs back from the database", jobs.len());
+            Some(jobs)
+        });
+
+        Scheduler {
+            path,
+            jobs: Mutex::new(jobs.unwrap_or_default()),
+            wake: Notify::new(),
+        }
+    }
+
+    /// Every job, soonest first.
+    pub fn all(&self) -> Vec<Job> {
+        let mut jobs = self.jobs.lock().unwrap().clone();
+        jobs.sort_by_key(|job| job.run_at);
+        jobs
+    }
+
+    /// Adds `job`, replacing any job with the same id.
+    pub fn add(&self, job: Job) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|other| other.id != job.id);
+        jobs.push(job);
+        self.save(&jobs);
+        self.wake.notify_one();
+    }
+
+    /// Takes job `id` off the schedule.
+    pub fn cancel(&self, id: &str) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let index = jobs.iter().position(|job| job.id == id)?;
+        let job = jobs.remove(index);
+        self.save(&jobs);
+        Some(job)
+    }
+
+    /// Swaps the config file's jobs for `schedules`. A job whose schedule hasn't
+    /// changed keeps its next run, so one missed while we were down still runs.
+    pub fn use_config(&self, schedules: &[ScheduleSettings], now: DateTime<Utc>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut from_config = vec![];
+        for (n, schedule) in schedules.iter().enumerate() {
+            let id = format!("config:{}", n);
+            let command = Command::from(schedule.command);
+            let unchanged = jobs.iter().find(|job| {
+                job.id == id && job.cron.as_deref() == Some(schedule.cron.as_str()) && job.does(&command)
+            });
+            match unchanged {
+                Some(job) => from_config.push(job.clone()),
+                None => match Job::recurring(&id, command, &schedule.cron, now) {
+                    Ok(job) => from_config.push(job),
+                    Err(e) => error!("Not scheduling {:?}: {}", schedule, e),
+                },
+            }
+        }
+
+        jobs.retain(|job| !job.id.starts_with("config:"));
+        jobs.extend(from_config);
+        self.save(&jobs);
+        self.wake.notify_one();
+    }
+
+    /// Takes off every job that's due by `now`, soonest first. Recurring jobs
+    /// go back on for their next run.
+    pub fn take_due(&self, now: DateTime<Utc>) -> Vec<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let (mut due, waiting): (Vec<Job>, Vec<Job>) = jobs.drain(..).partition(|job| job.run_at <= now);
+        due.sort_by_key(|job| job.run_at);
+        *jobs = waiting;
+        if due.is_empty() {
+            return due;
+        }
+
+        for job in &due {
+            if let Some(cron) = &job.cron {
+                match next_after(cron, now) {
+                    Ok(run_at) => jobs.push(Job { run_at, ..job.clone() }),
+                    Err(e) => error!("Dropping job {:?}: {}", job.id, e),
+                }
+            }
+        }
+        self.save(&jobs);
+        due
+    }
+
+    /// When the soonest job is due.
+    pub fn next_run(&self) -> Option<DateTime<Utc>> {
+        self.jobs.lock().unwrap().iter().map(|job| job.run_at).min()
+    }
+
+    /// Saved privately, since delayed signals' webhooks can have tokens in them.
+    fn save(&self, jobs: &[Job]) {
+        let write = |path: &PathBuf| -> io::Result<()> {
+            shutdown::write_private(path, serde_json::to_string_pretty(jobs)?.as_bytes())
+        };
+
+        if let Some(path) = &self.path {
+            if let Err(e) = write(path) {
+                error!("Can't save scheduled jobs to {:?}: {}", path, e);
+            }
+        }
+        storage::save_jobs(jobs);
+    }
+}
+
+lazy_static! {
+    pub static ref SCHEDULER: Scheduler = {
+        let path = get_settings().schedule_path.as_ref().map(PathBuf::from);
+        Scheduler::load(path, storage::STORAGE.as_ref())
+    };
+}
+
+/// Runs jobs as they come due, until shutdown. Anything still waiting then is
+/// picked up on the next start.
+pub async fn run_scheduled(server: String) {
+    SCHEDULER.use_config(&get_settings().schedules, Utc::now());
+
+    while IN_FLIGHT.is_accepting() {
+        let now = Utc::now();
+        let max_lateness = Duration::seconds(get_settings().schedule_max_lateness_secs as i64);
+        for job in SCHEDULER.take_due(now) {
+            if is_stale(&job, now, max_lateness) {
+                drop_stale(&job, now);
+            } else {
+                run(job, server.clone()).await;
+            }
+        }
+
+        let now = Utc::now();
+        let wait = SCHEDULER
+            .next_run()
+            .map_or(Duration::seconds(IDLE_WAIT_SECS), |run_at| run_at - now)
+            .clamp(Duration::zero(), Duration::seconds(IDLE_WAIT_SECS));
+        tokio::select! {
+            _ = sleep(wait.to_std().unwrap_or_default()) => (),
+            _ = SCHEDULER.wake.notified() => (),
+        }
+    }
+}
+
+/// Whether `job` is a delayed signal that came due more than `max_lateness`
+/// before `now`. Bot commands are never too late: the bots should still end
+/// up started or stopped.
+fn is_stale(job: &Job, now: DateTime<Utc>, max_lateness: Duration) -> bool {
+    matches!(job.command, Command::Signal { .. }) && now - job.run_at > max_lateness
+}
+
+fn drop_stale(job: &Job, now: DateTime<Utc>) {
+    let late_secs = (now - job.run_at).num_seconds();
+    logging::for_signal_sync(job.signal_id(), || warn!("Dropping delayed requests that are {}s late", late_secs));
+    journal::record(job.signal_id(), JournalEvent::SignalRejected {
+        reason: format!("Came due {}s late, after the limit of schedule_max_lateness_secs", late_secs),
+    });
+}
+
+/// Sends a held signal's requests off if it passes its risk limits, or runs a
+/// bot command to the end, so a stop and a start that both came due while we
+/// were down happen in order.
+async fn run(job: Job, server: String) {
+    let signal_id = job.signal_id().to_string();
+    let job_id = job.id;
+    match job.command {
+        Command::Signal { sequences, risk, then } => {
+            logging::for_signal_sync(&signal_id, || {
+                crate::release(&signal_id, &job_id, sequences, risk, then, server)
+            });
+        }
+        Command::StartBots => {
+            info!("Starting the bots, as scheduled by {:?}", job_id);
+            crate::start_bots().await;
+        }
+        Command::StopBots => {
+            info!("Stopping the bots, as scheduled by {:?}", job_id);
+            crate::stop_bots().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn it_finds_the_next_cron_run() {
+        // A Wednesday
+        let now = at("2021-06-02T12:00:00Z");
+        assert_eq!(next_after("0 21 * * Fri", now).unwrap(), at("2021-06-04T21:00:00Z"));
+        assert_eq!(next_after("30 0 21 * * Fri", now).unwrap(), at("2021-06-04T21:00:30Z"));
+        assert!(next_after("every friday", now).is_err());
+    }
+
+    #[test]
+    fn it_works_out_when_a_signal_runs() {
+        let now = at("2021-06-02T12:00:00Z");
+        let strategy = StrategySettings { delay_secs: Some(60), ..StrategySettings::default() };
+        let mut signal = crate::incoming::parse_signal(None, b"buy delay_secs=30").unwrap();
+
+        assert_eq!(run_at(&signal, &StrategySettings::default(), now).unwrap(), Some(at("2021-06-02T12:00:30Z")));
+        signal.delay_secs = None;
+        assert_eq!(run_at(&signal, &strategy, now).unwrap(), Some(at("2021-06-02T12:01:00Z")));
+        signal.execute_at = Some(at("2021-06-02T13:00:00Z"));
+        assert_eq!(run_at(&signal, &strategy, now).unwrap(), Some(at("2021-06-02T13:00:00Z")));
+        signal.execute_at = Some(at("2021-06-02T11:00:00Z"));
+        assert_eq!(run_at(&signal, &StrategySettings::default(), now).unwrap(), None);
+    }
+
+    #[test]
+    fn it_refuses_to_wait_too_long() {
+        let now = at("2021-06-02T12:00:00Z");
+        let mut signal = crate::incoming::parse_signal(None, b"buy delay_secs=18446744073709551615").unwrap();
+        assert!(matches!(
+            run_at(&signal, &StrategySettings::default(), now),
+            Err(SignalError::BadSchedule(_))
+        ));
+        signal.delay_secs = None;
+        signal.execute_at = Some(at("2031-06-02T12:00:00Z"));
+        assert!(run_at(&signal, &StrategySettings::default(), now).is_err());
+        assert!(after_delay(MAX_DELAY_SECS, now).is_ok());
+    }
+
+    #[test]
+    fn it_splits_requests_where_rules_wait() {
+        use crate::outgoing::deal_and_bot_types::{ActionType, BotType};
+        let request = |action, bot_type, hold_secs| OutgoingRequest::new((action, bot_type)).held(hold_secs);
+
+        let requests = [
+            request(ActionType::CloseDeal, BotType::Long, 0),
+            request(ActionType::CloseDeal, BotType::Short, 0),
+            request(ActionType::StartDeal, BotType::Short, 30),
+        ];
+        let sizes: Vec<(u64, usize)> =
+            stages(&requests).unwrap().iter().map(|(hold_secs, stage)| (*hold_secs, stage.len())).collect();
+        assert_eq!(sizes, [(0, 2), (30, 1)]);
+
+        assert_eq!(stages(&[]).unwrap().len(), 1);
+        assert!(stages(&[request(ActionType::StartDeal, BotType::Long, MAX_DELAY_SECS + 1)]).is_err());
+    }
+
+    #[test]
+    fn it_drops_delayed_signals_that_are_too_late() {
+        let now = at("2021-06-02T12:00:00Z");
+        let max_lateness = Duration::minutes(5);
+        let signal = Job::once("a", Command::signal(vec![]), now - Duration::minutes(10));
+        let stop = Job::once("b", Command::StopBots, now - Duration::hours(10));
+
+        assert!(is_stale(&signal, now, max_lateness));
+        assert!(!is_stale(&signal, now - Duration::minutes(6), max_lateness));
+        assert!(!is_stale(&stop, now, max_lateness));
+    }
+
+    #[test]
+    fn it_keeps_jobs_across_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("schedule.json");
+        let now = at("2021-06-02T12:00:00Z");
+        let weekends = [ScheduleSettings { cron: "0 21 * * Fri".into(), command: BotCommand::StopBots }];
+
+        let scheduler = Scheduler::load(Some(path.clone()), None);
+        scheduler.add(Job::once("a", Command::StartBots, now + Duration::minutes(5)));
+        scheduler.add(Job::once("b", Command::signal(vec![]), now + Duration::minutes(1)));
+        scheduler.use_config(&weekends, now);
+
+        let scheduler = Scheduler::load(Some(path), None);
+        let ids: Vec<String> = scheduler.all().into_iter().map(|job| job.id).collect();
+        assert_eq!(ids, ["b", "a", "config:0"]);
+
+        assert!(scheduler.take_due(now).is_empty());
+        let due: Vec<String> = scheduler.take_due(now + Duration::minutes(5)).into_iter().map(|job| job.id).collect();
+        assert_eq!(due, ["b", "a"]);
+        assert!(scheduler.cancel("a").is_none());
+
+        let friday = at("2021-06-04T21:00:00Z");
+        assert_eq!(scheduler.next_run(), Some(friday));
+        assert_eq!(scheduler.take_due(friday)[0].id, "config:0");
+        assert_eq!(scheduler.next_run(), Some(friday + Duration::weeks(1)));
+
+        scheduler.use_config(&[], friday);
+        assert_eq!(scheduler.next_run(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_keeps_the_schedule_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("schedule.json");
+        let scheduler = Scheduler::load(Some(path.clone()), None);
+        scheduler.add(Job::once("a", Command::StartBots, at("2021-06-02T12:00:00Z")));
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
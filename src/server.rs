@@ -0,0 +1,264 @@
+use crate::{settings::{get_settings, TlsSettings}, shutdown::stopped};
+use futures::future;
+use log::{error, info};
+use rustls::{internal::pemfile, NoClientAuth, ServerConfig};
+use std::{
+    fs,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::{sync::watch, time::{interval, Duration}};
+use warp::{filters::BoxedFilter, http::{StatusCode, Uri}, reply, Filter, Reply};
+
+/// Runs the API on the configured address, over HTTPS if there's a `tls`
+/// section in the settings and plain HTTP otherwise. Stops taking connections
+/// once `stop` flips to true. Fails if the TLS cert or key won't do.
+pub async fn serve<R: Reply + 'static>(api: BoxedFilter<(R,)>, stop: watch::Receiver<bool>) -> Result<(), String> {
+    let (listen_address, listen_port, tls) = {
+        let settings = get_settings();
+        (settings.listen_address, settings.listen_port, settings.tls.clone())
+    };
+    let addr = SocketAddr::new(listen_address, listen_port);
+
+    match tls {
+        None => {
+            info!("Listening on http://{}", addr);
+            let (_, server) = warp::serve(api).bind_with_graceful_shutdown(addr, stopped(stop));
+            server.await;
+            Ok(())
+        }
+        Some(tls) => {
+            let cert = LoadedCert::read(&tls)?;
+            if let Some(redirect_port) = tls.redirect_port {
+                let redirect_addr = SocketAddr::new(listen_address, redirect_port);
+                info!("Redirecting http://{} to HTTPS", redirect_addr);
+                let (_, redirect) = warp::serve(redirect_to_https(listen_port))
+                    .bind_with_graceful_shutdown(redirect_addr, stopped(stop.clone()));
+                tokio::spawn(redirect);
+            }
+            serve_tls(api, addr, tls, cert, stop).await;
+            Ok(())
+        }
+    }
+}
+
+/// Serves HTTPS, restarting the listener whenever the certificate or key changes
+/// on disk. A renewal that doesn't load is logged, and the old one kept.
+async fn serve_tls<R: Reply + 'static>(
+    api: BoxedFilter<(R,)>,
+    addr: SocketAddr,
+    tls: TlsSettings,
+    mut cert: LoadedCert,
+    stop: watch::Receiver<bool>,
+) {
+    loop {
+        info!("Listening on https://{}", addr);
+        let renewed = Arc::new(Mutex::new(None));
+        let (_, server) = warp::serve(api.clone())
+            .tls()
+            .cert(&cert.cert)
+            .key(&cert.key)
+            .bind_with_graceful_shutdown(
+                addr,
+                reload_or_stop(tls.clone(), cert.fingerprint.clone(), renewed.clone(), stop.clone()),
+            );
+        server.await;
+
+        if *stop.borrow() {
+            return;
+        }
+        let new_cert = renewed.lock().unwrap().take();
+        if let Some(new_cert) = new_cert {
+            info!("TLS certificates changed, reloading them");
+            cert = new_cert;
+        }
+    }
+}
+
+/// A cert and key that rustls takes, and what the files looked like when we read them.
+#[derive(Debug, Clone)]
+struct LoadedCert {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    fingerprint: CertFingerprint,
+}
+
+impl LoadedCert {
+    fn read(tls: &TlsSettings) -> Result<Self, String> {
+        let fingerprint = CertFingerprint::read(tls)
+            .ok_or_else(|| format!("Can't read TLS cert {} or key {}", tls.cert_path, tls.key_path))?;
+        let read = |path: &str| fs::read(path).map_err(|e| format!("Can't read {}: {}", path, e));
+        let (cert, key) = (read(&tls.cert_path)?, read(&tls.key_path)?);
+        check_cert(&cert, &key).map_err(|e| format!("Can't use TLS cert {} with key {}: {}", tls.cert_path, tls.key_path, e))?;
+        Ok(LoadedCert { cert, key, fingerprint })
+    }
+}
+
+/// Whether rustls, and so warp, will take this PEM cert chain and key.
+fn check_cert(cert: &[u8], key: &[u8]) -> Result<(), String> {
+    let certs = pemfile::certs(&mut &cert[..]).map_err(|()| "the cert isn't PEM".to_string())?;
+    if certs.is_empty() {
+        return Err("there's no certificate in the cert file".into());
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut &key[..]).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut &key[..]).unwrap_or_default();
+    }
+    let key = keys.into_iter().next().ok_or("there's no PKCS#8 or RSA key in the key file")?;
+    ServerConfig::new(NoClientAuth::new())
+        .set_single_cert(certs, key)
+        .map_err(|e| e.to_string())
+}
+
+/// Enough about the cert and key files to notice when they've been replaced.
+#[derive(Debug, Clone, PartialEq)]
+struct CertFingerprint {
+    cert: (SystemTime, u64),
+    key: (SystemTime, u64),
+}
+
+impl CertFingerprint {
+    fn read(tls: &TlsSettings) -> Option<Self> {
+        let stat = |path: &str| {
+            let metadata = fs::metadata(path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        };
+
+        Some(CertFingerprint {
+            cert: stat(&tls.cert_path)?,
+            key: stat(&tls.key_path)?,
+        })
+    }
+}
+
+async fn reload_or_stop(
+    tls: TlsSettings,
+    loaded: CertFingerprint,
+    renewed: Arc<Mutex<Option<LoadedCert>>>,
+    stop: watch::Receiver<bool>,
+) {
+    tokio::select! {
+        cert = certificates_renewed(tls, loaded) => *renewed.lock().unwrap() = Some(cert),
+        _ = stopped(stop) => (),
+    }
+}
+
+/// Resolves with the new cert once the files differ from `loaded`, have stayed
+/// the same for a whole check interval (so we don't pick up a half-written
+/// renewal), and load.
+async fn certificates_renewed(tls: TlsSettings, loaded: CertFingerprint) -> LoadedCert {
+    if tls.reload_interval_secs == 0 {
+        return future::pending().await;
+    }
+
+    let mut ticks = interval(Duration::from_secs(tls.reload_interval_secs));
+    ticks.tick().await;
+    let mut rejected = Some(loaded.clone());
+    let mut last_seen = rejected.clone();
+
+    loop {
+        ticks.tick().await;
+        let current = CertFingerprint::read(&tls);
+        if current.is_some() && current != rejected && current == last_seen {
+            match LoadedCert::read(&tls) {
+                Ok(cert) => return cert,
+                Err(e) => {
+                    error!("{}, keeping the old one", e);
+                    rejected = current.clone();
+                }
+            }
+        }
+        last_seen = current;
+    }
+}
+
+/// Where to send a plain HTTP request for `host` and `path_and_query` over HTTPS.
+fn https_location(host: &str, path_and_query: &str, https_port: u16) -> Option<Uri> {
+    // Drop the plain HTTP port, if the client sent one (careful with IPv6 literals)
+    let hostname = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+
+    let authority = if https_port == 443 {
+        hostname.to_string()
+    } else {
+        format!("{}:{}", hostname, https_port)
+    };
+
+    format!("https://{}{}", authority, path_and_query).parse().ok()
+}
+
+fn redirect_to_https(https_port: u16) -> BoxedFilter<(impl Reply,)> {
+    let query = warp::query::raw()
+        .map(|q: String| format!("?{}", q))
+        .or(warp::any().map(String::new))
+        .unify();
+
+    warp::header::optional::<String>("host")
+        .and(warp::path::full())
+        .and(query)
+        .map(move |host: Option<String>, path: warp::path::FullPath, query: String| {
+            let path_and_query = format!("{}{}", path.as_str(), query);
+            match host.and_then(|host| https_location(&host, &path_and_query, https_port)) {
+                Some(location) => reply::with_status(
+                    reply::with_header(reply(), "location", location.to_string()),
+                    StatusCode::MOVED_PERMANENTLY,
+                )
+                .into_response(),
+                None => StatusCode::BAD_REQUEST.into_response(),
+            }
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::test::request;
+
+    #[test]
+    fn location_drops_the_http_port() {
+        assert_eq!(
+            https_location("example.com:80", "/trade?x=1", 443).unwrap(),
+            "https://example.com/trade?x=1"
+        );
+        assert_eq!(
+            https_location("example.com", "/trade", 3137).unwrap(),
+            "https://example.com:3137/trade"
+        );
+        assert_eq!(
+            https_location("[::1]:8080", "/", 3137).unwrap(),
+            "https://[::1]:3137/"
+        );
+    }
+
+    #[test]
+    fn it_refuses_certs_that_wont_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let tls = TlsSettings {
+            cert_path: dir.path().join("cert.pem").to_str().unwrap().into(),
+            key_path: dir.path().join("key.pem").to_str().unwrap().into(),
+            reload_interval_secs: 0,
+            redirect_port: None,
+        };
+        assert!(LoadedCert::read(&tls).unwrap_err().contains("Can't read TLS cert"));
+
+        fs::write(&tls.cert_path, "-----BEGIN CERTIFICATE-----\nnope\n-----END CERTIFICATE-----\n").unwrap();
+        fs::write(&tls.key_path, "not a key").unwrap();
+        assert!(LoadedCert::read(&tls).unwrap_err().contains("Can't use TLS cert"));
+    }
+
+    #[tokio::test]
+    async fn it_redirects_plain_http() {
+        let response = request()
+            .path("/trade")
+            .header("host", "example.com")
+            .reply(&redirect_to_https(3137))
+            .await;
+
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()["location"], "https://example.com:3137/trade");
+    }
+}
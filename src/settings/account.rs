@@ -0,0 +1,40 @@
+use serde::Deserialize;
+use super::Secret;
+
+/// Another 3commas account to mirror strategies to, with its own bots.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AccountSettings {
+    /// Like the top-level `email_token`, this can come from a file instead:
+    /// `email_token_file`, or `<name>_email_token` in $CREDENTIALS_DIRECTORY or /run/secrets
+    pub email_token: Secret,
+    pub email_token_file: Option<String>,
+    pub long_bot_id: u64,
+    pub short_bot_id: u64,
+    /// Scales deal sizes from the strategy's `sizing`. Deals that aren't sized
+    /// use the bot's base order size, whatever this says.
+    pub size_multiplier: f64,
+    /// A 3commas API key to reconcile this account with, if `reconcile`'s own
+    /// can't see its bots. `<name>_api_key` and `<name>_api_secret` work like
+    /// the email token.
+    pub api_key: Secret,
+    pub api_key_file: Option<String>,
+    pub api_secret: Secret,
+    pub api_secret_file: Option<String>,
+}
+
+impl Default for AccountSettings {
+    fn default() -> Self {
+        Self {
+            email_token: Secret::default(),
+            email_token_file: None,
+            long_bot_id: 0,
+            short_bot_id: 0,
+            size_multiplier: 1.0,
+            api_key: Secret::default(),
+            api_key_file: None,
+            api_secret: Secret::default(),
+            api_secret_file: None,
+        }
+    }
+}
@@ -0,0 +1,10 @@
+use serde::Deserialize;
+
+/// Where to tell a human that something needs their attention.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AlertSettings {
+    /// Alerts get POSTed here as `{"text": ..., "content": ...}`, which Slack
+    /// and Discord incoming webhooks both understand. They always go to the log.
+    pub webhook_url: Option<String>,
+}
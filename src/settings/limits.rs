@@ -0,0 +1,32 @@
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Limits on the webhooks we take, to stay up under a flood of them. See doc/responses.md.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LimitSettings {
+    /// Webhook bodies bigger than this get a 413
+    pub max_body_bytes: usize,
+    /// Signals a minute from any one address, with bursts of up to as many at once
+    pub max_signals_per_minute_per_ip: Option<u32>,
+    /// Signals a minute from everyone together, the same way
+    pub max_signals_per_minute: Option<u32>,
+    /// Signals being handled, or with requests still going out, at once
+    pub max_concurrent_signals: Option<usize>,
+    /// Where an `X-Real-IP` header is believed from. Anyone else could send
+    /// whatever they like in it, so their own address counts instead
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 64 * 1024,
+            max_signals_per_minute_per_ip: None,
+            max_signals_per_minute: None,
+            max_concurrent_signals: None,
+            // nginx on the same machine
+            trusted_proxies: vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)],
+        }
+    }
+}
@@ -0,0 +1,12 @@
+use serde::Deserialize;
+
+/// How log lines are written.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// One readable line per record
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
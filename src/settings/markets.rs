@@ -0,0 +1,33 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Where to get each market's currencies and the smallest steps it trades in.
+/// See doc/markets.md.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MarketSettings {
+    /// A `.json` or `.csv` file, or an `http://`/`https://` URL that returns JSON
+    pub source: String,
+    /// Where the list of markets is in the JSON, like `/symbols`. Defaults to
+    /// the whole document.
+    pub json_pointer: Option<String>,
+    /// How often to load them again. 0 only loads them at startup.
+    pub refresh_secs: u64,
+    /// How long loading from a URL can take, connecting included
+    pub timeout_secs: u64,
+    /// Tickers that are another market's, like `XBTUSD: BTCUSD`. With an
+    /// exchange prefix, like `BITMEX:XBTUSD`, only for that exchange's tickers.
+    pub symbols: HashMap<String, String>,
+}
+
+impl Default for MarketSettings {
+    fn default() -> Self {
+        Self {
+            source: String::new(),
+            json_pointer: None,
+            refresh_secs: 3600,
+            timeout_secs: 10,
+            symbols: HashMap::new(),
+        }
+    }
+}
@@ -0,0 +1,413 @@
+use config::{Config, ConfigError, Environment, File, FileFormat};
+use directories_next::BaseDirs;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    result::Result,
+    sync::{Mutex, RwLock, RwLockReadGuard},
+};
+pub mod account;
+pub use account::AccountSettings;
+pub mod alerts;
+pub use alerts::AlertSettings;
+pub mod limits;
+pub use limits::LimitSettings;
+pub mod logging;
+pub use logging::LogFormat;
+pub mod markets;
+pub use markets::MarketSettings;
+pub mod reconcile;
+pub use reconcile::ReconcileSettings;
+pub mod retry;
+pub use retry::RetrySettings;
+pub mod risk;
+pub use risk::{OnViolation, RiskSettings, TradingHours};
+pub mod sanity;
+pub use sanity::{ReferencePriceSettings, SanitySettings};
+pub mod rules;
+pub use rules::{RuleConditions, RuleSettings, RuleStep};
+pub mod schedule;
+pub use schedule::{BotCommand, ScheduleSettings};
+pub mod secret;
+pub use secret::Secret;
+pub mod shutdown;
+pub use shutdown::ShutdownSettings;
+pub mod sizing;
+pub use sizing::{SizingRule, SizingSettings};
+pub mod strategy;
+pub use strategy::{Direction, OverrideField, StrategySettings};
+pub mod tenant;
+pub use tenant::TenantSettings;
+pub mod tls;
+pub use tls::TlsSettings;
+pub mod tracing;
+pub use self::tracing::TracingSettings;
+pub mod webhook;
+pub use webhook::WebhookSettings;
+
+/// The strategy used for signals that don't match any other.
+pub const DEFAULT_STRATEGY: &str = "default";
+
+/// The account made of the top-level `email_token`, `long_bot_id` and `short_bot_id`.
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+/// Between a tenant's name and the name of one of their accounts or strategies,
+/// or a signal id, wherever they're kept alongside everyone else's: `alice:main`.
+pub const TENANT_SEPARATOR: char = ':';
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Settings {
+    /// More 3commas accounts, by name. Strategies pick which ones they trade on.
+    pub accounts: HashMap<String, AccountSettings>,
+    pub admin_token: Secret,
+    pub admin_token_file: Option<String>,
+    pub alerts: AlertSettings,
+    pub listen_address: IpAddr,
+    pub listen_port: u16,
+    /// How much we'll take from webhooks, and how fast
+    pub limits: LimitSettings,
+    pub tls: Option<TlsSettings>,
+    pub long_bot_id: u64,
+    pub short_bot_id: u64,
+    pub email_token: Secret,
+    pub email_token_file: Option<String>,
+    pub tradingview_api_ips: HashSet<String>,
+    pub log_path: String,
+    pub log_format: LogFormat,
+    pub data_path: String,
+    /// Where to keep signals, requests, positions and config changes in SQLite,
+    /// for querying and exporting. Defaults to `tradeproxy.db` in the data
+    /// directory, or nowhere when running tests. See doc/storage.md.
+    pub database_path: Option<String>,
+    /// The config file the settings were read from, if there was one
+    #[serde(skip)]
+    pub config_path: Option<PathBuf>,
+    /// What each market trades in and in what steps, for sanity checks and
+    /// sizing. Off without this section.
+    pub markets: Option<MarketSettings>,
+    /// Where to keep the journal of signals and what came of them. Defaults to
+    /// `journal.jsonl` in the data directory, or nowhere when running tests.
+    pub journal_path: Option<String>,
+    /// Where to keep each strategy's position between restarts. Defaults to
+    /// `positions.json` in the data directory, or nowhere when running tests.
+    pub positions_path: Option<String>,
+    pub request_server: String,
+    pub request_path: String,
+    /// Check tracked positions against the 3commas API. Off without this section.
+    pub reconcile: Option<ReconcileSettings>,
+    pub retry: RetrySettings,
+    /// Bot commands to run on a cron schedule, see doc/scheduling.md
+    pub schedules: Vec<ScheduleSettings>,
+    /// Where to keep delayed signals and scheduled commands until they're due.
+    /// Defaults to `schedule.json` in the data directory, or nowhere when running tests.
+    pub schedule_path: Option<String>,
+    /// Delayed signals that come due more than this late, say because we were
+    /// down, are dropped rather than trading on old news
+    pub schedule_max_lateness_secs: u64,
+    pub shutdown: ShutdownSettings,
+    pub strategies: HashMap<String, StrategySettings>,
+    /// Other people's trading, by name, each on their own webhook URL. See doc/tenants.md.
+    pub tenants: HashMap<String, TenantSettings>,
+    /// Which tenant these settings are for, if they're the ones from `for_tenant`
+    #[serde(skip)]
+    pub tenant: Option<String>,
+    /// Send traces of each signal's trip through here to an OpenTelemetry collector.
+    /// Off without this section.
+    pub tracing: Option<TracingSettings>,
+    /// Other places to send signals to, by name. Strategies pick which ones.
+    pub webhooks: HashMap<String, WebhookSettings>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            accounts: HashMap::new(),
+            admin_token: Secret::default(),
+            admin_token_file: None,
+            alerts: AlertSettings::default(),
+            email_token: "89abcdef-789a-bcde-f012-456789abcdef".into(),
+            email_token_file: None,
+            listen_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            listen_port: 3137,
+            limits: LimitSettings::default(),
+            log_path: ".".into(),
+            log_format: LogFormat::default(),
+            data_path: ".".into(),
+            database_path: None,
+            config_path: None,
+            markets: None,
+            journal_path: None,
+            positions_path: None,
+            long_bot_id: 1234567,
+            request_server: "https://3commas.io".into(),
+            request_path: "/trade_signal/trading_view".into(),
+            reconcile: None,
+            retry: RetrySettings::default(),
+            schedules: vec![],
+            schedule_path: None,
+            schedule_max_lateness_secs: 300,
+            short_bot_id: 7654321,
+            shutdown: ShutdownSettings::default(),
+            strategies: HashMap::new(),
+            tenants: HashMap::new(),
+            tenant: None,
+            tls: None,
+            tracing: None,
+            webhooks: HashMap::new(),
+            tradingview_api_ips: [
+                "52.89.214.238",
+                "34.212.75.30",
+                "54.218.53.128",
+                "52.32.178.7",
+            ].iter().cloned().map(String::from).collect(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn new() -> Result<Self, ConfigError> {
+        let mut s = Config::default();
+        let base_dirs = BaseDirs::new();
+
+        // On Linux, this will be ~/.config
+        let user_config_dir = get_user_config_dir(&base_dirs);
+
+        // Start off by merging in the "default" configuration file
+        // s.merge(File::with_name("config/default"))?;
+
+        // Add in the current environment file
+        // Default to 'development' env
+        // Note that this file is _optional_
+        // let env = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
+        // s.merge(File::with_name(&format!("config/{}", env)).required(false))?;
+
+        s.merge(File::with_name("config/default.yaml").required(false))?;
+
+        // Add in a local configuration file
+        // This file shouldn't be checked in to git
+        // AND it should only be loaded when we're not running unit tests -- in that case, we should use the defaults
+        let config_file = CONFIG_FILE.lock().unwrap().clone();
+        let tp_config_dir = if cfg!(test) {
+            None
+        } else if let Some(config_file) = &config_file {
+            Some(config_file.parent().map(Path::to_path_buf).unwrap_or_default())
+        } else if cfg!(debug_assertions) {
+            Some(user_config_dir.join("tradeproxy-dev"))
+        } else {
+            Some(user_config_dir.join("tradeproxy"))
+        };
+
+        eprintln!("Config dir: {:?}", tp_config_dir);
+
+        // If we've found a user config directory:
+        // - Set up the log path
+        // - Find the config file's path
+        // - Merge that file into self
+        let data_dir: String = match &tp_config_dir {
+            Some(config_dir) => config_dir.to_str().unwrap().into(),
+            None => ".".into(),
+        };
+
+        let mut config_path = None;
+        let log_dir: String = if let Some(config_dir) = &tp_config_dir {
+            // Load up the config file
+            let config_file_path = config_file.unwrap_or_else(|| config_dir.join("config.yaml"));
+            config_path = Some(config_file_path.clone());
+            s.merge(
+                File::from(config_file_path)
+                    .format(FileFormat::Yaml)
+                    .required(true),
+            )?;
+
+            // Return the log directory
+            config_dir.join("log").to_str().unwrap().into()
+        } else {
+            // If there's not user config directory, we must log...here
+            ".".into()
+        };
+
+        s.set("log_path", log_dir).unwrap();
+        if tp_config_dir.is_some() {
+            let data_file = |name: &str| Path::new(&data_dir).join(name).to_str().unwrap().to_string();
+            s.set_default("journal_path", data_file("journal.jsonl")).unwrap();
+            s.set_default("positions_path", data_file("positions.json")).unwrap();
+            s.set_default("schedule_path", data_file("schedule.json")).unwrap();
+            s.set_default("database_path", data_file("tradeproxy.db")).unwrap();
+        }
+        s.set_default("data_path", data_dir).unwrap();
+
+        // Add in settings from the environment (with a prefix of TP)
+        // Eg.. `TP_DEBUG=1 ./target/app` would set the `debug` key
+        s.merge(Environment::with_prefix("tp"))?;
+
+        // You can deserialize (and thus freeze) the entire configuration as
+        let mut settings: Settings = s.try_into()?;
+        settings.config_path = config_path;
+        settings.resolve_secrets()?;
+        if tp_config_dir.is_some() {
+            for (name, tenant) in settings.tenants.iter_mut() {
+                if tenant.journal_path.is_none() {
+                    let path = Path::new(&settings.data_path).join(format!("journal-{}.jsonl", name));
+                    tenant.journal_path = Some(path.to_str().unwrap().into());
+                }
+            }
+        }
+        Ok(settings)
+    }
+
+    /// The settings `name`'s signals see: only their own strategies, accounts
+    /// and webhooks, and no top-level account to fall back on.
+    pub fn for_tenant(&self, name: &str) -> Option<Settings> {
+        let tenant = self.tenants.get(name)?;
+        Some(Settings {
+            accounts: tenant.accounts.clone(),
+            admin_token: tenant.admin_token.clone(),
+            email_token: Secret::default(),
+            journal_path: tenant.journal_path.clone(),
+            reconcile: None,
+            schedules: vec![],
+            strategies: tenant.strategies.clone(),
+            tenants: HashMap::new(),
+            tenant: Some(name.into()),
+            webhooks: tenant.webhooks.clone(),
+            ..self.clone()
+        })
+    }
+
+    /// The tenant whose webhook URL has `token` in it.
+    pub fn tenant_with_token(&self, token: &str) -> Option<&str> {
+        self.tenants
+            .iter()
+            .find(|(_, tenant)| tenant.token.matches(token))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// `name` as it's kept alongside every tenant's: `alice:name` for a tenant's
+    /// settings, and just `name` for the top-level ones.
+    pub fn qualified(&self, name: &str) -> String {
+        match &self.tenant {
+            Some(tenant) => format!("{}{}{}", tenant, TENANT_SEPARATOR, name),
+            None => name.into(),
+        }
+    }
+
+    /// The settings for the strategy called `name`. Signals for strategies that
+    /// aren't configured (or that don't say) get the `default` strategy, if there
+    /// is one. If no strategies are configured at all, everything gets the defaults.
+    pub fn strategy(&self, name: Option<&str>) -> Option<StrategySettings> {
+        if self.strategies.is_empty() {
+            return Some(StrategySettings::default());
+        }
+
+        name.and_then(|name| self.strategies.get(name))
+            .or_else(|| self.strategies.get(DEFAULT_STRATEGY))
+            .cloned()
+    }
+
+    /// Swaps in secrets kept outside the config file (see `Secret::resolve`).
+    fn resolve_secrets(&mut self) -> Result<(), ConfigError> {
+        self.email_token = Secret::resolve(
+            "email_token",
+            &self.email_token,
+            self.email_token_file.as_deref(),
+        )
+        .map_err(|e| ConfigError::Message(format!("Can't read email_token: {}", e)))?;
+        self.admin_token = Secret::resolve(
+            "admin_token",
+            &self.admin_token,
+            self.admin_token_file.as_deref(),
+        )
+        .map_err(|e| ConfigError::Message(format!("Can't read admin_token: {}", e)))?;
+        resolve_account_secrets("", &mut self.accounts)?;
+        for (name, tenant) in self.tenants.iter_mut() {
+            tenant.token = Secret::resolve(
+                &format!("{}_token", name),
+                &tenant.token,
+                tenant.token_file.as_deref(),
+            )
+            .map_err(|e| ConfigError::Message(format!("Can't read tenant {}'s token: {}", name, e)))?;
+            tenant.admin_token = Secret::resolve(
+                &format!("{}_admin_token", name),
+                &tenant.admin_token,
+                tenant.admin_token_file.as_deref(),
+            )
+            .map_err(|e| ConfigError::Message(format!("Can't read tenant {}'s admin_token: {}", name, e)))?;
+            resolve_account_secrets(&format!("{}_", name), &mut tenant.accounts)?;
+        }
+        if let Some(reconcile) = &mut self.reconcile {
+            reconcile.api_key = Secret::resolve(
+                "api_key",
+                &reconcile.api_key,
+                reconcile.api_key_file.as_deref(),
+            )
+            .map_err(|e| ConfigError::Message(format!("Can't read reconcile.api_key: {}", e)))?;
+            reconcile.api_secret = Secret::resolve(
+                "api_secret",
+                &reconcile.api_secret,
+                reconcile.api_secret_file.as_deref(),
+            )
+            .map_err(|e| ConfigError::Message(format!("Can't read reconcile.api_secret: {}", e)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Swaps in the accounts' email tokens and API keys, from
+/// `<prefix><name>_email_token` and so on when they're kept outside the config file.
+fn resolve_account_secrets(prefix: &str, accounts: &mut HashMap<String, AccountSettings>) -> Result<(), ConfigError> {
+    for (name, account) in accounts.iter_mut() {
+        account.email_token = Secret::resolve(
+            &format!("{}{}_email_token", prefix, name),
+            &account.email_token,
+            account.email_token_file.as_deref(),
+        )
+        .map_err(|e| ConfigError::Message(format!("Can't read {}{}'s email_token: {}", prefix, name, e)))?;
+        account.api_key = Secret::resolve(
+            &format!("{}{}_api_key", prefix, name),
+            &account.api_key,
+            account.api_key_file.as_deref(),
+        )
+        .map_err(|e| ConfigError::Message(format!("Can't read {}{}'s api_key: {}", prefix, name, e)))?;
+        account.api_secret = Secret::resolve(
+            &format!("{}{}_api_secret", prefix, name),
+            &account.api_secret,
+            account.api_secret_file.as_deref(),
+        )
+        .map_err(|e| ConfigError::Message(format!("Can't read {}{}'s api_secret: {}", prefix, name, e)))?;
+    }
+    Ok(())
+}
+
+fn get_user_config_dir<'a>(base_dirs: &'a Option<BaseDirs>) -> &'a Path {
+    let user_config_dir: &'a Path = match &base_dirs {
+        Some(base_dirs) => base_dirs.config_dir(),
+        None => panic!("Can't find user config directory!"),
+    };
+    user_config_dir
+}
+
+lazy_static! {
+    static ref CONFIG_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Reads the settings from `path` instead of the config directory's
+/// `config.yaml`, with the data and logs beside it. Only works before the
+/// settings are first used.
+pub fn use_config_file(path: PathBuf) {
+    *CONFIG_FILE.lock().unwrap() = Some(path);
+}
+
+lazy_static! {
+    pub static ref SETTINGS: RwLock<Settings> = match Settings::new() {
+        Ok(s) => RwLock::new(s),
+        Err(e) => panic!("Error loading config: {:?}", e),
+    };
+}
+
+pub fn get_settings() -> RwLockReadGuard<'static, Settings> {
+    SETTINGS.read().unwrap()
+}
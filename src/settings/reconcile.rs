@@ -0,0 +1,33 @@
+use serde::Deserialize;
+use super::Secret;
+
+/// How to check our idea of the open deals against what 3commas says.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ReconcileSettings {
+    /// The 3commas API, as opposed to the signal endpoint in `request_server`
+    pub api_server: String,
+    /// A read-only API key is enough, unless `auto_close` is on
+    pub api_key: Secret,
+    pub api_key_file: Option<String>,
+    pub api_secret: Secret,
+    pub api_secret_file: Option<String>,
+    /// How often to check. 0 only checks once, at startup.
+    pub interval_secs: u64,
+    /// Close deals 3commas has open that we think should be closed
+    pub auto_close: bool,
+}
+
+impl Default for ReconcileSettings {
+    fn default() -> Self {
+        Self {
+            api_server: "https://api.3commas.io".into(),
+            api_key: Secret::default(),
+            api_key_file: None,
+            api_secret: Secret::default(),
+            api_secret_file: None,
+            interval_secs: 300,
+            auto_close: false,
+        }
+    }
+}
@@ -0,0 +1,20 @@
+use serde::Deserialize;
+
+/// How hard to try when 3commas or a webhook doesn't answer.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetrySettings {
+    /// Tries per request, counting the first one
+    pub attempts: u32,
+    /// How long to wait before the first retry. Doubles after each one.
+    pub backoff_ms: u64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff_ms: 1000,
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use chrono::{NaiveTime, Weekday};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+
+/// Limits on what a strategy's signals can do. Everything's off by default.
+/// See doc/risk.md. Saved with signals that are held back, to check them
+/// against when they're due.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RiskSettings {
+    /// At most this many signals per `window_secs`
+    pub max_signals: Option<usize>,
+    pub window_secs: u64,
+
+    /// At least this long between signals that open deals
+    pub min_secs_between_flips: Option<u64>,
+
+    /// At most this many of the strategy's bots with an open deal at once
+    pub max_open_deals: Option<usize>,
+
+    /// Only open deals in these hours (UTC)
+    pub trading_hours: Option<TradingHours>,
+
+    /// Stop opening deals for the rest of the (UTC) day once realized losses reach this
+    pub max_daily_loss: Option<f64>,
+
+    /// Reject signals that break a limit, or hold on to them until they wouldn't
+    pub on_violation: OnViolation,
+
+    /// The longest we'll hold on to a deferred signal
+    pub max_defer_secs: u64,
+}
+
+impl Default for RiskSettings {
+    fn default() -> Self {
+        Self {
+            max_signals: None,
+            window_secs: 3600,
+            min_secs_between_flips: None,
+            max_open_deals: None,
+            trading_hours: None,
+            max_daily_loss: None,
+            on_violation: OnViolation::default(),
+            max_defer_secs: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OnViolation {
+    #[default]
+    Reject,
+    Defer,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TradingHours {
+    /// `HH:MM`, UTC
+    #[serde(deserialize_with = "hours_and_minutes", serialize_with = "to_hours_and_minutes")]
+    pub start: NaiveTime,
+
+    /// `HH:MM`, UTC. Can be before `start`, for sessions that run past midnight.
+    #[serde(deserialize_with = "hours_and_minutes", serialize_with = "to_hours_and_minutes")]
+    pub end: NaiveTime,
+
+    /// The days `start` falls on, like `[Mon, Tue, Wed, Thu, Fri]`. Every day if left out.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+}
+
+fn hours_and_minutes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&s, "%H:%M").map_err(D::Error::custom)
+}
+
+fn to_hours_and_minutes<S: Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.format("%H:%M").to_string())
+}
@@ -0,0 +1,122 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::{convert::TryFrom, fmt};
+use crate::{incoming::SignalAction, outgoing::deal_and_bot_types::{ActionType, BotType}, schedule::MAX_DELAY_SECS};
+
+/// A regular expression from the config file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pattern(pub Regex);
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Regex::new(&pattern).map(Pattern)
+    }
+}
+
+/// What a signal has to look like for a rule to apply. Everything given has to
+/// match; a rule with no conditions matches every signal.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RuleConditions {
+    pub action: Option<SignalAction>,
+    /// `order.comment`, exactly
+    pub comment: Option<String>,
+    /// `order.id`, exactly
+    pub order_id: Option<String>,
+    /// Searched for anywhere in `order.alert_message`
+    pub alert_message: Option<Pattern>,
+    /// `flat`, `long` or `short`
+    pub market_position: Option<String>,
+    pub prev_market_position: Option<String>,
+}
+
+/// One thing a rule does. Written like `close_deal long`, `start_deal both`
+/// or `wait 30`.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub enum RuleStep {
+    /// A request to each of the bots, in order
+    Request { action: ActionType, bots: Vec<BotType> },
+    /// Holds every later request back this many more seconds. tradeproxy
+    /// schedules those requests itself, like a delayed signal's.
+    Wait(u64),
+}
+
+impl TryFrom<String> for RuleStep {
+    type Error = String;
+
+    fn try_from(step: String) -> Result<Self, Self::Error> {
+        let words: Vec<&str> = step.split_whitespace().collect();
+        let (verb, object) = match words.as_slice() {
+            [verb, object] => (*verb, *object),
+            _ => return Err(format!("Expected two words, like \"close_deal long\", got {:?}", step)),
+        };
+
+        let action = match verb {
+            "wait" => {
+                return match object.parse() {
+                    Ok(secs) if secs <= MAX_DELAY_SECS => Ok(RuleStep::Wait(secs)),
+                    Ok(secs) => Err(format!("Can't wait {}s, the most is {}s", secs, MAX_DELAY_SECS)),
+                    Err(_) => Err(format!("Can't wait {:?} seconds", object)),
+                }
+            }
+            "start_deal" => ActionType::StartDeal,
+            "close_deal" => ActionType::CloseDeal,
+            "start_bot" => ActionType::StartBot,
+            "stop_bot" => ActionType::StopBot,
+            _ => return Err(format!("Unknown step {:?}", verb)),
+        };
+        let bots = match object {
+            "long" => vec![BotType::Long],
+            "short" => vec![BotType::Short],
+            "both" => vec![BotType::Long, BotType::Short],
+            _ => return Err(format!("Expected long, short or both, got {:?}", object)),
+        };
+        Ok(RuleStep::Request { action, bots })
+    }
+}
+
+/// Turns signals that match `when` into the steps in `then`, instead of the
+/// usual requests for their action. See doc/rules.md.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RuleSettings {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub when: RuleConditions,
+    pub then: Vec<RuleStep>,
+}
+
+impl fmt::Display for RuleSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{:?}", name),
+            None => write!(f, "{:?}", self.when),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(step: &str) -> Result<RuleStep, String> {
+        RuleStep::try_from(step.to_string())
+    }
+
+    #[test]
+    fn it_reads_steps() {
+        assert_eq!(step("wait 30"), Ok(RuleStep::Wait(30)));
+        assert!(matches!(
+            step("close_deal  both"),
+            Ok(RuleStep::Request { action: ActionType::CloseDeal, bots }) if bots == [BotType::Long, BotType::Short]
+        ));
+        assert!(step("close_deal").is_err());
+        assert!(step("open_deal long").is_err());
+        assert!(step("start_deal sideways").is_err());
+        assert!(step("wait forever").is_err());
+        assert!(step(&format!("wait {}", u64::MAX)).is_err());
+    }
+}
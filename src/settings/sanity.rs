@@ -0,0 +1,42 @@
+use serde::Deserialize;
+
+/// Optional checks that a signal makes sense before we act on it. See doc/sanity_checks.md.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SanitySettings {
+    /// Reject signals whose `timenow` (or bar `time`) is older than this
+    pub max_age_secs: Option<u64>,
+
+    /// Reject signals whose `order.price` is further than this from `reference_price`
+    pub max_price_deviation_pct: Option<f64>,
+    pub reference_price: Option<ReferencePriceSettings>,
+
+    /// Reject signals whose `market_position` contradicts `order.action`
+    pub check_market_position: bool,
+
+    /// Reject signals with a negative `market_position_size` or `prev_market_position_size`
+    pub check_position_size: bool,
+
+    /// Reject signals for markets that aren't in `markets`, or whose `order.price`
+    /// isn't on the market's price tick
+    pub check_market: bool,
+}
+
+/// Where to get the going price for a ticker.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReferencePriceSettings {
+    /// `{ticker}` is replaced with the signal's ticker,
+    /// like `https://api.binance.com/api/v3/ticker/price?symbol={ticker}`
+    pub url: String,
+
+    /// Where the price is in the JSON response, like `/price`
+    pub json_pointer: String,
+
+    /// How long to wait for the price before failing the check
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+    5
+}
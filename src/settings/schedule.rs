@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+/// Something to do to the bots at a set time.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BotCommand {
+    StartBots,
+    StopBots,
+}
+
+/// A bot command to run on a cron schedule, like stopping the bots for the weekend.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct ScheduleSettings {
+    /// `minute hour day-of-month month day-of-week`, in UTC, like `0 21 * * Fri`.
+    /// A leading seconds field is fine too.
+    pub cron: String,
+    pub command: BotCommand,
+}
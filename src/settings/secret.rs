@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize, Serializer};
+use std::{
+    env,
+    fmt,
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Where Docker mounts secrets.
+const DOCKER_SECRETS_DIR: &str = "/run/secrets";
+
+/// A string that must never end up in the logs, like the 3commas email token.
+///
+/// `Debug` and `Display` print a placeholder. The only way to get at the real
+/// value is `expose()`, or serializing it into an outgoing request.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new<S: Into<String>>(value: S) -> Self {
+        Secret(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether `given` is this secret. Compares without bailing out at the first
+    /// difference, so response times don't give it away. Nothing matches an empty secret.
+    pub fn matches(&self, given: &str) -> bool {
+        !self.is_empty()
+            && given.len() == self.0.len()
+            && given
+                .bytes()
+                .zip(self.0.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Reads a secret from a file, dropping the trailing newline editors like to add.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(Secret::new(contents.trim_end_matches(&['\r', '\n'][..])))
+    }
+
+    /// Finds the value for the secret called `name`. In order of preference:
+    ///
+    /// 1. The file named by the `<name>_file` setting (`file`)
+    /// 2. `$CREDENTIALS_DIRECTORY/<name>`, as set up by systemd's `LoadCredential=`
+    /// 3. `/run/secrets/<name>`, where Docker puts secrets
+    /// 4. The inline value, from the config file or a `TP_<NAME>` env var
+    pub fn resolve(name: &str, inline: &Secret, file: Option<&str>) -> io::Result<Self> {
+        if let Some(path) = file {
+            return Secret::from_file(path);
+        }
+
+        if let Some(path) = credential_file(name) {
+            return Secret::from_file(path);
+        }
+
+        Ok(inline.clone())
+    }
+}
+
+fn credential_file(name: &str) -> Option<PathBuf> {
+    let systemd_dir = env::var_os("CREDENTIALS_DIRECTORY").map(PathBuf::from);
+    let docker_dir = Some(PathBuf::from(DOCKER_SECRETS_DIR));
+
+    systemd_dir
+        .into_iter()
+        .chain(docker_dir)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// Serializing is how the secret gets sent to 3commas, so this one *does* expose it.
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(s: &str) -> Self {
+        Secret::new(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn debug_and_display_are_redacted() {
+        let secret = Secret::from("hunter2");
+        assert!(!format!("{:?}", secret).contains("hunter2"));
+        assert!(!format!("{}", secret).contains("hunter2"));
+        assert_eq!(secret.expose(), "hunter2");
+    }
+
+    #[test]
+    fn matches_only_the_same_value() {
+        let secret = Secret::from("hunter2");
+        assert!(secret.matches("hunter2"));
+        assert!(!secret.matches("hunter3"));
+        assert!(!secret.matches("hunter"));
+        assert!(!Secret::default().matches(""));
+    }
+
+    #[test]
+    fn serializes_the_real_value() {
+        let secret = Secret::from("hunter2");
+        assert_eq!(serde_json::to_string(&secret).unwrap(), r#""hunter2""#);
+    }
+
+    #[test]
+    fn file_wins_over_inline_value() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "from-a-file").unwrap();
+        let path = file.path().to_str().unwrap();
+
+        let resolved = Secret::resolve("email_token", &Secret::from("inline"), Some(path)).unwrap();
+        assert_eq!(resolved.expose(), "from-a-file");
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        assert!(Secret::resolve("email_token", &Secret::default(), Some("/nonexistent/token")).is_err());
+    }
+}
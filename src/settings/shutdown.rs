@@ -0,0 +1,21 @@
+use serde::Deserialize;
+
+/// What to do on SIGINT/SIGTERM.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ShutdownSettings {
+    /// How long to wait for in-flight request sequences before saving them for the next start
+    pub drain_timeout_secs: u64,
+
+    /// Stop both bots on the way out
+    pub stop_bots_on_exit: bool,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self {
+            drain_timeout_secs: 30,
+            stop_bots_on_exit: false,
+        }
+    }
+}
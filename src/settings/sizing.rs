@@ -0,0 +1,102 @@
+use serde::Deserialize;
+use std::convert::TryFrom;
+
+/// How big a deal to ask 3commas for. Without one of these, every deal uses the
+/// bot's own base order size.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(try_from = "UncheckedSizing")]
+pub struct SizingSettings {
+    #[serde(flatten)]
+    pub rule: SizingRule,
+
+    /// Sizes below this are raised to it
+    #[serde(default)]
+    pub min: Option<f64>,
+
+    /// Sizes above this are lowered to it
+    #[serde(default)]
+    pub max: Option<f64>,
+
+    /// Signals asking for more than this (before `min`/`max`) are refused outright,
+    /// on the assumption that something upstream has gone wrong.
+    #[serde(default)]
+    pub refuse_above: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum SizingRule {
+    /// The signal's `order.contracts`, in the base currency
+    Contracts,
+    /// The signal's `order.contracts` times `factor`, in the base currency
+    Multiplier { factor: f64 },
+    /// The same amount of the quote currency every time
+    FixedQuote { amount: f64 },
+    /// `percent` of what a 3commas account holds of `currency`, which should
+    /// be the quote currency
+    PercentOfBalance { percent: f64, account_id: u64, currency: String },
+}
+
+impl SizingRule {
+    /// The 3commas account and currency whose balance this sizes by, if any.
+    pub fn balance(&self) -> Option<(u64, &str)> {
+        match self {
+            SizingRule::PercentOfBalance { account_id, currency, .. } => Some((*account_id, currency)),
+            _ => None,
+        }
+    }
+}
+
+/// `SizingSettings` as they're written, before they're checked.
+#[derive(Deserialize)]
+struct UncheckedSizing {
+    #[serde(flatten)]
+    rule: SizingRule,
+    #[serde(default)]
+    min: Option<f64>,
+    #[serde(default)]
+    max: Option<f64>,
+    #[serde(default)]
+    refuse_above: Option<f64>,
+}
+
+impl TryFrom<UncheckedSizing> for SizingSettings {
+    type Error = String;
+
+    fn try_from(sizing: UncheckedSizing) -> Result<Self, Self::Error> {
+        if let (Some(min), Some(max)) = (sizing.min, sizing.max) {
+            if min > max {
+                return Err(format!("sizing has a min of {}, over its max of {}", min, max));
+            }
+        }
+        if let SizingRule::PercentOfBalance { percent, .. } = sizing.rule {
+            if !(percent > 0.0 && percent <= 100.0) {
+                return Err(format!("sizing can't use {}% of the balance", percent));
+            }
+        }
+        Ok(SizingSettings {
+            rule: sizing.rule,
+            min: sizing.min,
+            max: sizing.max,
+            refuse_above: sizing.refuse_above,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sizing(json: &str) -> Result<SizingSettings, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn it_refuses_sizes_it_cant_keep_to() {
+        assert!(sizing(r#"{"rule": "contracts", "min": 1, "max": 10}"#).is_ok());
+        assert!(sizing(r#"{"rule": "contracts", "min": 10, "max": 1}"#).unwrap_err().contains("over its max"));
+        assert!(sizing(r#"{"rule": "percent_of_balance", "percent": 2.5, "account_id": 1, "currency": "USDT"}"#).is_ok());
+        assert!(sizing(r#"{"rule": "percent_of_balance", "percent": 0, "account_id": 1, "currency": "USDT"}"#).is_err());
+        assert!(sizing(r#"{"rule": "percent_of_balance", "percent": 250, "account_id": 1, "currency": "USDT"}"#).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use serde::Deserialize;
+use std::fmt;
+use crate::{incoming::SignalFormat, outgoing::deal_and_bot_types::BotType};
+use super::{RiskSettings, RuleSettings, SanitySettings, SizingSettings};
+
+/// Which of the bots a strategy trades with.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    #[default]
+    Both,
+    /// Never touch the short bot: sells just close the long deal
+    LongOnly,
+    /// Never touch the long bot: buys just close the short deal
+    ShortOnly,
+}
+
+impl Direction {
+    pub fn allows(&self, bot_type: &BotType) -> bool {
+        !matches!(
+            (self, bot_type),
+            (Direction::LongOnly, BotType::Short) | (Direction::ShortOnly, BotType::Long)
+        )
+    }
+}
+
+/// What a signal's `alert_message` can change about it, see doc/alert_message.md.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverrideField {
+    /// Send the signal to another strategy instead
+    Strategy,
+    Action,
+    Contracts,
+    StopLoss,
+    TakeProfit,
+    DelaySecs,
+    /// Plan the requests, but don't send them
+    DryRun,
+}
+
+impl fmt::Display for OverrideField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OverrideField::Strategy => "strategy",
+            OverrideField::Action => "action",
+            OverrideField::Contracts => "contracts",
+            OverrideField::StopLoss => "stop_loss",
+            OverrideField::TakeProfit => "take_profit",
+            OverrideField::DelaySecs => "delay_secs",
+            OverrideField::DryRun => "dry_run",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Per-strategy behaviour, keyed by the `strategy` name signals carry.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct StrategySettings {
+    /// Turn signals away without acting on them
+    pub paused: bool,
+
+    /// Which signal formats this strategy takes. All of them, unless you say otherwise.
+    pub formats: Vec<SignalFormat>,
+
+    pub direction: Direction,
+
+    /// Deal sizes, see doc/sizing.md
+    pub sizing: Option<SizingSettings>,
+
+    /// Limits on how much the strategy can trade, see doc/risk.md
+    pub risk: RiskSettings,
+
+    /// Checks for stale or inconsistent signals, see doc/sanity_checks.md
+    pub sanity: SanitySettings,
+
+    /// Names from the top-level `accounts` to trade on. `default` is the
+    /// top-level account, and the only one if this is left out.
+    pub accounts: Vec<String>,
+
+    /// Names from the top-level `webhooks` to send this strategy's signals to, see doc/webhooks.md
+    pub webhooks: Vec<String>,
+
+    /// Turn away a signal identical to one we had this recently, like an alert
+    /// that fired twice. Off without this.
+    pub dedup_window_secs: Option<u64>,
+
+    /// Hold each signal's requests this long before sending them, unless the
+    /// signal says otherwise. See doc/scheduling.md
+    pub delay_secs: Option<u64>,
+
+    /// What to do with signals that match, instead of the usual requests for
+    /// their action. The first rule that matches wins. See doc/rules.md
+    pub rules: Vec<RuleSettings>,
+
+    /// What a signal's `alert_message` may override. It isn't read at all
+    /// without this. See doc/alert_message.md
+    pub alert_message_overrides: Vec<OverrideField>,
+}
+
+impl Default for StrategySettings {
+    fn default() -> Self {
+        Self {
+            paused: false,
+            formats: SignalFormat::all(),
+            direction: Direction::default(),
+            sizing: None,
+            risk: RiskSettings::default(),
+            sanity: SanitySettings::default(),
+            accounts: vec![],
+            webhooks: vec![],
+            dedup_window_secs: None,
+            delay_secs: None,
+            rules: vec![],
+            alert_message_overrides: vec![],
+        }
+    }
+}
+
+impl StrategySettings {
+    pub fn accepts(&self, format: SignalFormat) -> bool {
+        self.formats.contains(&format)
+    }
+}
@@ -0,0 +1,27 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use super::{AccountSettings, Secret, StrategySettings, WebhookSettings};
+
+/// Someone else's trading, hosted alongside ours. Their signals come in on
+/// `/trade/<token>` and only ever see their own strategies, accounts and
+/// webhooks. See doc/tenants.md.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TenantSettings {
+    /// The secret part of the tenant's webhook URL. Can come from a file, like
+    /// the other secrets: `token_file`, or `<name>_token` in $CREDENTIALS_DIRECTORY or /run/secrets
+    pub token: Secret,
+    pub token_file: Option<String>,
+    /// Lets the tenant into `/admin`, to see their own signals, positions and jobs
+    pub admin_token: Secret,
+    pub admin_token_file: Option<String>,
+    pub accounts: HashMap<String, AccountSettings>,
+    pub strategies: HashMap<String, StrategySettings>,
+    pub webhooks: HashMap<String, WebhookSettings>,
+    /// Where to keep the tenant's journal. Defaults to `journal-<name>.jsonl`
+    /// in the data directory, or nowhere when running tests.
+    pub journal_path: Option<String>,
+    /// Turn away the tenant's signals beyond this many a minute, with bursts of
+    /// up to as many at once
+    pub max_signals_per_minute: Option<u32>,
+}
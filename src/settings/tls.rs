@@ -0,0 +1,24 @@
+use serde::Deserialize;
+
+/// Turns on native TLS for the listener. Without this, we speak plain HTTP and
+/// expect something like nginx in front of us.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsSettings {
+    /// PEM certificate chain
+    pub cert_path: String,
+
+    /// PEM private key (PKCS#8 or RSA)
+    pub key_path: String,
+
+    /// How often to check the cert and key for renewals. 0 turns reloading off.
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+
+    /// If set, also listen for plain HTTP on this port and redirect everything to HTTPS.
+    #[serde(default)]
+    pub redirect_port: Option<u16>,
+}
+
+fn default_reload_interval_secs() -> u64 {
+    3600
+}
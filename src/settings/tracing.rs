@@ -0,0 +1,19 @@
+use serde::Deserialize;
+
+/// Where to send traces. Tracing is off without this section.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct TracingSettings {
+    /// An OpenTelemetry collector's OTLP/gRPC endpoint
+    pub otlp_endpoint: String,
+    pub service_name: String,
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: "http://localhost:4317".into(),
+            service_name: "tradeproxy".into(),
+        }
+    }
+}
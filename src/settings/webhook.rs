@@ -0,0 +1,29 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Somewhere other than 3commas to send signals to. The URL, header values and
+/// body are templates, see doc/webhooks.md.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct WebhookSettings {
+    pub url: String,
+    pub method: String,
+    pub headers: HashMap<String, String>,
+    /// Sent as is after filling in, so say what it is with a `Content-Type` header
+    pub body: Option<String>,
+    /// Send it again after a server error or timeout, which is only safe if
+    /// the other end can take the same signal twice
+    pub idempotent: bool,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            method: "POST".into(),
+            headers: HashMap::new(),
+            body: None,
+            idempotent: false,
+        }
+    }
+}
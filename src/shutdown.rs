@@ -0,0 +1,374 @@
+use crate::{
+    journal::{self, JournalEvent},
+    outgoing::{sequence::Sequence, OutgoingRequest},
+    settings::{get_settings, DEFAULT_STRATEGY},
+};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use tokio::{
+    sync::watch,
+    time::{sleep, timeout, Duration},
+};
+
+/// The name of the file unfinished sequences are saved to, in the data directory.
+const PENDING_FILE: &str = "pending_requests.json";
+
+/// What's left of one sequence, and whether its next request is on its way.
+struct Running {
+    remaining: Sequence,
+    sending: bool,
+}
+
+/// Keeps track of the request sequences that are still running, so we can let
+/// them finish (or save them for later) when we're asked to shut down.
+pub struct InFlight {
+    accepting: AtomicBool,
+    next_id: AtomicU64,
+    sequences: Mutex<HashMap<u64, Running>>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        InFlight {
+            accepting: AtomicBool::new(true),
+            next_id: AtomicU64::new(0),
+            sequences: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// False once shutdown has started. New signals should be turned away.
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::SeqCst)
+    }
+
+    pub fn stop_accepting(&self) {
+        self.accepting.store(false, Ordering::SeqCst);
+    }
+
+    /// Registers a new sequence and returns its id.
+    pub fn begin(&self, sequence: &Sequence) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let running = Running { remaining: sequence.clone(), sending: false };
+        self.sequences.lock().unwrap().insert(id, running);
+        id
+    }
+
+    /// Marks the first remaining request or webhook of sequence `id` as being
+    /// sent. False if a drain gave up on the sequence and saved what's left of
+    /// it for the next start, in which case nothing more of it should be sent.
+    pub fn sending(&self, id: u64) -> bool {
+        match self.sequences.lock().unwrap().get_mut(&id) {
+            Some(running) => {
+                running.sending = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Marks the first remaining request or webhook of sequence `id` as done.
+    pub fn advance(&self, id: u64) {
+        if let Some(running) = self.sequences.lock().unwrap().get_mut(&id) {
+            running.remaining.pop_front();
+            running.sending = false;
+        }
+    }
+
+    pub fn finish(&self, id: u64) {
+        self.sequences.lock().unwrap().remove(&id);
+    }
+
+    /// True if a running sequence still has a request for `bot_id` on `account`
+    /// to send, or is sending one.
+    pub fn is_busy_with(&self, account: &str, bot_id: u64) -> bool {
+        self.sequences.lock().unwrap().values().any(|running| {
+            running.remaining.account == account
+                && running.remaining.requests.iter().any(|request| request.bot_id == bot_id)
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.sequences.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits up to `limit` for every sequence to finish, and returns whatever
+    /// didn't make it. A request that was on its way may have gone through, so
+    /// it's journaled as uncertain and left out rather than sent twice.
+    pub async fn drain(&self, limit: Duration) -> Vec<Sequence> {
+        let all_done = async {
+            while !self.is_empty() {
+                sleep(Duration::from_millis(100)).await;
+            }
+        };
+
+        if timeout(limit, all_done).await.is_err() {
+            warn!("Timed out waiting for {} request sequence(s) to finish", self.len());
+        }
+
+        self.sequences
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, running)| {
+                let mut remaining = running.remaining;
+                if running.sending {
+                    give_up_on_next(&mut remaining);
+                }
+                remaining
+            })
+            .filter(|sequence| !sequence.is_empty())
+            .collect()
+    }
+}
+
+/// Drops the request or webhook `sequence` was in the middle of sending.
+fn give_up_on_next(sequence: &mut Sequence) {
+    match sequence.requests.first() {
+        Some(request) => {
+            warn!(
+                "{:?} request to bot {} for signal {} was interrupted, and may have gone through. Not resending it",
+                request.action, request.bot_id, sequence.signal_id
+            );
+            journal::record(&sequence.signal_id, JournalEvent::RequestExecuted {
+                account: sequence.account.clone(),
+                action: format!("{:?}", request.action),
+                bot_id: request.bot_id,
+                status: None,
+                latency_ms: 0,
+                error: Some("Interrupted by shutdown, it may have gone through".into()),
+                attempts: 0,
+            });
+        }
+        None => {
+            if let Some(webhook) = sequence.webhooks.first() {
+                warn!("Webhook {} for signal {} was interrupted. Not resending it", webhook.name, sequence.signal_id);
+            }
+        }
+    }
+    sequence.pop_front();
+}
+
+lazy_static! {
+    pub static ref IN_FLIGHT: InFlight = InFlight::new();
+}
+
+/// Resolves on SIGINT or SIGTERM.
+pub async fn signal_received() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).expect("Can't listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => info!("Got SIGINT"),
+            _ = sigterm.recv() => info!("Got SIGTERM"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Got Ctrl-C");
+    }
+}
+
+/// Resolves once `stop` has been flipped to true.
+pub async fn stopped(mut stop: watch::Receiver<bool>) {
+    while !*stop.borrow() {
+        if stop.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+fn pending_path() -> PathBuf {
+    PathBuf::from(&get_settings().data_path).join(PENDING_FILE)
+}
+
+/// Lets in-flight sequences finish, and saves the ones that don't finish in time.
+pub async fn drain_in_flight() {
+    let drain_timeout = Duration::from_secs(get_settings().shutdown.drain_timeout_secs);
+    info!(
+        "Waiting up to {:?} for {} request sequence(s) to finish...",
+        drain_timeout,
+        IN_FLIGHT.len()
+    );
+
+    let unfinished = IN_FLIGHT.drain(drain_timeout).await;
+    if unfinished.is_empty() {
+        info!("All request sequences finished");
+        return;
+    }
+
+    match save_pending(&unfinished) {
+        Ok(path) => warn!(
+            "Saved {} unfinished request sequence(s) to {:?}, they'll resume on the next start",
+            unfinished.len(),
+            path
+        ),
+        Err(e) => error!(
+            "Couldn't save unfinished request sequences ({}), they're lost: {:?}",
+            e, unfinished
+        ),
+    }
+}
+
+fn save_pending(unfinished: &[Sequence]) -> io::Result<PathBuf> {
+    let path = pending_path();
+    let json = serde_json::to_string_pretty(unfinished)?;
+    write_private(&path, json.as_bytes())?;
+    Ok(path)
+}
+
+/// Writes `contents` to `path` so only we can read it, from before the first
+/// byte goes in, for files with email tokens in them.
+pub fn write_private(path: &std::path::Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+
+    // The mode only applies to new files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)
+}
+
+/// What's in the pending file. Older versions saved bare lists of requests,
+/// without the signal or strategy they were for.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Pending {
+    Sequences(Vec<Sequence>),
+    Requests(Vec<Vec<OutgoingRequest>>),
+}
+
+fn parse_pending(json: &str) -> serde_json::Result<Vec<Sequence>> {
+    Ok(match serde_json::from_str(json)? {
+        Pending::Sequences(sequences) => sequences,
+        Pending::Requests(lists) => lists
+            .into_iter()
+            .map(|requests| Sequence::new(&journal::new_signal_id(None), DEFAULT_STRATEGY, requests))
+            .collect(),
+    })
+}
+
+/// Takes the sequences saved by the last shutdown, if there are any.
+pub fn take_pending() -> Vec<Sequence> {
+    let path = pending_path();
+    let json = match fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(_) => return vec![],
+    };
+
+    let pending = match parse_pending(&json) {
+        Ok(pending) => pending,
+        Err(e) => {
+            error!("Can't read unfinished request sequences from {:?}: {}", path, e);
+            return vec![];
+        }
+    };
+
+    if let Err(e) = fs::remove_file(&path) {
+        error!("Can't remove {:?}, its requests may run twice: {}", path, e);
+    }
+    pending
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outgoing::{OutgoingRequest, deal_and_bot_types::{ActionType, BotType}};
+
+    fn sequence() -> Sequence {
+        Sequence::new("abc", "default", vec![
+            OutgoingRequest::new((ActionType::CloseDeal, BotType::Short)),
+            OutgoingRequest::new((ActionType::StartDeal, BotType::Long)),
+        ])
+    }
+
+    #[tokio::test]
+    async fn drain_returns_the_unfinished_part() {
+        let in_flight = InFlight::new();
+        let id = in_flight.begin(&sequence());
+        in_flight.advance(id);
+
+        let unfinished = in_flight.drain(Duration::from_millis(10)).await;
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].signal_id, "abc");
+        assert_eq!(unfinished[0].requests.len(), 1);
+        assert!(ActionType::is_start(&unfinished[0].requests[0].action));
+    }
+
+    #[tokio::test]
+    async fn drain_leaves_out_what_was_being_sent() {
+        let in_flight = InFlight::new();
+        let id = in_flight.begin(&sequence());
+        assert!(in_flight.sending(id));
+
+        let unfinished = in_flight.drain(Duration::from_millis(10)).await;
+        assert_eq!(unfinished[0].requests.len(), 1);
+        assert!(ActionType::is_start(&unfinished[0].requests[0].action));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_keeps_private_files_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pending_requests.json");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, b"[]").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[]");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn it_reads_pending_files_from_older_versions() {
+        let sequences = serde_json::to_string(&[sequence()]).unwrap();
+        let pending = parse_pending(&sequences).unwrap();
+        assert_eq!(pending[0].signal_id, "abc");
+        assert_eq!(pending[0].requests.len(), 2);
+
+        let requests = serde_json::to_string(&[sequence().requests]).unwrap();
+        let pending = parse_pending(&requests).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].strategy, DEFAULT_STRATEGY);
+        assert_eq!(pending[0].requests.len(), 2);
+
+        assert!(parse_pending("[]").unwrap().is_empty());
+        assert!(parse_pending("{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn drain_returns_nothing_when_everything_finished() {
+        let in_flight = InFlight::new();
+        let id = in_flight.begin(&sequence());
+        in_flight.finish(id);
+
+        assert!(in_flight.drain(Duration::from_secs(5)).await.is_empty());
+    }
+}
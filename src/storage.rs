@@ -0,0 +1,787 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use log::{error, info};
+use rusqlite::{params, types::ValueRef, Connection, OptionalExtension, NO_PARAMS};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{mpsc, Mutex},
+    thread,
+    time::Duration,
+};
+use crate::{
+    dedup::Seen,
+    journal::{tenant_of, JournalEntry, JournalEvent},
+    positions::AccountPositions,
+    schedule::Job,
+    settings::{get_settings, Settings},
+};
+
+/// Each step from an empty database to the current schema. `user_version`
+/// says how many have run. Only ever add to the end.
+const MIGRATIONS: &[&str] = &[
+    // 1: signals, what went out for them, positions and config changes
+    "CREATE TABLE signals (
+        id TEXT PRIMARY KEY,
+        tenant TEXT,
+        strategy TEXT NOT NULL,
+        format TEXT NOT NULL,
+        received_at TEXT NOT NULL,
+        signal TEXT NOT NULL,
+        status TEXT NOT NULL,
+        reason TEXT
+    );
+    CREATE INDEX signals_by_time ON signals (received_at);
+
+    CREATE TABLE requests (
+        id INTEGER PRIMARY KEY,
+        signal_id TEXT NOT NULL,
+        account TEXT NOT NULL,
+        action TEXT NOT NULL,
+        bot_id INTEGER NOT NULL,
+        request TEXT NOT NULL,
+        planned_at TEXT NOT NULL,
+        sent_at TEXT,
+        attempts INTEGER,
+        status INTEGER,
+        latency_ms INTEGER,
+        error TEXT
+    );
+    CREATE INDEX requests_by_signal ON requests (signal_id);
+    CREATE INDEX requests_by_time ON requests (planned_at);
+
+    CREATE TABLE webhook_calls (
+        id INTEGER PRIMARY KEY,
+        signal_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        method TEXT NOT NULL,
+        url TEXT NOT NULL,
+        sent_at TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        status INTEGER,
+        latency_ms INTEGER NOT NULL,
+        error TEXT
+    );
+    CREATE INDEX webhook_calls_by_signal ON webhook_calls (signal_id);
+    CREATE INDEX webhook_calls_by_time ON webhook_calls (sent_at);
+
+    CREATE TABLE positions (
+        account TEXT NOT NULL,
+        strategy TEXT NOT NULL,
+        position TEXT NOT NULL,
+        updated_at TEXT NOT NULL,
+        PRIMARY KEY (account, strategy)
+    );
+
+    CREATE TABLE config_audit (
+        id INTEGER PRIMARY KEY,
+        loaded_at TEXT NOT NULL,
+        version TEXT NOT NULL,
+        path TEXT NOT NULL,
+        sha256 TEXT NOT NULL
+    );",
+    // 2: scheduled jobs, to read back with the positions at startup
+    "CREATE TABLE scheduled_jobs (
+        id TEXT PRIMARY KEY,
+        run_at TEXT NOT NULL,
+        job TEXT NOT NULL
+    );",
+    // 3: signals dedup is holding repeats of off for, to read back at startup
+    "CREATE TABLE dedup (
+        fingerprint TEXT PRIMARY KEY,
+        signal_id TEXT NOT NULL,
+        seen_at TEXT NOT NULL,
+        until TEXT NOT NULL
+    );",
+];
+
+/// The tables `export` knows, and the column each is filtered by time on.
+const TABLES: [(&str, &str); 5] = [
+    ("signals", "received_at"),
+    ("requests", "planned_at"),
+    ("webhook_calls", "sent_at"),
+    ("positions", "updated_at"),
+    ("config_audit", "loaded_at"),
+];
+
+/// Times are kept as fixed-width RFC 3339 text, so they sort as they compare.
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Signals, the requests and webhook calls they led to, positions, scheduled
+/// jobs, dedup's recent signals and config changes, in SQLite. The journal
+/// stays the full record of signals; positions and jobs are read back from
+/// here when their files aren't there, and dedup's signals always are.
+pub struct Storage {
+    connection: Mutex<Connection>,
+}
+
+impl Storage {
+    /// Opens the database at `path`, creating it if need be, and brings its
+    /// schema up to date. Only we can read it, since signals and scheduled
+    /// webhooks can have tokens in them.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        create_private(path).map_err(|e| format!("Can't create {:?}: {}", path, e))?;
+        let connection = Connection::open(path).map_err(|e| e.to_string())?;
+        // The CLI can read while the server writes
+        connection
+            .busy_timeout(Duration::from_secs(5))
+            .and_then(|_| connection.query_row("PRAGMA journal_mode = WAL", NO_PARAMS, |_| Ok(())))
+            .map_err(|e| e.to_string())?;
+        Storage::with_connection(connection)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self, String> {
+        Storage::with_connection(Connection::open_in_memory().map_err(|e| e.to_string())?)
+    }
+
+    fn with_connection(mut connection: Connection) -> Result<Self, String> {
+        migrate(&mut connection)?;
+        Ok(Storage { connection: Mutex::new(connection) })
+    }
+
+    /// Files a journal entry under the tables it belongs in. Entries that
+    /// only the journal keeps, like planned exits, are left out.
+    pub fn record(&self, entry: &JournalEntry) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        let at = timestamp(entry.at);
+        let id = &entry.signal_id;
+        let set_status = |status: &str, reason: Option<&str>| {
+            connection
+                .execute(
+                    "UPDATE signals SET status = ?2, reason = ?3 WHERE id = ?1",
+                    params![id, status, reason],
+                )
+                .map(|_| ())
+        };
+
+        match &entry.event {
+            JournalEvent::SignalReceived { strategy, format, signal, .. } => {
+                let format = serde_json::to_value(format).unwrap_or_default();
+                connection.execute(
+                    "INSERT OR REPLACE INTO signals (id, tenant, strategy, format, received_at, signal, status)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, 'received')",
+                    params![id, tenant_of(id), strategy, format.as_str(), at, signal.to_string()],
+                )?;
+            }
+            JournalEvent::SignalRejected { reason } => set_status("rejected", Some(reason))?,
+            JournalEvent::SignalDeferred { reason, .. } => set_status("deferred", Some(reason))?,
+            JournalEvent::SignalScheduled { .. } => set_status("scheduled", None)?,
+            JournalEvent::SignalDryRun => set_status("dry_run", None)?,
+            JournalEvent::SequencePlanned(plan) => {
+                set_status("planned", None)?;
+                for request in &plan.requests {
+                    connection.execute(
+                        "INSERT INTO requests (signal_id, account, action, bot_id, request, planned_at)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        params![
+                            id,
+                            plan.account,
+                            request.action,
+                            request.bot_id as i64,
+                            serde_json::to_string(request).unwrap_or_default(),
+                            at
+                        ],
+                    )?;
+                }
+            }
+            JournalEvent::RequestExecuted { account, action, bot_id, status, latency_ms, error, attempts } => {
+                // Goes with the first of the signal's planned requests that hasn't been sent
+                let planned: Option<i64> = connection
+                    .query_row(
+                        "SELECT id FROM requests
+                         WHERE signal_id = ?1 AND account = ?2 AND action = ?3 AND bot_id = ?4 AND sent_at IS NULL
+                         ORDER BY id LIMIT 1",
+                        params![id, account, action, *bot_id as i64],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let planned = match planned {
+                    Some(planned) => planned,
+                    None => {
+                        connection.execute(
+                            "INSERT INTO requests (signal_id, account, action, bot_id, request, planned_at)
+                             VALUES (?1, ?2, ?3, ?4, 'null', ?5)",
+                            params![id, account, action, *bot_id as i64, at],
+                        )?;
+                        connection.last_insert_rowid()
+                    }
+                };
+                connection.execute(
+                    "UPDATE requests SET sent_at = ?2, attempts = ?3, status = ?4, latency_ms = ?5, error = ?6
+                     WHERE id = ?1",
+                    params![planned, at, attempts, status, *latency_ms as i64, error],
+                )?;
+            }
+            JournalEvent::WebhookExecuted { name, method, url, status, latency_ms, error, attempts } => {
+                connection.execute(
+                    "INSERT INTO webhook_calls (signal_id, name, method, url, sent_at, attempts, status, latency_ms, error)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![id, name, method, url, at, attempts, status, *latency_ms as i64, error],
+                )?;
+            }
+            JournalEvent::ExitsPlanned { .. } | JournalEvent::PositionDiverged { .. } => (),
+        }
+        Ok(())
+    }
+
+    /// Brings the positions table in line with `positions`, touching only the
+    /// rows that changed.
+    pub fn save_positions(&self, positions: &HashMap<String, AccountPositions>, now: DateTime<Utc>) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        for (account, strategies) in positions {
+            for (strategy, position) in strategies {
+                let position = serde_json::to_value(position).unwrap_or_default();
+                transaction.execute(
+                    "INSERT INTO positions (account, strategy, position, updated_at) VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (account, strategy) DO UPDATE
+                     SET position = excluded.position, updated_at = excluded.updated_at
+                     WHERE position != excluded.position",
+                    params![account, strategy, position.as_str(), timestamp(now)],
+                )?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /// The positions last saved, or nothing if none ever were.
+    pub fn load_positions(&self) -> rusqlite::Result<Option<HashMap<String, AccountPositions>>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT account, strategy, position FROM positions")?;
+        let mut rows = statement.query(NO_PARAMS)?;
+        let mut positions: HashMap<String, AccountPositions> = HashMap::new();
+        let mut any = false;
+        while let Some(row) = rows.next()? {
+            let (account, strategy, position): (String, String, String) = (row.get(0)?, row.get(1)?, row.get(2)?);
+            match serde_json::from_value(Value::String(position)) {
+                Ok(position) => {
+                    positions.entry(account).or_default().insert(strategy, position);
+                }
+                Err(e) => error!("Skipping {:?}'s position on {:?} in the database: {}", strategy, account, e),
+            }
+            any = true;
+        }
+        Ok(if any { Some(positions) } else { None })
+    }
+
+    /// Makes `jobs` the scheduled jobs.
+    pub fn save_jobs(&self, jobs: &[Job]) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM scheduled_jobs", NO_PARAMS)?;
+        for job in jobs {
+            transaction.execute(
+                "INSERT INTO scheduled_jobs (id, run_at, job) VALUES (?1, ?2, ?3)",
+                params![job.id, timestamp(job.run_at), serde_json::to_string(job).unwrap_or_default()],
+            )?;
+        }
+        transaction.commit()
+    }
+
+    /// The scheduled jobs last saved, soonest first. Ones that don't make
+    /// sense to this version are dropped.
+    pub fn load_jobs(&self) -> rusqlite::Result<Vec<Job>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT id, job FROM scheduled_jobs ORDER BY run_at")?;
+        let mut rows = statement.query(NO_PARAMS)?;
+        let mut jobs = vec![];
+        while let Some(row) = rows.next()? {
+            let (id, job): (String, String) = (row.get(0)?, row.get(1)?);
+            match serde_json::from_str(&job) {
+                Ok(job) => jobs.push(job),
+                Err(e) => error!("Dropping scheduled job {:?} from the database: {}", id, e),
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// Keeps `seen` under `fingerprint` until it runs out, and forgets any
+    /// that already have.
+    pub fn save_seen(&self, fingerprint: &str, seen: &Seen) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM dedup WHERE until <= ?1", params![timestamp(seen.at)])?;
+        transaction.execute(
+            "INSERT OR REPLACE INTO dedup (fingerprint, signal_id, seen_at, until) VALUES (?1, ?2, ?3, ?4)",
+            params![fingerprint, seen.signal_id, timestamp(seen.at), timestamp(seen.until)],
+        )?;
+        transaction.commit()
+    }
+
+    /// The signals dedup is still holding repeats of off for at `now`, by fingerprint.
+    pub fn load_seen(&self, now: DateTime<Utc>) -> rusqlite::Result<HashMap<String, Seen>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT fingerprint, signal_id, seen_at, until FROM dedup WHERE until > ?1")?;
+        let mut rows = statement.query(params![timestamp(now)])?;
+        let mut seen = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let (fingerprint, signal_id, at, until): (String, String, String, String) =
+                (row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?);
+            match (at.parse(), until.parse()) {
+                (Ok(at), Ok(until)) => {
+                    seen.insert(fingerprint, Seen { signal_id, at, until });
+                }
+                _ => error!("Skipping {:?} from the dedup table, its times don't parse", signal_id),
+            }
+        }
+        Ok(seen)
+    }
+
+    /// Notes the config file we started with, if it's changed since the last
+    /// time, or we're a different version. True if it had.
+    pub fn audit_config(&self, path: &Path, version: &str, now: DateTime<Utc>) -> Result<bool, String> {
+        let contents = fs::read(path).map_err(|e| format!("Can't read {:?}: {}", path, e))?;
+        let sha256 = hex::encode(Sha256::digest(&contents));
+        let connection = self.connection.lock().unwrap();
+        let last: Option<(String, String)> = connection
+            .query_row(
+                "SELECT version, sha256 FROM config_audit ORDER BY id DESC LIMIT 1",
+                NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+        if last.is_some_and(|(last_version, last_sha256)| last_version == version && last_sha256 == sha256) {
+            return Ok(false);
+        }
+
+        connection
+            .execute(
+                "INSERT INTO config_audit (loaded_at, version, path, sha256) VALUES (?1, ?2, ?3, ?4)",
+                params![timestamp(now), version, path.to_string_lossy(), sha256],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+
+    /// Writes `table`'s rows from `since` up to `until` to `out`, one JSON
+    /// object per line, oldest first. Says how many there were.
+    pub fn export(
+        &self,
+        table: &str,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        out: &mut dyn Write,
+    ) -> Result<usize, String> {
+        let time_column = TABLES
+            .iter()
+            .find(|(name, _)| *name == table)
+            .map(|(_, time_column)| time_column)
+            .ok_or_else(|| format!("There's no {:?} table to export", table))?;
+
+        let connection = self.connection.lock().unwrap();
+        let sql = format!(
+            "SELECT * FROM {table} WHERE (?1 IS NULL OR {time} >= ?1) AND (?2 IS NULL OR {time} < ?2) ORDER BY {time}",
+            table = table,
+            time = time_column
+        );
+        let mut statement = connection.prepare(&sql).map_err(|e| e.to_string())?;
+        let columns: Vec<String> = statement.column_names().into_iter().map(String::from).collect();
+        let mut rows = statement
+            .query(params![since.map(timestamp), until.map(timestamp)])
+            .map_err(|e| e.to_string())?;
+
+        let mut count = 0;
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            let mut object = Map::new();
+            for (n, column) in columns.iter().enumerate() {
+                let value = match row.get_raw_checked(n).map_err(|e| e.to_string())? {
+                    ValueRef::Null => Value::Null,
+                    ValueRef::Integer(i) => i.into(),
+                    ValueRef::Real(f) => f.into(),
+                    // Signals and requests are kept as JSON, and come back out as it
+                    ValueRef::Text(text) => {
+                        let text = String::from_utf8_lossy(text);
+                        match column.as_str() {
+                            "signal" | "request" => serde_json::from_str(&text).unwrap_or(Value::String(text.into())),
+                            _ => Value::String(text.into()),
+                        }
+                    }
+                    ValueRef::Blob(blob) => hex::encode(blob).into(),
+                };
+                object.insert(column.clone(), value);
+            }
+            writeln!(out, "{}", Value::Object(object)).map_err(|e| e.to_string())?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Deletes signals received before `before`, with their requests and
+    /// webhook calls, and any others from before then. Positions and the
+    /// config audit are kept. Says how many rows went.
+    pub fn prune(&self, before: DateTime<Utc>) -> rusqlite::Result<usize> {
+        let before = timestamp(before);
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let mut deleted = 0;
+        for (table, time_column) in &[("requests", "planned_at"), ("webhook_calls", "sent_at")] {
+            deleted += transaction.execute(
+                &format!(
+                    "DELETE FROM {table} WHERE {time} < ?1
+                     OR signal_id IN (SELECT id FROM signals WHERE received_at < ?1)",
+                    table = table,
+                    time = time_column
+                ),
+                params![before],
+            )?;
+        }
+        deleted += transaction.execute("DELETE FROM signals WHERE received_at < ?1", params![before])?;
+        transaction.commit()?;
+        Ok(deleted)
+    }
+}
+
+/// Creates the file at `path` if it isn't there, and makes it ours alone.
+/// SQLite gives its WAL files the same mode.
+fn create_private(path: &Path) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path)?;
+
+    // The mode only applies to new files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+/// Runs the migrations the database hasn't had yet, each in its own transaction.
+fn migrate(connection: &mut Connection) -> Result<(), String> {
+    let version: i64 = connection
+        .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+    if version as usize > MIGRATIONS.len() {
+        return Err(format!(
+            "The database is at version {}, newer than this tradeproxy knows ({})",
+            version,
+            MIGRATIONS.len()
+        ));
+    }
+
+    for (n, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let migrated = connection.transaction().and_then(|transaction| {
+            transaction.execute_batch(migration)?;
+            transaction.pragma_update(None, "user_version", &(n as i64 + 1))?;
+            transaction.commit()
+        });
+        migrated.map_err(|e| format!("Can't migrate the database to version {}: {}", n + 1, e))?;
+        info!("Database is now at version {}", n + 1);
+    }
+    Ok(())
+}
+
+/// Something to write to the database.
+enum Change {
+    Entry(JournalEntry),
+    Positions(HashMap<String, AccountPositions>, DateTime<Utc>),
+    Jobs(Vec<Job>),
+    Seen(String, Seen),
+    /// Says when everything before it is written
+    Flush(mpsc::Sender<()>),
+}
+
+lazy_static! {
+    /// The database at `database_path`, if there is one and it opens
+    pub static ref STORAGE: Option<Storage> = open(&get_settings());
+    /// Changes on their way to `STORAGE`. A thread of its own writes them, so
+    /// nothing handling signals waits on the disk or on another process's lock.
+    static ref WRITER: Option<mpsc::Sender<Change>> = STORAGE.as_ref().and_then(start_writer);
+}
+
+fn open(settings: &Settings) -> Option<Storage> {
+    let path = settings.database_path.as_ref()?;
+    Storage::open(path)
+        .inspect_err(|e| error!("Can't open the database at {}, carrying on without it: {}", path, e))
+        .ok()
+}
+
+fn start_writer(storage: &'static Storage) -> Option<mpsc::Sender<Change>> {
+    let (sender, changes) = mpsc::channel();
+    let writing = thread::Builder::new().name("storage".into()).spawn(move || {
+        for change in changes {
+            let written = match change {
+                Change::Entry(entry) => storage.record(&entry).map_err(|e| format!("{} ({:?})", e, entry)),
+                Change::Positions(positions, at) => storage.save_positions(&positions, at).map_err(|e| e.to_string()),
+                Change::Jobs(jobs) => storage.save_jobs(&jobs).map_err(|e| e.to_string()),
+                Change::Seen(fingerprint, seen) => storage.save_seen(&fingerprint, &seen).map_err(|e| e.to_string()),
+                Change::Flush(done) => {
+                    let _ = done.send(());
+                    Ok(())
+                }
+            };
+            if let Err(e) = written {
+                error!("Can't write to the database: {}", e);
+            }
+        }
+    });
+    writing
+        .inspect_err(|e| error!("Can't start writing to the database, carrying on without it: {}", e))
+        .ok()
+        .map(|_| sender)
+}
+
+fn write(change: Change) {
+    if let Some(writer) = WRITER.as_ref() {
+        if writer.send(change).is_err() {
+            error!("The database writer has stopped");
+        }
+    }
+}
+
+/// Files `entry` in the database, if there is one.
+pub fn record(entry: JournalEntry) {
+    write(Change::Entry(entry));
+}
+
+/// Saves `positions` to the database, if there is one.
+pub fn save_positions(positions: &HashMap<String, AccountPositions>) {
+    write(Change::Positions(positions.clone(), Utc::now()));
+}
+
+/// Saves the scheduled jobs to the database, if there is one.
+pub fn save_jobs(jobs: &[Job]) {
+    write(Change::Jobs(jobs.to_vec()));
+}
+
+/// Saves a signal dedup holds repeats of off for to the database, if there is one.
+pub fn save_seen(fingerprint: &str, seen: &Seen) {
+    write(Change::Seen(fingerprint.into(), seen.clone()));
+}
+
+/// Waits for everything sent to the database so far to be written, for
+/// shutting down.
+pub fn flush() {
+    if let Some(writer) = WRITER.as_ref() {
+        let (done, written) = mpsc::channel();
+        if writer.send(Change::Flush(done)).is_ok() {
+            let _ = written.recv();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::{
+        incoming::SignalFormat,
+        journal::{PlannedRequest, SequencePlan},
+        positions::{Position, PositionBook},
+        schedule::{Command, Scheduler},
+    };
+
+    fn entry(at: &str, signal_id: &str, event: JournalEvent) -> JournalEntry {
+        JournalEntry {
+            at: at.parse().unwrap(),
+            signal_id: signal_id.into(),
+            event,
+        }
+    }
+
+    fn exported(storage: &Storage, table: &str) -> Vec<Value> {
+        let mut out = vec![];
+        storage.export(table, None, None, &mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    fn signal(storage: &Storage, at: &str, signal_id: &str) {
+        storage
+            .record(&entry(at, signal_id, JournalEvent::SignalReceived {
+                strategy: "fancy v1".into(),
+                format: SignalFormat::Strategy,
+                signal: json!({"order": {"action": "buy"}}),
+                dry_run: false,
+            }))
+            .unwrap();
+    }
+
+    #[test]
+    fn it_migrates_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tradeproxy.db");
+        signal(&Storage::open(&path).unwrap(), "2021-06-02T12:00:00Z", "abc");
+
+        let storage = Storage::open(&path).unwrap();
+        assert_eq!(exported(&storage, "signals").len(), 1);
+        let version: i64 = storage
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn it_follows_a_signal_to_its_requests() {
+        let storage = Storage::in_memory().unwrap();
+        signal(&storage, "2021-06-02T12:00:00Z", "alice:abc");
+        let start = PlannedRequest {
+            action: "StartDeal".into(),
+            bot_id: 1234567,
+            order: None,
+            stop_loss_percentage: Some(2.0),
+            take_profit_percentage: None,
+        };
+        storage
+            .record(&entry("2021-06-02T12:00:00Z", "alice:abc", JournalEvent::SequencePlanned(SequencePlan {
+                account: "main".into(),
+                requests: vec![start],
+                webhooks: vec![],
+            })))
+            .unwrap();
+        storage
+            .record(&entry("2021-06-02T12:00:01Z", "alice:abc", JournalEvent::RequestExecuted {
+                account: "main".into(),
+                action: "StartDeal".into(),
+                bot_id: 1234567,
+                status: Some(200),
+                latency_ms: 12,
+                error: None,
+                attempts: 2,
+            }))
+            .unwrap();
+
+        let signals = exported(&storage, "signals");
+        assert_eq!(signals[0]["tenant"], "alice");
+        assert_eq!(signals[0]["status"], "planned");
+        assert_eq!(signals[0]["signal"]["order"]["action"], "buy");
+
+        let requests = exported(&storage, "requests");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["request"]["stop_loss_percentage"], 2.0);
+        assert_eq!(requests[0]["status"], 200);
+        assert_eq!(requests[0]["attempts"], 2);
+        assert_eq!(requests[0]["sent_at"], "2021-06-02T12:00:01.000Z");
+    }
+
+    #[test]
+    fn it_prunes_old_records() {
+        let storage = Storage::in_memory().unwrap();
+        signal(&storage, "2021-06-01T12:00:00Z", "old");
+        signal(&storage, "2021-06-02T12:00:00Z", "new");
+        storage
+            .record(&entry("2021-06-02T12:00:00Z", "old", JournalEvent::WebhookExecuted {
+                name: "discord".into(),
+                method: "POST".into(),
+                url: "https://discord.com".into(),
+                status: Some(204),
+                latency_ms: 30,
+                error: None,
+                attempts: 1,
+            }))
+            .unwrap();
+
+        let mut out = vec![];
+        let since = "2021-06-02T00:00:00Z".parse().ok();
+        assert_eq!(storage.export("signals", since, None, &mut out).unwrap(), 1);
+        assert!(storage.export("sqlite_master", None, None, &mut out).is_err());
+
+        assert_eq!(storage.prune("2021-06-02T00:00:00Z".parse().unwrap()).unwrap(), 2);
+        assert_eq!(exported(&storage, "signals")[0]["id"], "new");
+        assert!(exported(&storage, "webhook_calls").is_empty());
+    }
+
+    #[test]
+    fn it_keeps_positions_and_config_changes() {
+        let storage = Storage::in_memory().unwrap();
+        let mut positions = HashMap::new();
+        positions.insert("default".to_string(), vec![("fancy v1".to_string(), Position::Long)].into_iter().collect());
+        storage.save_positions(&positions, "2021-06-02T12:00:00Z".parse().unwrap()).unwrap();
+        storage.save_positions(&positions, "2021-06-02T13:00:00Z".parse().unwrap()).unwrap();
+        let saved = exported(&storage, "positions");
+        assert_eq!(saved[0]["position"], "long");
+        assert_eq!(saved[0]["updated_at"], "2021-06-02T12:00:00.000Z");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        fs::write(&path, "long_bot_id: 1").unwrap();
+        let now = Utc::now();
+        assert!(storage.audit_config(&path, "0.3.2", now).unwrap());
+        assert!(!storage.audit_config(&path, "0.3.2", now).unwrap());
+        fs::write(&path, "long_bot_id: 2").unwrap();
+        assert!(storage.audit_config(&path, "0.3.2", now).unwrap());
+        assert_eq!(exported(&storage, "config_audit").len(), 2);
+    }
+
+    #[test]
+    fn it_gives_back_positions_and_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Storage::open(dir.path().join("tradeproxy.db")).unwrap();
+        assert!(database.load_positions().unwrap().is_none());
+        let mut positions = HashMap::new();
+        positions.insert("default".to_string(), vec![("fancy v1".to_string(), Position::Short)].into_iter().collect());
+        database.save_positions(&positions, Utc::now()).unwrap();
+
+        let now = Utc::now();
+        let later = Job::once("b", Command::StopBots, now + chrono::Duration::hours(1));
+        let sooner = Job::once("a", Command::StartBots, now + chrono::Duration::minutes(1));
+        database.save_jobs(&[later.clone(), sooner]).unwrap();
+        database.save_jobs(&[later]).unwrap();
+
+        let book = PositionBook::load(Some(dir.path().join("positions.json")), Some(&database));
+        assert!(book.is_saved());
+        assert_eq!(book.all()["default"]["fancy v1"], Position::Short);
+        let scheduler = Scheduler::load(Some(dir.path().join("schedule.json")), Some(&database));
+        let ids: Vec<String> = scheduler.all().into_iter().map(|job| job.id).collect();
+        assert_eq!(ids, ["b"]);
+    }
+
+    #[test]
+    fn it_gives_back_signals_dedup_is_holding() {
+        let database = Storage::in_memory().unwrap();
+        let now: DateTime<Utc> = "2021-06-02T12:00:00Z".parse().unwrap();
+        let seen = |signal_id: &str, at: DateTime<Utc>| Seen {
+            signal_id: signal_id.into(),
+            at,
+            until: at + chrono::Duration::seconds(60),
+        };
+        database.save_seen("old", &seen("a", now - chrono::Duration::seconds(90))).unwrap();
+        database.save_seen("new", &seen("b", now - chrono::Duration::seconds(30))).unwrap();
+        database.save_seen("newer", &seen("c", now)).unwrap();
+
+        let held = database.load_seen(now).unwrap();
+        let mut ids: Vec<&str> = held.values().map(|seen| seen.signal_id.as_str()).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, ["b", "c"]);
+        assert_eq!(held["new"].at, now - chrono::Duration::seconds(30));
+        // Ones that ran out are gone for good
+        let rows: i64 = database
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM dedup", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_keeps_the_database_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tradeproxy.db");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        Storage::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,166 @@
+use opentelemetry::{
+    global,
+    sdk::{trace, Resource},
+    trace::{
+        get_active_span, FutureExt, SpanBuilder, SpanContext, SpanId, StatusCode, TraceContextExt,
+        TraceError, TraceId, TraceState, Tracer, TRACE_FLAG_SAMPLED,
+    },
+    Context, KeyValue,
+};
+use std::future::Future;
+use uuid::Uuid;
+use crate::settings::{TracingSettings, TENANT_SEPARATOR};
+
+const TRACER: &str = "tradeproxy";
+
+/// Starts sending spans to the collector in `settings`. Until this is called,
+/// every span is a no-op.
+pub fn start(settings: &TracingSettings) -> Result<(), TraceError> {
+    let resource = Resource::new(vec![KeyValue::new("service.name", settings.service_name.clone())]);
+    opentelemetry_otlp::new_pipeline()
+        .with_endpoint(settings.otlp_endpoint.clone())
+        .with_trace_config(trace::config().with_resource(resource))
+        .with_tonic()
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    Ok(())
+}
+
+/// Sends whatever spans are still waiting.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// A signal's trace id is its signal id, so a trace can be found from the
+/// journal, the logs or the `x-signal-id` header. Its root span's id is the
+/// low half of that, so any part of the pipeline (even after a restart) can
+/// hang its spans off the root without being handed it. A tenant's signal ids
+/// start with the tenant's name, which is left out.
+fn root_span_context(signal_id: &str) -> Option<SpanContext> {
+    let uuid = signal_id.rsplit(TENANT_SEPARATOR).next().unwrap_or(signal_id);
+    let id = Uuid::parse_str(uuid).ok()?.as_u128();
+    Some(SpanContext::new(
+        TraceId::from_u128(id),
+        SpanId::from_u64(id as u64),
+        TRACE_FLAG_SAMPLED,
+        true,
+        TraceState::default(),
+    ))
+}
+
+/// The root span for a signal: receiving the webhook and parsing it.
+pub fn in_root_span<R>(signal_id: &str, name: &'static str, f: impl FnOnce() -> R) -> R {
+    let mut builder = SpanBuilder::from_name(name).with_parent_context(Context::new());
+    if let Some(root) = root_span_context(signal_id) {
+        builder = builder.with_trace_id(root.trace_id()).with_span_id(root.span_id());
+    }
+    let span = global::tracer(TRACER).build(builder.with_attributes(vec![signal_attribute(signal_id)]));
+    let cx = Context::current_with_span(span);
+    let result = {
+        let _attached = cx.clone().attach();
+        f()
+    };
+    cx.span().end();
+    result
+}
+
+/// Runs `f` in a span under the signal's root span.
+pub async fn in_signal_span<F: Future>(
+    signal_id: &str,
+    name: &'static str,
+    mut attributes: Vec<KeyValue>,
+    f: F,
+) -> F::Output {
+    let parent = match root_span_context(signal_id) {
+        Some(root) => Context::new().with_remote_span_context(root),
+        None => Context::current(),
+    };
+    attributes.push(signal_attribute(signal_id));
+    let cx = start_span(name, attributes, parent);
+    let output = f.with_context(cx.clone()).await;
+    cx.span().end();
+    output
+}
+
+/// Runs `f` in a span under whichever one we're in.
+pub async fn in_span<F: Future>(name: &'static str, attributes: Vec<KeyValue>, f: F) -> F::Output {
+    let cx = start_span(name, attributes, Context::current());
+    let output = f.with_context(cx.clone()).await;
+    cx.span().end();
+    output
+}
+
+/// Like `in_span`, for code that doesn't await.
+pub fn in_span_sync<R>(name: &'static str, attributes: Vec<KeyValue>, f: impl FnOnce() -> R) -> R {
+    let cx = start_span(name, attributes, Context::current());
+    let result = {
+        let _attached = cx.clone().attach();
+        f()
+    };
+    cx.span().end();
+    result
+}
+
+/// Adds an attribute to the span we're in.
+pub fn record(attribute: KeyValue) {
+    get_active_span(|span| span.set_attribute(attribute));
+}
+
+/// Marks the span we're in as having gone wrong.
+pub fn record_error(message: String) {
+    get_active_span(|span| span.set_status(StatusCode::Error, message));
+}
+
+fn start_span(name: &'static str, attributes: Vec<KeyValue>, parent: Context) -> Context {
+    let span = global::tracer(TRACER).build(
+        SpanBuilder::from_name(name)
+            .with_parent_context(parent.clone())
+            .with_attributes(attributes),
+    );
+    parent.with_span(span)
+}
+
+fn signal_attribute(signal_id: &str) -> KeyValue {
+    KeyValue::new("signal.id", signal_id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNAL_ID: &str = "6f9619ff-8b86-d011-b42d-00c04fc964ff";
+
+    fn current_trace_id() -> TraceId {
+        Context::current().span().span_context().trace_id()
+    }
+
+    #[test]
+    fn it_uses_the_signal_id_as_the_trace_id() {
+        let root = root_span_context(SIGNAL_ID).unwrap();
+        assert_eq!(root.trace_id().to_hex(), "6f9619ff8b86d011b42d00c04fc964ff");
+        assert_eq!(root.span_id().to_hex(), "b42d00c04fc964ff");
+        assert!(root_span_context("reconcile").is_none());
+    }
+
+    #[test]
+    fn it_leaves_the_tenant_out_of_the_trace_id() {
+        let tenant_signal_id = format!("alice{}{}", TENANT_SEPARATOR, SIGNAL_ID);
+        let root = root_span_context(&tenant_signal_id).unwrap();
+        assert_eq!(root.trace_id(), root_span_context(SIGNAL_ID).unwrap().trace_id());
+        assert!(root_span_context("alice:reconcile").is_none());
+    }
+
+    #[tokio::test]
+    async fn it_hangs_spans_off_the_signal() {
+        let trace_id = in_signal_span(SIGNAL_ID, "execute_sequence", vec![], async {
+            in_span("execute_request", vec![], async {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                in_span_sync("plan_requests", vec![], current_trace_id)
+            })
+            .await
+        })
+        .await;
+
+        assert_eq!(trace_id, root_span_context(SIGNAL_ID).unwrap().trace_id());
+        assert_eq!(current_trace_id(), TraceId::invalid());
+    }
+}
@@ -6,11 +6,13 @@ if [[ "$2" == "--dev" ]]; then
     PORT=3138
 fi
 
-if [[ "$1" == "buy" || "$1" == "sell" ]]; then
+ACTIONS="buy sell close_long close_short flatten reverse start_bots stop_bots panic_sell"
+
+if [[ " $ACTIONS " == *" $1 "* && -n "$1" ]]; then
     curl --header "Content-Type: application/json" \
          --request POST \
          --data "{\"action\": \"$1\", \"contracts\": 1}" \
          http://localhost:$PORT/trade
 else
-    echo "Need a buy or sell argument!"
+    echo "Need one of these actions: $ACTIONS"
 fi